@@ -0,0 +1,363 @@
+use crate::output::{self, sentence};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::{fmt,fs,io};
+
+// The rate of rise is taken over this window, and only once the readings
+// span MIN_RATE_SPAN: a 9 bit probe steps 0.5 °C, which over one second
+// would look like 30 °C a minute
+const RATE_WINDOW: TimeDelta = TimeDelta::seconds(60);
+const MIN_RATE_SPAN: TimeDelta = TimeDelta::seconds(30);
+const DEFAULT_HYSTERESIS: f64 = 1.0; // °C, or °C per minute for the rate
+// The limits for every probe without a line of its own
+const DEFAULT_PROBE: &str = "*";
+
+/// The alarm limits of one probe; temperatures are in °C whatever the
+/// output units.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    pub high: Option<f64>,
+    pub low: Option<f64>,
+    // °C per minute
+    pub rise: Option<f64>,
+    // No good reading for this long
+    pub stale: Option<TimeDelta>,
+    // How far back past a limit a reading has to come to clear its alarm,
+    // so that a temperature sitting on the limit does not flap
+    pub hysteresis: f64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { high: None, low: None, rise: None, stale: None, hysteresis: DEFAULT_HYSTERESIS }
+    }
+}
+
+/// Alarm limits by ROM ID or probe name, one probe per line:
+///
+/// ```text
+/// # probe: limits in °C, °C per minute and seconds
+/// Engine room: high=90 rise=5 stale=60
+/// Freezer: low=-25 high=-12 hysteresis=2
+/// *: stale=120
+/// ```
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, Limits>> {
+    let path = path.as_ref();
+    parse(&fs::read_to_string(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+fn parse(text: &str) -> Result<HashMap<String, Limits>, String> {
+    let mut limits = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |e: &str| format!("line {}: {}", i + 1, e);
+        let (probe, settings) = line.rsplit_once(':').ok_or_else(|| error("expected probe: limit=value ..."))?;
+        let mut probe_limits = Limits::default();
+        for setting in settings.split_whitespace() {
+            let (key, value) = setting.split_once('=').ok_or_else(|| error(&format!("expected limit=value, got {:?}", setting)))?;
+            let value: f64 = value.parse().map_err(|_| error(&format!("bad number {:?}", value)))?;
+            match key {
+                "high" => probe_limits.high = Some(value),
+                "low" => probe_limits.low = Some(value),
+                "rise" if value > 0.0 => probe_limits.rise = Some(value),
+                "stale" if value > 0.0 => probe_limits.stale = Some(TimeDelta::milliseconds((value * 1000.0) as i64)),
+                "hysteresis" if value >= 0.0 => probe_limits.hysteresis = value,
+                "rise" | "stale" | "hysteresis" => return Err(error(&format!("{} cannot be {}", key, value))),
+                _ => return Err(error(&format!("unknown limit {:?}, expected high, low, rise, stale or hysteresis", key))),
+            }
+        }
+        if let (Some(low), Some(high)) = (probe_limits.low, probe_limits.high) {
+            if low >= high {
+                return Err(error("low must be below high"));
+            }
+        }
+        if limits.insert(probe.trim().to_string(), probe_limits).is_some() {
+            return Err(error(&format!("{} has limits already", probe.trim())));
+        }
+    }
+    Ok(limits)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Kind {
+    High,
+    Low,
+    Rise,
+    Stale,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::High => write!(f, "high"),
+            Kind::Low => write!(f, "low"),
+            Kind::Rise => write!(f, "rise"),
+            Kind::Stale => write!(f, "stale"),
+        }
+    }
+}
+
+/// An alarm that was raised or cleared, or is still active.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alarm {
+    // The ALR alarm number, fixed for a probe and kind
+    pub number: u32,
+    pub probe: String,
+    pub name: String,
+    pub kind: Kind,
+    pub active: bool,
+    pub text: String,
+}
+
+impl Alarm {
+    /// `$IIALR,hhmmss.ss,nnn,A|V,V,text`: threshold exceeded or not, never
+    /// acknowledged as nothing reads acknowledgements here.
+    pub fn alr(&self, now: DateTime<Utc>) -> String {
+        let time = format!("{}.{:02}", now.format("%H%M%S"), now.timestamp_subsec_millis() / 10);
+        let condition = if self.active { "A" } else { "V" };
+        sentence(&format!("IIALR,{},{:03},{},V,{}", time, self.number, condition, output::field(&self.text)))
+    }
+
+    pub fn json(&self, now: DateTime<Utc>) -> String {
+        json!({
+            "time": now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "id": self.probe,
+            "name": self.name,
+            "alarm": self.kind.to_string(),
+            "active": self.active,
+            "text": self.text,
+        })
+        .to_string()
+    }
+}
+
+struct Probe {
+    name: String,
+    // Good readings within RATE_WINDOW, in °C
+    readings: VecDeque<(DateTime<Utc>, f64)>,
+    // The last good reading, or when the probe was first seen
+    last_good: DateTime<Utc>,
+    active: HashMap<Kind, Alarm>,
+}
+
+/// Checks readings against each probe's limits and keeps track of which
+/// alarms are active.
+pub struct Monitor {
+    limits: HashMap<String, Limits>,
+    probes: HashMap<String, Probe>,
+    numbers: HashMap<(String, Kind), u32>,
+}
+
+impl Monitor {
+    pub fn new(limits: HashMap<String, Limits>) -> Monitor {
+        Monitor { limits, probes: HashMap::new(), numbers: HashMap::new() }
+    }
+
+    // By ROM ID, then by name, then the default
+    fn limits_for(&self, id: &str, name: &str) -> Option<&Limits> {
+        self.limits.get(id).or_else(|| self.limits.get(name)).or_else(|| self.limits.get(DEFAULT_PROBE))
+    }
+
+    /// A probe was read (None when the read failed); returns the alarms
+    /// raised or cleared by it.
+    pub fn reading(&mut self, id: &str, name: &str, celsius: Option<f64>, now: DateTime<Utc>) -> Vec<Alarm> {
+        let limits = match self.limits_for(id, name) {
+            Some(limits) => limits.clone(),
+            None => return Vec::new(),
+        };
+        let probe = self.probes.entry(id.to_string()).or_insert_with(|| Probe {
+            name: name.to_string(),
+            readings: VecDeque::new(),
+            last_good: now,
+            active: HashMap::new(),
+        });
+        probe.name = name.to_string();
+        let celsius = match celsius {
+            Some(celsius) => celsius,
+            None => return Vec::new(),
+        };
+        probe.last_good = now;
+        probe.readings.push_back((now, celsius));
+        while probe.readings.front().is_some_and(|&(time, _)| now - time > RATE_WINDOW) {
+            probe.readings.pop_front();
+        }
+        let rate = match (probe.readings.front(), probe.readings.back()) {
+            (Some(&(first, from)), Some(&(last, to))) if last - first >= MIN_RATE_SPAN => {
+                Some((to - from) / ((last - first).num_milliseconds() as f64 / 60_000.0))
+            }
+            _ => None,
+        };
+
+        let h = limits.hysteresis;
+        let mut checks = vec![(Kind::Stale, Some(false), String::new())];
+        if let Some(high) = limits.high {
+            let raise = celsius > high;
+            let clear = celsius < high - h;
+            checks.push((Kind::High, state(raise, clear), format!("{} high temperature {:.1} C (limit {:.1} C)", name, celsius, high)));
+        }
+        if let Some(low) = limits.low {
+            let raise = celsius < low;
+            let clear = celsius > low + h;
+            checks.push((Kind::Low, state(raise, clear), format!("{} low temperature {:.1} C (limit {:.1} C)", name, celsius, low)));
+        }
+        if let (Some(rise), Some(rate)) = (limits.rise, rate) {
+            let raise = rate > rise;
+            let clear = rate < rise - h;
+            checks.push((Kind::Rise, state(raise, clear), format!("{} rising {:.1} C/min (limit {:.1} C/min)", name, rate, rise)));
+        }
+        let mut changes = Vec::new();
+        for (kind, active, text) in checks {
+            if let Some(active) = active {
+                changes.extend(self.set(id, kind, active, text));
+            }
+        }
+        changes
+    }
+
+    /// Probes without a good reading for their stale limit, including ones
+    /// that have dropped off the bus; returns the alarms raised.
+    pub fn tick(&mut self, now: DateTime<Utc>) -> Vec<Alarm> {
+        let mut stale = Vec::new();
+        for (id, probe) in &self.probes {
+            if let Some(limit) = self.limits_for(id, &probe.name).and_then(|limits| limits.stale) {
+                let silent = now - probe.last_good;
+                if silent >= limit {
+                    let text = format!("{} no reading for {} s", probe.name, silent.num_seconds());
+                    stale.push((id.clone(), text));
+                }
+            }
+        }
+        let mut changes = Vec::new();
+        for (id, text) in stale {
+            changes.extend(self.set(&id, Kind::Stale, true, text));
+        }
+        changes
+    }
+
+    /// The alarms still active, to be repeated.
+    pub fn active(&self) -> Vec<&Alarm> {
+        let mut active: Vec<&Alarm> = self.probes.values().flat_map(|probe| probe.active.values()).collect();
+        active.sort_by_key(|alarm| alarm.number);
+        active
+    }
+
+    // Raises or clears an alarm, returning it when that is a change
+    fn set(&mut self, id: &str, kind: Kind, active: bool, text: String) -> Option<Alarm> {
+        let probe = self.probes.get_mut(id)?;
+        match (active, probe.active.contains_key(&kind)) {
+            (true, false) => {
+                let next = self.numbers.len() as u32 + 1;
+                let number = *self.numbers.entry((id.to_string(), kind)).or_insert(next);
+                let alarm = Alarm { number, probe: id.to_string(), name: probe.name.clone(), kind, active, text };
+                probe.active.insert(kind, alarm.clone());
+                Some(alarm)
+            }
+            (false, true) => {
+                let mut alarm = probe.active.remove(&kind)?;
+                alarm.active = false;
+                alarm.text = format!("{} {} alarm cleared", alarm.name, kind);
+                Some(alarm)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Between the raise and clear points an alarm stays as it is
+fn state(raise: bool, clear: bool) -> Option<bool> {
+    match (raise, clear) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// A desktop notification, as rust_i2c_as3935 does for lightning.
+pub fn notify(alarm: &Alarm) {
+    use notify_rust::{Hint, Notification, Urgency};
+    let urgency = if alarm.active { Urgency::Critical } else { Urgency::Normal };
+    // No desktop session (a headless boat computer) is not a problem
+    if let Err(e) = Notification::new()
+        .summary("Temperature alarm")
+        .body(&alarm.text)
+        .icon(if alarm.active { "dialog-warning" } else { "dialog-information" })
+        .appname("1-Wire temperature")
+        .hint(Hint::Urgency(urgency))
+        .hint(Hint::SuppressSound(!alarm.active))
+        .timeout(60_000_000)
+        .show()
+    {
+        eprintln!("notification failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse() {
+        let limits = parse("# Boat\nEngine room: high=90 rise=5 stale=60\n*: stale=120 hysteresis=0.5\n").unwrap();
+        let engine = &limits["Engine room"];
+        assert_eq!((Some(90.0), None, Some(5.0)), (engine.high, engine.low, engine.rise));
+        assert_eq!(Some(TimeDelta::seconds(60)), engine.stale);
+        assert_eq!(DEFAULT_HYSTERESIS, engine.hysteresis);
+        assert_eq!(0.5, limits["*"].hysteresis);
+        assert_eq!(Err("line 1: low must be below high".to_string()), parse("Freezer: low=-10 high=-20"));
+        assert!(parse("Freezer: cold=-10").is_err());
+        assert!(parse("Freezer: rise=-1").is_err());
+        assert!(parse("Freezer high=1").is_err());
+    }
+
+    #[test]
+    fn test_monitor() {
+        let start = Utc.with_ymd_and_hms(2024, 7, 1, 12, 0, 0).unwrap();
+        let at = |secs: i64| start + TimeDelta::seconds(secs);
+        let limits = parse("28-0316a2795cff: high=90 rise=5 stale=60\nFreezer: low=-25 high=-12 hysteresis=2").unwrap();
+        let mut monitor = Monitor::new(limits);
+
+        // Warming up slowly, then past the limit
+        assert!(monitor.reading("28-0316a2795cff", "Engine room", Some(86.0), at(0)).is_empty());
+        assert!(monitor.reading("28-0316a2795cff", "Engine room", Some(87.0), at(30)).is_empty());
+        let raised = monitor.reading("28-0316a2795cff", "Engine room", Some(90.5), at(60));
+        assert_eq!(1, raised.len());
+        assert_eq!((Kind::High, true, 1), (raised[0].kind, raised[0].active, raised[0].number));
+        assert_eq!("$IIALR,120100.00,001,A,V,Engine room high temperature 90.5 C (limit 90.0 C)*5B", raised[0].alr(at(60)));
+        // Within the hysteresis it stays up
+        assert!(monitor.reading("28-0316a2795cff", "Engine room", Some(89.5), at(90)).is_empty());
+        let cleared = monitor.reading("28-0316a2795cff", "Engine room", Some(88.5), at(120));
+        assert_eq!((Kind::High, false), (cleared[0].kind, cleared[0].active));
+        assert!(cleared[0].alr(at(120)).starts_with("$IIALR,120200.00,001,V,V,Engine room high alarm cleared*"));
+
+        // 6 °C in 40 seconds is a fire
+        monitor.reading("28-0316a2795cff", "Engine room", Some(84.0), at(150));
+        let raised = monitor.reading("28-0316a2795cff", "Engine room", Some(88.0), at(190));
+        assert_eq!(Kind::Rise, raised[0].kind);
+        assert_eq!("Engine room rising 6.0 C/min (limit 5.0 C/min)", raised[0].text);
+        assert_eq!(1, monitor.active().len());
+
+        // A failed read counts towards stale, and a probe that drops off the
+        // bus does too
+        monitor.reading("28-0316a2795cff", "Engine room", None, at(220));
+        assert!(monitor.tick(at(240)).is_empty());
+        let stale = monitor.tick(at(250));
+        assert_eq!((Kind::Stale, "Engine room no reading for 60 s"), (stale[0].kind, stale[0].text.as_str()));
+        assert!(monitor.tick(at(260)).is_empty());
+        let cleared = monitor.reading("28-0316a2795cff", "Engine room", Some(70.0), at(270));
+        assert!(cleared.iter().any(|alarm| alarm.kind == Kind::Stale && !alarm.active));
+
+        // Limits by name, and a probe without any
+        let raised = monitor.reading("28-0316a27a31ff", "Freezer", Some(-11.0), at(0));
+        assert_eq!((Kind::High, 4), (raised[0].kind, raised[0].number));
+        assert!(monitor.reading("28-0316a27a31ff", "Freezer", Some(-13.5), at(10)).is_empty());
+        assert!(!monitor.reading("28-0316a27a31ff", "Freezer", Some(-14.5), at(20))[0].active);
+        assert!(monitor.reading("28-0416c1b2e4ff", "Cabin", Some(60.0), at(0)).is_empty());
+    }
+}
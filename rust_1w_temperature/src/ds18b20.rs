@@ -1,64 +1,432 @@
-use std::{fs,io,num};
-use std::path::PathBuf;
+use std::{fs,io,thread};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use crate::w1_errors::*;
 
-static W1_PATH_PREFIX: &str = "/sys/bus/w1/devices";
+// The default root; the *_in functions take another, e.g. a test fixture
+pub static W1_PATH_PREFIX: &str = "/sys/bus/w1/devices";
 static W1_PATH_SUFFIX: &str = "w1_slave";
+// DS18B20 ROM IDs start with its family code
+static DS18B20_FAMILY: &str = "28-";
 
-pub struct MilliCelsius(u32);
+// The temperature register at power-on, 85 °C
+const POWER_ON_RESET: [u8; 2] = [0x50, 0x05];
+// Reserved byte 6 at power-on. A conversion leaves 0x10 minus the low nibble
+// of the temperature there, so 0x10 after one to exactly 85 °C.
+const POWER_ON_BYTE_6: u8 = 0x0c;
+// A previous reading this close makes 85 °C believable
+const NEAR_POWER_ON: i32 = 5000;
+
+// The datasheet's limits for the resolution and the TH/TL alarm bytes
+const RESOLUTIONS: std::ops::RangeInclusive<u8> = 9..=12;
+const ALARM_RANGE: std::ops::RangeInclusive<i8> = -55..=125;
+// A bulk conversion waits for the slowest probe's conversion time and
+// then some; 750 ms (12 bits) when none can be read
+const BULK_SLACK: Duration = Duration::from_millis(250);
+const DEFAULT_CONV_TIME: Duration = Duration::from_millis(750);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MilliCelsius(i32);
 
 impl MilliCelsius {
+    pub fn to_celsius(self) -> f64 {
+        (self.0 as f64) / 1000.0
+    }
+
     pub fn to_fahrenheit(self) -> f64 {
-        (self.0 as f64) / 1000.0 / 5.0 * 9.0 + 32.0
+        self.to_celsius() / 5.0 * 9.0 + 32.0
     }
 
-    pub fn as_u32(self) -> u32 {
+    pub fn to_kelvin(self) -> f64 {
+        self.to_celsius() + 273.15
+    }
+
+    pub fn as_i32(self) -> i32 {
         self.0
     }
 }
 
+/// Everything the kernel reports in w1_slave:
+///
+/// ```text
+/// 6e 01 55 05 7f 7e a5 66 f2 : crc=f2 YES
+/// 6e 01 55 05 7f 7e a5 66 f2 t=22875
+/// ```
+#[derive(Debug, PartialEq)]
+pub struct W1Slave {
+    // Temperature LSB/MSB, TH, TL, configuration, 3 reserved bytes and the CRC
+    pub scratchpad: [u8; 9],
+    // The kernel's YES/NO
+    pub crc_ok: bool,
+    pub temp: MilliCelsius,
+}
+
+impl W1Slave {
+    /// The temperature, unless the reading is one of the ways a DS18B20
+    /// fails.
+    ///
+    /// 85 °C is both the power-on value of the temperature register and a
+    /// temperature an engine room or exhaust probe can reach. It is taken as
+    /// a reset only while byte 6 still has its power-on value, which not
+    /// every clone changes; see `temperature_after`.
+    pub fn temperature(&self) -> Result<MilliCelsius, W1Error> {
+        self.temperature_after(None)
+    }
+
+    /// Like `temperature`, but 85 °C is also kept when the probe's previous
+    /// reading was within 5 °C of it.
+    pub fn temperature_after(&self, previous: Option<MilliCelsius>) -> Result<MilliCelsius, W1Error> {
+        self.verify()?;
+        let near = previous.is_some_and(|previous| (previous.0 - self.temp.0).abs() <= NEAR_POWER_ON);
+        if self.scratchpad[..2] == POWER_ON_RESET && self.scratchpad[6] == POWER_ON_BYTE_6 && !near {
+            return Err(W1Error::PowerOnReset);
+        }
+        Ok(self.temp)
+    }
+
+    /// Whether the scratchpad made it across the bus intact.
+    pub fn verify(&self) -> Result<(), W1Error> {
+        if self.scratchpad.iter().all(|&b| b == 0) {
+            return Err(W1Error::BusError);
+        }
+        let expected = crc8(&self.scratchpad[..8]);
+        if !self.crc_ok || expected != self.scratchpad[8] {
+            return Err(W1Error::BadCrc { expected, found: self.scratchpad[8] });
+        }
+        Ok(())
+    }
+
+    /// 9 to 12 bits, from the configuration register.
+    pub fn resolution(&self) -> u8 {
+        9 + ((self.scratchpad[4] >> 5) & 0x03)
+    }
+
+    pub fn alarms(&self) -> Alarms {
+        Alarms { low: self.scratchpad[3] as i8, high: self.scratchpad[2] as i8 }
+    }
+}
+
+/// The TL and TH bytes, in whole degrees Celsius. The DS18B20 flags itself
+/// for an alarm search when a conversion falls outside them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Alarms {
+    pub low: i8,
+    pub high: i8,
+}
+
 pub struct DS18B20 {
+    root: PathBuf,
     w1_id: String
 }
 
 impl DS18B20 {
-    pub fn new() -> Result<DS18B20, io::Error> {
-        for entry in fs::read_dir(W1_PATH_PREFIX)? {
-            let filename = entry?.file_name().into_string().unwrap();
-            if filename.contains("28-") {
-                return Ok(DS18B20 {
-                    w1_id: filename
-                })
-            }
-        }
-        panic!("Unable to find a DS18B20")
+    /// The first DS18B20 on the bus.
+    pub fn new() -> Result<DS18B20, W1Error> {
+        DS18B20::new_in(W1_PATH_PREFIX)
+    }
+
+    pub fn new_in<P: AsRef<Path>>(root: P) -> Result<DS18B20, W1Error> {
+        DS18B20::all_in(root)?.into_iter().next().ok_or(W1Error::NoSensors)
     }
 
     pub fn new_for_id(id: String) -> DS18B20 {
+        DS18B20::new_for_id_in(W1_PATH_PREFIX, id)
+    }
+
+    pub fn new_for_id_in<P: AsRef<Path>>(root: P, id: String) -> DS18B20 {
          DS18B20 {
+             root: root.as_ref().to_path_buf(),
              w1_id: id
          }
     }
 
+    /// Every DS18B20 on every bus master, sorted by ROM ID. Errors with
+    /// NoSensors rather than returning none.
+    pub fn all() -> Result<Vec<DS18B20>, W1Error> {
+        DS18B20::all_in(W1_PATH_PREFIX)
+    }
+
+    pub fn all_in<P: AsRef<Path>>(root: P) -> Result<Vec<DS18B20>, W1Error> {
+        let root = root.as_ref();
+        let mut ids = match master_slaves(root) {
+            Ok(ids) => ids,
+            // Without bus masters listed, look at the devices themselves
+            Err(_) => scan_devices(root)?,
+        };
+        ids.retain(|id| id.starts_with(DS18B20_FAMILY));
+        ids.sort();
+        ids.dedup();
+        if ids.is_empty() {
+            return Err(W1Error::NoSensors);
+        }
+        Ok(ids.into_iter().map(|id| DS18B20::new_for_id_in(root, id)).collect())
+    }
+
+    pub fn id(&self) -> &str {
+        &self.w1_id
+    }
+
     pub fn read_raw(&self) -> io::Result<String> {
-        let mut path = PathBuf::from(W1_PATH_PREFIX);
+        let mut path = self.root.clone();
         path.push(&self.w1_id);
         path.push(W1_PATH_SUFFIX);
         fs::read_to_string(path)
     }
 
+    pub fn read_w1_slave(&self) -> Result<W1Slave, W1Error> {
+        parse_w1_slave(&self.read_raw()?)
+    }
+
     pub fn read_temp(&self) -> Result<MilliCelsius, W1Error> {
-        let temp_data = self.read_raw()?;
-        if !temp_data.contains("YES") {
-            return Err(W1Error::BadSerialConnection);
+        self.read_w1_slave()?.temperature()
+    }
+
+    /// For polling: a reading at the power-on value is believed when the
+    /// previous one was close to it.
+    pub fn read_temp_after(&self, previous: Option<MilliCelsius>) -> Result<MilliCelsius, W1Error> {
+        self.read_w1_slave()?.temperature_after(previous)
+    }
+
+    // For settings read from the scratchpad, where a good temperature is
+    // not needed
+    fn read_verified(&self) -> Result<W1Slave, W1Error> {
+        let w1_slave = self.read_w1_slave()?;
+        w1_slave.verify()?;
+        Ok(w1_slave)
+    }
+
+    // The w1_therm attributes beside w1_slave (resolution, alarms, ...)
+    fn attribute(&self, name: &str) -> PathBuf {
+        self.root.join(&self.w1_id).join(name)
+    }
+
+    // None when the kernel is too old to have the attribute
+    fn read_attribute(&self, name: &str) -> Result<Option<String>, W1Error> {
+        match fs::read_to_string(self.attribute(name)) {
+            Ok(value) => Ok(Some(value.trim().to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_attribute(&self, name: &str, value: &str) -> Result<(), W1Error> {
+        Ok(fs::write(self.attribute(name), value)?)
+    }
+
+    /// 9 to 12 bits; from the scratchpad on kernels without `resolution`.
+    pub fn resolution(&self) -> Result<u8, W1Error> {
+        match self.read_attribute("resolution")? {
+            Some(bits) => Ok(bits.parse()?),
+            None => Ok(self.read_verified()?.resolution()),
+        }
+    }
+
+    /// Fewer bits convert faster: 94 ms at 9 bits (0.5 °C steps) up to
+    /// 750 ms at 12 (0.0625 °C).
+    pub fn set_resolution(&self, bits: u8) -> Result<(), W1Error> {
+        if !RESOLUTIONS.contains(&bits) {
+            return Err(W1Error::InvalidSetting(format!("resolution {} bits, expected 9 to 12", bits)));
+        }
+        self.write_attribute("resolution", &bits.to_string())
+    }
+
+    /// TL and TH; from the scratchpad on kernels without `alarms`.
+    pub fn alarms(&self) -> Result<Alarms, W1Error> {
+        let value = match self.read_attribute("alarms")? {
+            Some(value) => value,
+            None => return Ok(self.read_verified()?.alarms()),
+        };
+        // "TL TH"
+        match value.split_whitespace().collect::<Vec<_>>()[..] {
+            [low, high] => Ok(Alarms { low: low.parse()?, high: high.parse()? }),
+            _ => Err(W1Error::Malformed(value)),
+        }
+    }
+
+    pub fn set_alarms(&self, alarms: Alarms) -> Result<(), W1Error> {
+        if !ALARM_RANGE.contains(&alarms.low) || !ALARM_RANGE.contains(&alarms.high) || alarms.low > alarms.high {
+            return Err(W1Error::InvalidSetting(format!(
+                "alarms {} to {} °C, expected -55 to 125 with low <= high",
+                alarms.low, alarms.high
+            )));
+        }
+        self.write_attribute("alarms", &format!("{} {}", alarms.low, alarms.high))
+    }
+
+    /// How long the kernel waits for a conversion; the datasheet time for
+    /// the resolution on kernels without `conv_time`.
+    pub fn conv_time(&self) -> Result<Duration, W1Error> {
+        match self.attribute_conv_time()? {
+            Some(time) => Ok(time),
+            None => datasheet_conv_time(self.resolution()?),
+        }
+    }
+
+    // From the attributes alone: the scratchpad would take a conversion to read
+    fn attribute_conv_time(&self) -> Result<Option<Duration>, W1Error> {
+        if let Some(ms) = self.read_attribute("conv_time")? {
+            return Ok(Some(Duration::from_millis(ms.parse()?)));
+        }
+        match self.read_attribute("resolution")? {
+            Some(bits) => Ok(Some(datasheet_conv_time(bits.parse()?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes `conv_time` as the kernel takes it: 0 measures the probe's
+    /// actual conversion time, 1 restores the datasheet time and anything
+    /// else is the time in milliseconds.
+    pub fn set_conv_time(&self, ms: u32) -> Result<(), W1Error> {
+        self.write_attribute("conv_time", &ms.to_string())
+    }
+
+    /// Copies the resolution and alarms to the EEPROM, so that they survive
+    /// a power cycle.
+    pub fn save_settings(&self) -> Result<(), W1Error> {
+        self.write_attribute("eeprom_cmd", "save")
+    }
+}
+
+// 94 ms at 9 bits, doubling with every bit
+fn datasheet_conv_time(bits: u8) -> Result<Duration, W1Error> {
+    if !RESOLUTIONS.contains(&bits) {
+        return Err(W1Error::InvalidSetting(format!("resolution {} bits, expected 9 to 12", bits)));
+    }
+    Ok(Duration::from_micros(93_750 << (bits - 9)))
+}
+
+/// Starts a conversion on every probe of every bus master at once, so that
+/// reading them afterwards takes one conversion time rather than one each.
+/// False when the kernel has no `therm_bulk_read`; the probes are then
+/// converted one by one as they are read.
+pub fn bulk_convert() -> Result<bool, W1Error> {
+    bulk_convert_in(W1_PATH_PREFIX)
+}
+
+pub fn bulk_convert_in<P: AsRef<Path>>(root: P) -> Result<bool, W1Error> {
+    let root = root.as_ref();
+    let mut triggered = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let path = entry.path().join("therm_bulk_read");
+        if entry.file_name().to_string_lossy().starts_with("w1_bus_master") && path.exists() {
+            fs::write(&path, "trigger")?;
+            triggered.push(path);
+        }
+    }
+    if triggered.is_empty() {
+        return Ok(false);
+    }
+    // Probes on kernels without the attributes count as 12 bits
+    let conv_time = DS18B20::all_in(root)
+        .map(|sensors| {
+            sensors
+                .iter()
+                .map(|sensor| sensor.attribute_conv_time().ok().flatten().unwrap_or(DEFAULT_CONV_TIME))
+                .max()
+        })
+        .ok()
+        .flatten()
+        .unwrap_or(DEFAULT_CONV_TIME);
+    // -1 while a conversion is in progress. The kernel waits out the rest
+    // when a probe is read early, so a timeout is not an error.
+    let started = Instant::now();
+    while started.elapsed() < conv_time + BULK_SLACK {
+        let mut converting = false;
+        for path in &triggered {
+            converting |= fs::read_to_string(path)?.trim() == "-1";
+        }
+        if !converting {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(true)
+}
+
+// The slaves every w1_bus_masterN lists, one ROM ID per line ("not found." when empty)
+fn master_slaves(root: &Path) -> io::Result<Vec<String>> {
+    let mut ids = Vec::new();
+    let mut masters = 0;
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with("w1_bus_master") {
+            continue;
+        }
+        masters += 1;
+        let slaves = fs::read_to_string(entry.path().join("w1_master_slaves"))?;
+        ids.extend(slaves.lines().map(str::trim).filter(|id| !id.is_empty()).map(String::from));
+    }
+    if masters == 0 {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no w1_bus_master"));
+    }
+    Ok(ids)
+}
+
+fn scan_devices(root: &Path) -> io::Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(root)? {
+        ids.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    Ok(ids)
+}
+
+/// Dallas/Maxim CRC-8 (x^8 + x^5 + x^4 + 1), as used for the scratchpad
+/// and ROM IDs.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        let mut byte = byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8c;
+            }
+            byte >>= 1;
         }
-        Ok(MilliCelsius(parse_temp(temp_data)?))
     }
+    crc
 }
 
-fn parse_temp(temp_data: String) -> Result<u32, num::ParseIntError> {
-    let (_, temp_str) = temp_data.split_at(temp_data.find("t=").unwrap() + 2);
-    temp_str.trim().parse::<u32>()
+fn parse_scratchpad(bytes: &str, data: &str) -> Result<[u8; 9], W1Error> {
+    let mut scratchpad = [0u8; 9];
+    let mut bytes = bytes.split_whitespace();
+    for byte in scratchpad.iter_mut() {
+        let hex = bytes.next().ok_or_else(|| W1Error::Malformed(data.to_string()))?;
+        *byte = u8::from_str_radix(hex, 16)?;
+    }
+    if bytes.next().is_some() {
+        return Err(W1Error::Malformed(data.to_string()));
+    }
+    Ok(scratchpad)
+}
+
+/// Parses both lines of w1_slave, without judging the reading.
+pub fn parse_w1_slave(data: &str) -> Result<W1Slave, W1Error> {
+    let malformed = || W1Error::Malformed(data.to_string());
+    let mut lines = data.lines();
+    let (crc_line, temp_line) = match (lines.next(), lines.next()) {
+        (Some(crc_line), Some(temp_line)) => (crc_line, temp_line),
+        _ => return Err(malformed()),
+    };
+    let (bytes, status) = crc_line.split_once(':').ok_or_else(malformed)?;
+    let scratchpad = parse_scratchpad(bytes, data)?;
+    let crc_ok = match status.split_whitespace().last() {
+        Some("YES") => true,
+        Some("NO") => false,
+        _ => return Err(malformed()),
+    };
+    let (_, temp) = temp_line.split_once("t=").ok_or_else(malformed)?;
+    Ok(W1Slave {
+        scratchpad,
+        crc_ok,
+        temp: MilliCelsius(parse_temp(temp)?),
+    })
+}
+
+fn parse_temp(temp_str: &str) -> Result<i32, W1Error> {
+    Ok(temp_str.trim().parse::<i32>()?)
 }
 
 #[cfg(test)]
@@ -67,8 +435,55 @@ mod tests {
 
     #[test]
     fn test_parse_temp() {
-        let temp_data ="6e 01 55 05 7f 7e a5 66 f2 : crc=f2 YES
-6e 01 55 05 7f 7e a5 66 f2 t=22875".to_string();
-        assert_eq!(Ok(22875), parse_temp(temp_data));
+        let temp_data = "6e 01 55 05 7f 7e a5 66 f2 : crc=f2 YES
+6e 01 55 05 7f 7e a5 66 f2 t=22875";
+        let w1_slave = parse_w1_slave(temp_data).unwrap();
+        assert_eq!([0x6e, 0x01, 0x55, 0x05, 0x7f, 0x7e, 0xa5, 0x66, 0xf2], w1_slave.scratchpad);
+        assert_eq!(22875, w1_slave.temperature().unwrap().as_i32());
+        assert_eq!(12, w1_slave.resolution());
+        assert_eq!(Alarms { low: 5, high: 85 }, w1_slave.alarms());
+
+        // The freezer
+        let temp_data = "5e fe 4b 46 7f ff 02 10 8b : crc=8b YES
+5e fe 4b 46 7f ff 02 10 8b t=-26125";
+        let temp = parse_w1_slave(temp_data).unwrap().temperature().unwrap();
+        assert_eq!(-26125, temp.as_i32());
+        // 9 bits, with TL -20 °C
+        let w1_slave = parse_w1_slave("ec ff 4b ec 1f ff 0c 10 9c : crc=9c YES\nec ff 4b ec 1f ff 0c 10 9c t=-1250").unwrap();
+        assert_eq!(9, w1_slave.resolution());
+        assert_eq!(Alarms { low: -20, high: 75 }, w1_slave.alarms());
+        assert!((temp.to_fahrenheit() - -15.025).abs() < 1e-9);
+        assert_eq!(Ok(-1250), parse_temp("-1250\n").map_err(|e| e.to_string()));
+    }
+
+    #[test]
+    fn test_bad_readings() {
+        let reading = |data: &str| parse_w1_slave(data).and_then(|w1_slave| w1_slave.temperature());
+
+        let bad_crc = "ec ff 4b 46 7f ff 0c 10 83 : crc=83 NO
+ec ff 4b 46 7f ff 0c 10 83 t=-1250";
+        assert!(matches!(reading(bad_crc), Err(W1Error::BadCrc { expected: 0x82, found: 0x83 })));
+
+        let power_on = "50 05 4b 46 7f ff 0c 10 1c : crc=1c YES
+50 05 4b 46 7f ff 0c 10 1c t=85000";
+        assert!(matches!(reading(power_on), Err(W1Error::PowerOnReset)));
+        // An engine room at 85 °C, after 83.5 °C or with byte 6 converted
+        let w1_slave = parse_w1_slave(power_on).unwrap();
+        assert_eq!(85000, w1_slave.temperature_after(Some(MilliCelsius(83500))).unwrap().as_i32());
+        assert!(matches!(w1_slave.temperature_after(Some(MilliCelsius(21000))), Err(W1Error::PowerOnReset)));
+        let converted = "50 05 4b 46 7f ff 10 10 bd : crc=bd YES
+50 05 4b 46 7f ff 10 10 bd t=85000";
+        assert_eq!(85000, reading(converted).unwrap().as_i32());
+
+        // The CRC of all zeros is zero, so the kernel says YES
+        let bus_error = "00 00 00 00 00 00 00 00 00 : crc=00 YES
+00 00 00 00 00 00 00 00 00 t=0";
+        assert!(matches!(reading(bus_error), Err(W1Error::BusError)));
+
+        assert!(matches!(reading(""), Err(W1Error::Malformed(_))));
+        assert!(matches!(reading("6e 01 55 : crc=f2 YES\n6e 01 55 t=22875"), Err(W1Error::Malformed(_))));
+        assert!(matches!(reading("6e 01 55 05 7f 7e a5 66 f2 : crc=f2 YES\n6e 01 55 05 7f 7e a5 66 f2"), Err(W1Error::Malformed(_))));
+        assert!(matches!(reading("6e 01 55 05 7f 7e a5 66 f2 : crc=f2 YES\nt=warm"), Err(W1Error::Parse(_))));
+        assert_eq!(0xf2, crc8(&[0x6e, 0x01, 0x55, 0x05, 0x7f, 0x7e, 0xa5, 0x66]));
     }
 }
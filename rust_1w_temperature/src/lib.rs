@@ -0,0 +1,5 @@
+pub mod alarms;
+pub mod ds18b20;
+pub mod names;
+pub mod output;
+pub mod w1_errors;
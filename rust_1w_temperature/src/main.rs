@@ -1,18 +1,222 @@
-mod ds18b20;
-mod w1_errors;
-use std::env;
+use chrono::Utc;
+use rust_1w_temperature::{alarms, ds18b20, names};
+use rust_1w_temperature::output::{self, Format, Output, Units};
+use std::{env,io,process,thread};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-fn main() {
-    let sensor = ds18b20::DS18B20::new().unwrap();
-    let temp = sensor.read_temp().unwrap();
+static DEFAULT_CONFIG: &str = "/etc/rust_1w_temperature.conf";
+static USAGE: &str = "Usage: rust_1w_temperature [-r|--raw] [-c|--config FILE] [-u|--units C|K|F] [--resolution BITS]
+       rust_1w_temperature -d|--daemon [-i|--interval SECS] [--json] [--tcp ADDR|--udp ADDR]
+                           [-a|--alarms FILE] [--notify]
+                           [-c|--config FILE] [-u|--units C|K|F] [--resolution BITS]";
+
+struct Daemon {
+    interval: Duration,
+    format: Format,
+    output: Output,
+    monitor: alarms::Monitor,
+    notify: bool,
+}
 
+fn usage(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn main() {
     // Default print friendly, but allow "-r/--raw" to
     // be passed to display the millicelsius directly instead
-    let args: Vec<String> = env::args().collect();
-    if (args.len() > 1) && args[1].contains("-r") {
-        println!("{}", temp.as_u32());
+    let mut raw = false;
+    let mut config = None;
+    let mut units = None;
+    let mut resolution = None;
+    let mut daemon = false;
+    let mut interval = Duration::from_secs(10);
+    let mut format = Format::Xdr;
+    let mut output = None;
+    let mut limits = None;
+    let mut notify = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage(&format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "-r" | "--raw" => raw = true,
+            "-c" | "--config" => config = Some(value()),
+            "-u" | "--units" => units = Some(value().parse::<Units>().unwrap_or_else(|e| usage(&e))),
+            "--resolution" => resolution = Some(value().parse::<u8>().unwrap_or_else(|e| usage(&e.to_string()))),
+            "-d" | "--daemon" => daemon = true,
+            "-i" | "--interval" => {
+                interval = match value().parse::<f64>() {
+                    Ok(secs) if secs >= 1.0 => Duration::from_secs_f64(secs),
+                    _ => usage("the interval is at least 1 second (a conversion takes 750 ms)"),
+                }
+            }
+            "--json" => format = Format::Json,
+            "--tcp" => output = Some(Output::tcp(value())),
+            "--udp" => output = Some(Output::udp(&value())),
+            "-a" | "--alarms" => limits = Some(value()),
+            "--notify" => notify = true,
+            _ => usage(&format!("unknown option {}", arg)),
+        }
+    }
+    if !daemon && (output.is_some() || format == Format::Json || limits.is_some() || notify) {
+        usage("--json, --tcp, --udp, --alarms and --notify need --daemon");
+    }
+    let output = match output.unwrap_or(Ok(Output::Stdout)) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    // Probes without a name are shown by their ROM ID
+    let names = match names::load(config.as_deref().unwrap_or(DEFAULT_CONFIG)) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && config.is_none() => HashMap::new(),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    // Not saved to the EEPROM, so this is back to the default after a power
+    // cycle; a daemon sets it again when it starts
+    if let Some(bits) = resolution {
+        match ds18b20::DS18B20::all() {
+            Ok(sensors) => {
+                for sensor in &sensors {
+                    if let Err(e) = sensor.set_resolution(bits) {
+                        eprintln!("{}: {}", sensor.id(), e);
+                        process::exit(1);
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if daemon {
+        let limits = match limits.map(alarms::load).transpose() {
+            Ok(limits) => limits.unwrap_or_default(),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        let monitor = alarms::Monitor::new(limits);
+        // NMEA consumers expect Celsius
+        run(Daemon { interval, format, output, monitor, notify }, &names, units.unwrap_or(Units::Celsius));
+    }
+
+    let sensors = match ds18b20::DS18B20::all() {
+        Ok(sensors) => sensors,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    // Converting them all at once, the probes are read without waiting for
+    // a conversion each
+    if let Err(e) = ds18b20::bulk_convert() {
+        eprintln!("therm_bulk_read: {}", e);
+    }
+
+    let units = units.unwrap_or(Units::Fahrenheit);
+    let mut failed = false;
+    for sensor in &sensors {
+        let name = names.get(sensor.id()).map_or(sensor.id(), String::as_str);
+        match sensor.read_temp() {
+            Ok(temp) if raw => println!("{}: {}", name, temp.as_i32()),
+            Ok(temp) => println!("{}: {:.1} {}", name, units.convert(temp), units.symbol()),
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+// Polls every probe forever; the bus is scanned each time so that probes
+// can come and go
+fn run(mut daemon: Daemon, names: &HashMap<String, String>, units: Units) -> ! {
+    // The last good reading of each probe, which tells a real 85 °C from a
+    // reset
+    let mut last = HashMap::new();
+    loop {
+        let started = Instant::now();
+        // One conversion time for all the probes rather than one each
+        if let Err(e) = ds18b20::bulk_convert() {
+            eprintln!("therm_bulk_read: {}", e);
+        }
+        let mut changes = Vec::new();
+        match ds18b20::DS18B20::all() {
+            Ok(sensors) => {
+                for sensor in &sensors {
+                    let name = names.get(sensor.id()).map_or(sensor.id(), String::as_str);
+                    let reading = sensor.read_temp_after(last.get(sensor.id()).copied());
+                    match &reading {
+                        Ok(temp) => {
+                            last.insert(sensor.id().to_string(), *temp);
+                        }
+                        Err(e) => eprintln!("{}: {}", name, e),
+                    }
+                    let celsius = reading.as_ref().ok().map(|temp| temp.to_celsius());
+                    changes.extend(daemon.monitor.reading(sensor.id(), name, celsius, Utc::now()));
+                    let line = match (daemon.format, reading) {
+                        (Format::Xdr, Ok(temp)) => output::xdr(name, temp, units),
+                        (Format::Xdr, Err(_)) => continue,
+                        (Format::Json, reading) => {
+                            output::json(Utc::now(), sensor.id(), name, reading.map_err(|e| e.to_string()), units)
+                        }
+                    };
+                    if let Err(e) = daemon.output.send(&line) {
+                        eprintln!("send: {}", e);
+                    }
+                }
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+        changes.extend(daemon.monitor.tick(Utc::now()));
+        alarm(&mut daemon, &changes);
+        thread::sleep(daemon.interval.saturating_sub(started.elapsed()));
+    }
+}
+
+// Sends and notifies the alarms raised or cleared by this poll. ALR is
+// repeated every poll while an alarm is active, as displays expect;
+// JSON lines only report the changes.
+fn alarm(daemon: &mut Daemon, changes: &[alarms::Alarm]) {
+    let now = Utc::now();
+    let mut lines = Vec::new();
+    for alarm in changes {
+        eprintln!("{}", alarm.text);
+        if daemon.notify {
+            alarms::notify(alarm);
+        }
+        lines.push(match daemon.format {
+            Format::Xdr => alarm.alr(now),
+            Format::Json => alarm.json(now),
+        });
+    }
+    if daemon.format == Format::Xdr {
+        for alarm in daemon.monitor.active() {
+            if !changes.iter().any(|change| change.number == alarm.number) {
+                lines.push(alarm.alr(now));
+            }
+        }
     }
-    else {
-        println!("{:.1} F", temp.to_fahrenheit());
+    for line in lines {
+        if let Err(e) = daemon.output.send(&line) {
+            eprintln!("send: {}", e);
+        }
     }
 }
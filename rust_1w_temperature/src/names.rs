@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::{fs,io};
+use std::path::Path;
+
+/// Friendly names for probes, one per line:
+///
+/// ```text
+/// # ROM ID = name
+/// 28-0316a2795cff = Engine room
+/// 28-0316a27a31ff = Freezer
+/// ```
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, String>> {
+    let path = path.as_ref();
+    parse(&fs::read_to_string(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+fn parse(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut names = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((id, name)) if !id.trim().is_empty() && !name.trim().is_empty() => {
+                names.insert(id.trim().to_string(), name.trim().to_string());
+            }
+            _ => return Err(format!("line {}: expected ROM-ID = name, got {:?}", i + 1, line)),
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let names = parse("# Boat\n28-0316a2795cff = Engine room\n\n28-0316a27a31ff=Freezer\n").unwrap();
+        assert_eq!("Engine room", names["28-0316a2795cff"]);
+        assert_eq!("Freezer", names["28-0316a27a31ff"]);
+        assert_eq!(Err("line 2: expected ROM-ID = name, got \"Cabin\"".to_string()), parse("\nCabin"));
+        assert!(load("/nonexistent/probes.conf").is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::ds18b20::MilliCelsius;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::json;
+use std::io::{self, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Units {
+    Celsius,
+    Kelvin,
+    Fahrenheit,
+}
+
+impl Units {
+    pub fn convert(self, temp: MilliCelsius) -> f64 {
+        match self {
+            Units::Celsius => temp.to_celsius(),
+            Units::Kelvin => temp.to_kelvin(),
+            Units::Fahrenheit => temp.to_fahrenheit(),
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Units::Celsius => "C",
+            Units::Kelvin => "K",
+            Units::Fahrenheit => "F",
+        }
+    }
+}
+
+impl FromStr for Units {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Units, String> {
+        match s.to_ascii_uppercase().as_str() {
+            "C" | "CELSIUS" => Ok(Units::Celsius),
+            "K" | "KELVIN" => Ok(Units::Kelvin),
+            "F" | "FAHRENHEIT" => Ok(Units::Fahrenheit),
+            _ => Err(format!("unknown units {:?}, expected C, K or F", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Xdr,
+    Json,
+}
+
+/// Adds the `*hh` checksum: the XOR of everything between `$` and `*`.
+pub fn sentence(data: &str) -> String {
+    let checksum = data.bytes().fold(0, |sum, b| sum ^ b);
+    format!("${}*{:02X}", data, checksum)
+}
+
+/// A sentence field cannot hold the NMEA delimiters or anything but
+/// printable ASCII.
+pub fn field(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            ',' | '*' | '$' | '!' | '\\' | '^' | '~' => '_',
+            c if c != ' ' && !c.is_ascii_graphic() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+// Many readers split transducer names on spaces
+fn transducer_name(name: &str) -> String {
+    field(name).replace(' ', "_")
+}
+
+/// One XDR temperature sentence for a probe, e.g.
+/// `$IIXDR,C,25.1,C,Engine_room*hh`.
+pub fn xdr(name: &str, temp: MilliCelsius, units: Units) -> String {
+    sentence(&format!("IIXDR,C,{:.1},{},{}", units.convert(temp), units.symbol(), transducer_name(name)))
+}
+
+/// One JSON line for a probe; a probe that could not be read carries the
+/// error instead of a temperature.
+pub fn json(time: DateTime<Utc>, id: &str, name: &str, reading: Result<MilliCelsius, String>, units: Units) -> String {
+    let mut line = json!({
+        "time": time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "id": id,
+        "name": name,
+    });
+    match reading {
+        Ok(temp) => {
+            line["temperature"] = json!((units.convert(temp) * 1000.0).round() / 1000.0);
+            line["units"] = json!(units.symbol());
+        }
+        Err(e) => line["error"] = json!(e),
+    }
+    line.to_string()
+}
+
+/// Where the lines go: stdout, every client of a TCP port, or UDP datagrams
+/// (to a broadcast address too).
+pub enum Output {
+    Stdout,
+    Tcp { listener: TcpListener, clients: Vec<TcpStream> },
+    Udp { socket: UdpSocket, target: String },
+}
+
+impl Output {
+    pub fn tcp<A: ToSocketAddrs>(bind: A) -> io::Result<Output> {
+        let listener = TcpListener::bind(bind)?;
+        listener.set_nonblocking(true)?;
+        Ok(Output::Tcp { listener, clients: Vec::new() })
+    }
+
+    pub fn udp(target: &str) -> io::Result<Output> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_broadcast(true)?;
+        // Resolve now so that a bad address fails at startup
+        target.to_socket_addrs()?;
+        Ok(Output::Udp { socket, target: target.to_string() })
+    }
+
+    /// Sends one line; TCP clients that went away are dropped.
+    pub fn send(&mut self, line: &str) -> io::Result<()> {
+        let line = format!("{}\r\n", line);
+        match self {
+            Output::Stdout => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(line.as_bytes())?;
+                stdout.flush()
+            }
+            Output::Tcp { listener, clients } => {
+                loop {
+                    match listener.accept() {
+                        Ok((client, address)) => {
+                            eprintln!("client {} connected", address);
+                            // A stalled client must not hold up the polling
+                            client.set_write_timeout(Some(Duration::from_secs(1)))?;
+                            clients.push(client);
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+                clients.retain_mut(|client| client.write_all(line.as_bytes()).is_ok());
+                Ok(())
+            }
+            Output::Udp { socket, target } => socket.send_to(line.as_bytes(), target.as_str()).map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::Value;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_xdr() {
+        let temp = crate::ds18b20::parse_w1_slave(
+            "91 01 4b 46 7f ff 0f 10 25 : crc=25 YES\n91 01 4b 46 7f ff 0f 10 25 t=25062",
+        )
+        .unwrap()
+        .temperature()
+        .unwrap();
+        assert_eq!("$IIXDR,C,25.1,C,Engine_room*38", xdr("Engine room", temp, Units::Celsius));
+        assert_eq!("$IIXDR,C,298.2,K,28-0316a2795cff*41", xdr("28-0316a2795cff", temp, Units::Kelvin));
+        assert!(xdr("Fridge, top", temp, Units::Fahrenheit).starts_with("$IIXDR,C,77.1,F,Fridge__top*"));
+        assert_eq!(Ok(Units::Kelvin), "k".parse());
+        assert!("R".parse::<Units>().is_err());
+    }
+
+    #[test]
+    fn test_json_and_tcp() {
+        let time = Utc.with_ymd_and_hms(2024, 7, 1, 12, 0, 0).unwrap();
+        let line: Value = serde_json::from_str(&json(
+            time,
+            "28-0416c1b2e4ff",
+            "Freezer",
+            Err("bad CRC".to_string()),
+            Units::Celsius,
+        ))
+        .unwrap();
+        assert_eq!("2024-07-01T12:00:00.000Z", line["time"]);
+        assert_eq!("bad CRC", line["error"]);
+        assert!(line.get("temperature").is_none());
+
+        let mut output = Output::tcp("127.0.0.1:0").unwrap();
+        let address = match &output {
+            Output::Tcp { listener, .. } => listener.local_addr().unwrap(),
+            _ => unreachable!(),
+        };
+        let client = TcpStream::connect(address).unwrap();
+        output.send("$IIXDR,C,25.1,C,Engine_room*38").unwrap();
+        let mut received = String::new();
+        BufReader::new(client).read_line(&mut received).unwrap();
+        assert_eq!("$IIXDR,C,25.1,C,Engine_room*38\r\n", received);
+    }
+}
@@ -1,12 +1,42 @@
-use std::{io,num};
+use std::{fmt,io,num};
 
 #[derive(Debug)]
 pub enum W1Error {
     Io(io::Error),
     Parse(num::ParseIntError),
-    BadSerialConnection,
+    // w1_slave did not have the two "xx xx .. : crc=xx YES" and "xx xx .. t=N" lines
+    Malformed(String),
+    // The scratchpad CRC did not match (the kernel's "NO"): noise on the bus
+    BadCrc { expected: u8, found: u8 },
+    // 85 °C is the power-on value of the temperature register, so the
+    // sensor was reset (e.g. lost power) and has not converted since. A real
+    // 85 °C looks the same on some probes; see W1Slave::temperature.
+    PowerOnReset,
+    // Every scratchpad byte read as zero: the data line is held low
+    BusError,
+    // No DS18B20 on any bus
+    NoSensors,
+    // A resolution, alarm or conversion time the DS18B20 cannot take
+    InvalidSetting(String),
 }
 
+impl fmt::Display for W1Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            W1Error::Io(err) => write!(f, "{}", err),
+            W1Error::Parse(err) => write!(f, "bad temperature: {}", err),
+            W1Error::Malformed(data) => write!(f, "unexpected w1_slave contents {:?}", data),
+            W1Error::BadCrc { expected, found } => write!(f, "bad CRC: expected {:02x}, found {:02x}", expected, found),
+            W1Error::PowerOnReset => write!(f, "power-on reset value (85 °C), no conversion yet"),
+            W1Error::BusError => write!(f, "bus error, the scratchpad reads all zeros"),
+            W1Error::NoSensors => write!(f, "unable to find a DS18B20"),
+            W1Error::InvalidSetting(setting) => write!(f, "invalid setting: {}", setting),
+        }
+    }
+}
+
+impl std::error::Error for W1Error {}
+
 impl From<io::Error> for W1Error {
     fn from(err: io::Error) -> W1Error {
         W1Error::Io(err)
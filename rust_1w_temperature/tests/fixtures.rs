@@ -0,0 +1,119 @@
+// Reads the fake sysfs trees in tests/fixtures, which look like
+// /sys/bus/w1/devices on a Pi with w1-gpio loaded
+use rust_1w_temperature::ds18b20::{bulk_convert_in, Alarms, DS18B20};
+use rust_1w_temperature::w1_errors::W1Error;
+use std::{env,fs,io,process};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+#[test]
+fn test_enumerate_master_slaves() {
+    let ids: Vec<String> = DS18B20::all_in(fixture("devices"))
+        .unwrap()
+        .iter()
+        .map(|sensor| sensor.id().to_string())
+        .collect();
+    // The DS18S20 (family 10) is not one of ours; 28-0516d3a8b9ff is listed
+    // by the bus master but has no directory
+    assert_eq!(vec!["28-0316a2795cff", "28-0316a27a31ff", "28-0416c1b2e4ff", "28-0516d3a8b9ff"], ids);
+}
+
+#[test]
+fn test_read_every_case() {
+    let sensors = DS18B20::all_in(fixture("devices")).unwrap();
+    assert_eq!(25062, sensors[0].read_temp().unwrap().as_i32());
+    assert_eq!(-26125, sensors[1].read_temp().unwrap().as_i32());
+    assert!(matches!(sensors[2].read_temp(), Err(W1Error::BadCrc { expected: 0x82, found: 0x83 })));
+    match sensors[3].read_temp() {
+        Err(W1Error::Io(e)) => assert_eq!(io::ErrorKind::NotFound, e.kind()),
+        other => panic!("expected a missing device, got {:?}", other.map(|temp| temp.as_i32())),
+    }
+
+    let freezer = DS18B20::new_for_id_in(fixture("devices"), "28-0316a27a31ff".to_string());
+    assert!(!freezer.read_w1_slave().unwrap().scratchpad.iter().all(|&b| b == 0));
+}
+
+#[test]
+fn test_first_sensor() {
+    assert_eq!("28-0316a2795cff", DS18B20::new_in(fixture("devices")).unwrap().id());
+    // Without w1_bus_master entries the device directories are scanned
+    assert_eq!("28-0316a2795cff", DS18B20::new_in(fixture("scan")).unwrap().id());
+    assert!(matches!(DS18B20::new_in(fixture("empty")), Err(W1Error::NoSensors)));
+    assert!(matches!(DS18B20::new_in(fixture("nonexistent")), Err(W1Error::Io(_))));
+}
+
+// A writable copy of a fixture, for the tests that change settings
+fn scratch_copy(name: &str) -> PathBuf {
+    fn copy(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_dir() {
+                copy(&entry.path(), &to.join(entry.file_name()));
+            } else {
+                fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+    let to = env::temp_dir().join(format!("rust_1w_temperature-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&to);
+    copy(&fixture(name), &to);
+    to
+}
+
+#[test]
+fn test_settings() {
+    let root = scratch_copy("devices");
+    let sensors = DS18B20::all_in(&root).unwrap();
+    let engine = &sensors[0];
+    assert_eq!(12, engine.resolution().unwrap());
+    assert_eq!(Alarms { low: 70, high: 75 }, engine.alarms().unwrap());
+    assert_eq!(Duration::from_millis(750), engine.conv_time().unwrap());
+
+    engine.set_resolution(10).unwrap();
+    assert_eq!("10", fs::read_to_string(root.join("28-0316a2795cff/resolution")).unwrap());
+    assert_eq!(10, engine.resolution().unwrap());
+    assert!(matches!(engine.set_resolution(13), Err(W1Error::InvalidSetting(_))));
+
+    engine.set_alarms(Alarms { low: -10, high: 95 }).unwrap();
+    assert_eq!("-10 95", fs::read_to_string(root.join("28-0316a2795cff/alarms")).unwrap());
+    assert_eq!(Alarms { low: -10, high: 95 }, engine.alarms().unwrap());
+    assert!(matches!(engine.set_alarms(Alarms { low: 95, high: -10 }), Err(W1Error::InvalidSetting(_))));
+    assert!(matches!(engine.set_alarms(Alarms { low: -60, high: 0 }), Err(W1Error::InvalidSetting(_))));
+
+    engine.set_conv_time(0).unwrap();
+    assert_eq!("0", fs::read_to_string(root.join("28-0316a2795cff/conv_time")).unwrap());
+    engine.save_settings().unwrap();
+    assert_eq!("save", fs::read_to_string(root.join("28-0316a2795cff/eeprom_cmd")).unwrap());
+
+    // Without conv_time the resolution gives the datasheet time, if it is one
+    fs::remove_file(root.join("28-0316a2795cff/conv_time")).unwrap();
+    assert_eq!(Duration::from_micros(187_500), engine.conv_time().unwrap());
+    fs::write(root.join("28-0316a2795cff/resolution"), "8").unwrap();
+    assert!(matches!(engine.conv_time(), Err(W1Error::InvalidSetting(_))));
+
+    // The freezer is on an older kernel: its settings come from the scratchpad
+    let freezer = &sensors[1];
+    assert_eq!(12, freezer.resolution().unwrap());
+    assert_eq!(Alarms { low: 70, high: 75 }, freezer.alarms().unwrap());
+    assert_eq!(Duration::from_millis(750), freezer.conv_time().unwrap());
+    // and a scratchpad with a bad CRC says nothing about them
+    assert!(matches!(sensors[2].resolution(), Err(W1Error::BadCrc { .. })));
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_bulk_convert() {
+    let root = scratch_copy("devices");
+    assert!(bulk_convert_in(&root).unwrap());
+    assert_eq!("trigger", fs::read_to_string(root.join("w1_bus_master1/therm_bulk_read")).unwrap());
+    assert_eq!(25062, DS18B20::new_in(&root).unwrap().read_temp().unwrap().as_i32());
+    // Without therm_bulk_read the probes are converted as they are read
+    assert!(!bulk_convert_in(fixture("empty")).unwrap());
+    fs::remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,216 @@
+use std::{io,thread};
+use std::time::Duration;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use i2cdev::linux::LinuxI2CDevice;
+
+/// What a fingerprint asks of a device. Byte and word reads only write the
+/// register pointer; Write and Command send a command the chip acts on, so
+/// they are left out with --read-only.
+pub enum Check {
+    // The register, masked, reads this value
+    Byte { register: u8, mask: u8, value: u8 },
+    // A big-endian 16 bit register reads this value
+    Word { register: u8, value: u16 },
+    // A plain one-byte read gets no answer
+    NoReply,
+    // A command to send first, e.g. to wake the chip
+    Write(&'static [u8]),
+    // A 16 bit command answered by a 16 bit word (and a CRC), masked
+    Command { command: u16, mask: u16, value: u16 },
+}
+
+pub struct Fingerprint {
+    pub part: &'static str,
+    // The names in I2C_SCANNER_KNOWN_DEVICES this confirms
+    pub names: &'static [&'static str],
+    pub addresses: &'static [u8],
+    pub checks: &'static [Check],
+}
+
+impl Fingerprint {
+    fn writes(&self) -> bool {
+        self.checks.iter().any(|check| matches!(check, Check::Write(_) | Check::Command { .. }))
+    }
+}
+
+const fn byte(register: u8, value: u8) -> Check {
+    Check::Byte { register, mask: 0xff, value }
+}
+
+const BMX_ADDRESSES: &[u8] = &[0x76, 0x77];
+const MPU_ADDRESSES: &[u8] = &[0x68, 0x69];
+const INA_ADDRESSES: &[u8] = &[0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f];
+
+// Tried in order; the first whose checks all pass identifies the device
+pub const FINGERPRINTS: [Fingerprint; 21] = [
+    // Bosch chip ID at 0xD0
+    Fingerprint { part: "BME280", names: &["BME280"], addresses: BMX_ADDRESSES, checks: &[byte(0xd0, 0x60)] },
+    Fingerprint { part: "BMP280", names: &["BMP280"], addresses: BMX_ADDRESSES, checks: &[byte(0xd0, 0x58)] },
+    // Engineering samples of the BMP280
+    Fingerprint { part: "BMP280", names: &["BMP280"], addresses: BMX_ADDRESSES, checks: &[byte(0xd0, 0x56)] },
+    Fingerprint { part: "BMP280", names: &["BMP280"], addresses: BMX_ADDRESSES, checks: &[byte(0xd0, 0x57)] },
+    // The same chip ID, told apart by the variant ID at 0xF0
+    Fingerprint { part: "BME680", names: &["BME680"], addresses: BMX_ADDRESSES, checks: &[byte(0xd0, 0x61), byte(0xf0, 0x00)] },
+    Fingerprint { part: "BME688", names: &["BME688"], addresses: BMX_ADDRESSES, checks: &[byte(0xd0, 0x61), byte(0xf0, 0x01)] },
+    Fingerprint { part: "BMP180/BMP085", names: &["BMP180", "BMP085"], addresses: &[0x77], checks: &[byte(0xd0, 0x55)] },
+    Fingerprint { part: "SPL06-007", names: &["SPL06-007"], addresses: BMX_ADDRESSES, checks: &[byte(0x0d, 0x10)] },
+    // InvenSense WHO_AM_I at 0x75, or 0x00 on the ICM-20948
+    Fingerprint { part: "MPU-9250", names: &["MPU-9250"], addresses: MPU_ADDRESSES, checks: &[byte(0x75, 0x71)] },
+    Fingerprint { part: "MPU6050", names: &["MPU6050"], addresses: MPU_ADDRESSES, checks: &[byte(0x75, 0x68)] },
+    Fingerprint { part: "ICM-20948", names: &["ICM-20948"], addresses: MPU_ADDRESSES, checks: &[byte(0x00, 0xea)] },
+    // Manufacturer 0x5449 ("TI") and device IDs
+    Fingerprint { part: "HDC1080", names: &["HDC1080"], addresses: &[0x40], checks: &[Check::Word { register: 0xfe, value: 0x5449 }, Check::Word { register: 0xff, value: 0x1050 }] },
+    Fingerprint { part: "INA260", names: &["INA260"], addresses: INA_ADDRESSES, checks: &[Check::Word { register: 0xfe, value: 0x5449 }, Check::Word { register: 0xff, value: 0x2270 }] },
+    // No ID register: the configuration's reset value, so only until
+    // something configures it
+    Fingerprint { part: "INA219", names: &["INA219"], addresses: INA_ADDRESSES, checks: &[Check::Word { register: 0x00, value: 0x399f }] },
+    Fingerprint { part: "MCP9808", names: &["MCP9808"], addresses: &[0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f], checks: &[Check::Word { register: 0x06, value: 0x0054 }] },
+    Fingerprint { part: "LIS3DH", names: &["LIS3DH"], addresses: &[0x18, 0x19], checks: &[byte(0x0f, 0x33)] },
+    Fingerprint { part: "ADXL345", names: &["ADXL345"], addresses: &[0x53, 0x1d], checks: &[byte(0x00, 0xe5)] },
+    Fingerprint { part: "HMC5883", names: &["HMC5883"], addresses: &[0x1e], checks: &[byte(0x0a, b'H'), byte(0x0b, b'4'), byte(0x0c, b'3')] },
+    Fingerprint { part: "CCS811", names: &["CCS811"], addresses: &[0x5a, 0x5b], checks: &[byte(0x20, 0x81)] },
+    // No ID register either: the reserved bits of the AFE gain and the
+    // always-set top bit of register 2
+    Fingerprint { part: "AS3935", names: &["AS3935"], addresses: &[0x03], checks: &[Check::Byte { register: 0x00, mask: 0xc1, value: 0x00 }, Check::Byte { register: 0x02, mask: 0x80, value: 0x80 }] },
+    // Wake it up, then read the ID register. It shares 0x70 with muxes, whose
+    // channels the wake-up command would switch; a mux answers a plain read
+    // with its channels, the SHTC3 does not.
+    Fingerprint { part: "SHTC3", names: &["SHTC3"], addresses: &[0x70], checks: &[Check::NoReply, Check::Write(&[0x35, 0x17]), Check::Command { command: 0xefc8, mask: 0x083f, value: 0x0807 }] },
+];
+
+/// Register access for fingerprinting, so that it can be tried on a fake
+/// device.
+pub trait Registers {
+    fn read_byte_data(&mut self, register: u8) -> io::Result<u8>;
+    // As the chips send it, most significant byte first
+    fn read_word_be(&mut self, register: u8) -> io::Result<u16>;
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    fn read(&mut self, data: &mut [u8]) -> io::Result<()>;
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Registers for LinuxI2CDevice {
+    fn read_byte_data(&mut self, register: u8) -> io::Result<u8> {
+        Ok(i2cdev::core::I2CDevice::smbus_read_byte_data(self, register)?)
+    }
+
+    fn read_word_be(&mut self, register: u8) -> io::Result<u16> {
+        // SMBus words are little-endian
+        Ok(i2cdev::core::I2CDevice::smbus_read_word_data(self, register)?.swap_bytes())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        Ok(i2cdev::core::I2CDevice::write(self, data)?)
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> io::Result<()> {
+        Ok(i2cdev::core::I2CDevice::read(self, data)?)
+    }
+}
+
+fn passes<R: Registers>(dev: &mut R, check: &Check) -> io::Result<bool> {
+    match *check {
+        Check::Byte { register, mask, value } => Ok(dev.read_byte_data(register)? & mask == value),
+        Check::Word { register, value } => Ok(dev.read_word_be(register)? == value),
+        Check::NoReply => Ok(dev.read(&mut [0u8]).is_err()),
+        Check::Write(data) => {
+            dev.write(data)?;
+            // Long enough for the SHTC3 to wake up
+            thread::sleep(Duration::from_millis(1));
+            Ok(true)
+        }
+        Check::Command { command, mask, value } => {
+            dev.write(&command.to_be_bytes())?;
+            let mut answer = [0u8; 3];
+            dev.read(&mut answer)?;
+            Ok(u16::from_be_bytes([answer[0], answer[1]]) & mask == value)
+        }
+    }
+}
+
+/// The first fingerprint for this address that the device matches. A check
+/// that fails to read only rules that fingerprint out; with read_only,
+/// fingerprints that send commands are skipped.
+pub fn identify<R: Registers>(dev: &mut R, addr: u8, read_only: bool) -> Option<&'static Fingerprint> {
+    FINGERPRINTS.iter().filter(|fingerprint| fingerprint.addresses.contains(&addr)).find(|fingerprint| {
+        !(read_only && fingerprint.writes())
+            && fingerprint.checks.iter().all(|check| passes(dev, check).unwrap_or(false))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Registers that read as given; anything else does not answer
+    #[derive(Default)]
+    struct Fake {
+        bytes: HashMap<u8, u8>,
+        words: HashMap<u8, u16>,
+        // What a plain read returns before and after a command
+        plain: Option<u8>,
+        id: Option<[u8; 3]>,
+        written: Vec<Vec<u8>>,
+    }
+
+    impl Registers for Fake {
+        fn read_byte_data(&mut self, register: u8) -> io::Result<u8> {
+            self.bytes.get(&register).copied().ok_or_else(|| io::Error::from_raw_os_error(6))
+        }
+
+        fn read_word_be(&mut self, register: u8) -> io::Result<u16> {
+            self.words.get(&register).copied().ok_or_else(|| io::Error::from_raw_os_error(6))
+        }
+
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.written.push(data.to_vec());
+            Ok(())
+        }
+
+        fn read(&mut self, data: &mut [u8]) -> io::Result<()> {
+            let no_answer = || io::Error::from_raw_os_error(6);
+            match self.written.is_empty() {
+                true => data.fill(self.plain.ok_or_else(no_answer)?),
+                false => data.copy_from_slice(&self.id.ok_or_else(no_answer)?[..data.len()]),
+            }
+            Ok(())
+        }
+    }
+
+    fn fake(bytes: &[(u8, u8)]) -> Fake {
+        Fake { bytes: bytes.iter().copied().collect(), ..Fake::default() }
+    }
+
+    fn fake_words(words: &[(u8, u16)]) -> Fake {
+        Fake { words: words.iter().copied().collect(), ..Fake::default() }
+    }
+
+    #[test]
+    fn test_identify() {
+        let part = |dev: &mut Fake, addr, read_only| identify(dev, addr, read_only).map(|fingerprint| fingerprint.part);
+        assert_eq!(Some("BME280"), part(&mut fake(&[(0xd0, 0x60)]), 0x76, true));
+        assert_eq!(Some("BMP280"), part(&mut fake(&[(0xd0, 0x58)]), 0x77, true));
+        assert_eq!(Some("BME688"), part(&mut fake(&[(0xd0, 0x61), (0xf0, 0x01)]), 0x77, true));
+        // Only at the part's own addresses
+        assert_eq!(None, part(&mut fake(&[(0xd0, 0x60)]), 0x68, true));
+        assert_eq!(Some("MPU-9250"), part(&mut fake(&[(0x75, 0x71)]), 0x68, true));
+        assert_eq!(Some("ICM-20948"), part(&mut fake(&[(0x00, 0xea), (0x75, 0x00)]), 0x69, true));
+        assert_eq!(Some("INA219"), part(&mut fake_words(&[(0x00, 0x399f)]), 0x40, true));
+        assert_eq!(Some("HDC1080"), part(&mut fake_words(&[(0xfe, 0x5449), (0xff, 0x1050)]), 0x40, true));
+        assert_eq!(Some("INA260"), part(&mut fake_words(&[(0xfe, 0x5449), (0xff, 0x2270)]), 0x45, true));
+        assert_eq!(None, part(&mut fake(&[]), 0x76, true));
+
+        // The SHTC3 has to be woken up, which --read-only rules out
+        let mut shtc3 = Fake { id: Some([0x08, 0x87, 0x5b]), ..Fake::default() };
+        assert_eq!(None, part(&mut shtc3, 0x70, true));
+        assert!(shtc3.written.is_empty());
+        assert_eq!(Some("SHTC3"), part(&mut shtc3, 0x70, false));
+        assert_eq!(vec![vec![0x35, 0x17], vec![0xef, 0xc8]], shtc3.written);
+        // A mux at 0x70 answers a plain read, so it is never sent the wake-up
+        let mut mux = Fake { plain: Some(0x00), ..Fake::default() };
+        assert_eq!(None, part(&mut mux, 0x70, false));
+        assert!(mux.written.is_empty());
+    }
+}
@@ -1,11 +1,18 @@
 extern crate i2cdev;
 
+mod identify;
+mod mux;
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use i2cdev::linux::*;
+use identify::Fingerprint;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::{env,fs,io,process};
 
 type I2cDeviceInfo = (&'static str, &'static str, &'static [u8]);
 
-const I2C_SCANNER_KNOWN_DEVICES: [I2cDeviceInfo; 220] = [
+const I2C_SCANNER_KNOWN_DEVICES: [I2cDeviceInfo; 221] = [
     ("47L04/47C04/47L16/47C16", "4K/16K I2C Serial EERAM - Control register", &[0x1c, 0x18, 0x1a, 0x1e]),
     ("47L04/47C04/47L16/47C16", "4K/16K I2C Serial EERAM - SRAM Memory with EEPROM backup", &[0x54, 0x50, 0x56, 0x52]),
     ("AD5243", "Dual, 256-Position, I2 C-Compatible Digital Potentiometer", &[0x2f]),
@@ -23,6 +30,7 @@ const I2C_SCANNER_KNOWN_DEVICES: [I2cDeviceInfo; 220] = [
     ("AMG8833", "IR Thermal Camera Breakout", &[0x68, 0x69]),
     ("APDS-9250", "Digital RGB, IR and Ambient Light Sensor", &[0x52]),
     ("APDS-9960", "IR/Color/Proximity Sensor", &[0x39]),
+    ("AS3935", "Franklin Lightning Sensor", &[0x03, 0x02, 0x01]),
     ("AS7262", "6-channel visible spectral_ID device with electronic shutter and smart interface", &[0x49]),
     ("AT24C02N", "Two-wire Serial EEPROM 2K (256 x 8)", &[0x57, 0x54, 0x50, 0x56, 0x53, 0x55, 0x52, 0x51]),
     ("AT24C64", "2-Wire Serial EEPROM 64K (8192 x 8)", &[0x57, 0x54, 0x50, 0x56, 0x53, 0x55, 0x52, 0x51]),
@@ -228,45 +236,481 @@ const I2C_SCANNER_KNOWN_DEVICES: [I2cDeviceInfo; 220] = [
     ("XD8574A", "I²C 8-Bit I/O Expander", &[0x23, 0x24, 0x26, 0x22, 0x27, 0x21, 0x25, 0x20]),
 ];
 
-fn lookup(addr: u8) {
-    for i in 0 .. I2C_SCANNER_KNOWN_DEVICES.len() {
-        let addresses = I2C_SCANNER_KNOWN_DEVICES[i].2;
-        for j in 0 .. addresses.len() {
-            if addr == addresses[j] {
-                println!("  {}:  {}", I2C_SCANNER_KNOWN_DEVICES[i].0, I2C_SCANNER_KNOWN_DEVICES[i].1);
-            }
+fn lookup(addr: u8) -> impl Iterator<Item = &'static I2cDeviceInfo> {
+    I2C_SCANNER_KNOWN_DEVICES.iter().filter(move |(_, _, addresses)| addresses.contains(&addr))
+}
+
+static DEFAULT_BUS: &str = "/dev/i2c-1";
+static USAGE: &str = "Usage: rust_i2c_scan [-b|--bus N|PATH] [-a|--all] [-i|--identify] [-r|--read-only] [-m|--mux] [--json]";
+// Devices the kernel knows about, as BUS-00ADDR, with a driver link once bound
+static SYSFS_I2C_DEVICES: &str = "/sys/bus/i2c/devices";
+
+// What a scanned address turned out to be; addresses that did not answer
+// are left out
+enum Status {
+    // With the part its ID registers identified
+    Found(Option<&'static Fingerprint>),
+    // A kernel driver has claimed the address, so it was not probed; the
+    // driver's name when sysfs has it
+    Busy(Option<String>),
+    // The probe failed some other way than with no answer, e.g. the adapter
+    // cannot do the quick write
+    Error(String),
+}
+
+fn usage(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+// "1" is /dev/i2c-1; anything else is a path
+fn bus_path(bus: &str) -> String {
+    match bus.parse::<u32>() {
+        Ok(n) => format!("/dev/i2c-{}", n),
+        Err(_) => bus.to_string(),
+    }
+}
+
+fn bus_number(path: &str) -> Option<u32> {
+    path.strip_prefix("/dev/i2c-")?.parse().ok()
+}
+
+// The kernel driver bound to an address, e.g. rtc-ds1307 for 1-0068
+fn driver(bus: Option<u32>, addr: u8) -> Option<String> {
+    let link = fs::read_link(format!("{}/{}-{:04x}/driver", SYSFS_I2C_DEVICES, bus?, addr)).ok()?;
+    Some(link.file_name()?.to_string_lossy().into_owned())
+}
+
+// Every /dev/i2c-N, in bus number order
+fn all_buses() -> io::Result<Vec<String>> {
+    let mut buses = Vec::new();
+    for entry in fs::read_dir("/dev")? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(n) = name.strip_prefix("i2c-").and_then(|n| n.parse::<u32>().ok()) {
+            buses.push(n);
         }
     }
+    buses.sort();
+    Ok(buses.into_iter().map(|n| format!("/dev/i2c-{}", n)).collect())
 }
 
-fn main() {
+// The errno of a failed ioctl, however i2cdev wrapped it
+fn errno(err: LinuxI2CError) -> (Option<i32>, String) {
+    let err = io::Error::from(err);
+    (err.raw_os_error(), err.to_string())
+}
+
+// i2cdetect's range: the rest is reserved, and 0x00 is the general call that
+// every device answers
+const FIRST_ADDRESS: u8 = 0x03;
+const LAST_ADDRESS: u8 = 0x77;
+
+// How to scan: identify reads ID registers of the devices found, and
+// read_only probes by reading alone (i2cdetect -r), as a quick write can
+// corrupt some EEPROMs, and leaves out identification that sends commands;
+// mux looks behind the channels of muxes
+#[derive(Clone, Copy)]
+struct Options {
+    identify: bool,
+    read_only: bool,
+    mux: bool,
+}
+
+// A bus with what is behind the channels of its muxes
+struct BusScan {
+    found: Vec<(u8, Status)>,
+    muxes: Vec<Mux>,
+}
 
+struct Mux {
+    addr: u8,
+    channels: Vec<Channel>,
+}
+
+struct Channel {
+    number: u8,
+    // The kernel's adapter for the channel, when the kernel drives the mux
+    bus: Option<String>,
+    // Only what was not already on the bus itself, which stays visible
+    // whatever channel is on
+    found: io::Result<Vec<(u8, Status)>>,
+}
+
+// Skips the addresses in skip, which are known already
+fn scan(path: &str, options: Options, skip: &[u8]) -> io::Result<Vec<(u8, Status)>> {
     use crate::i2cdev::core::I2CDevice;
 
-    for addr in 0..=127 {
-        //println!("Scanning Address {:#02x}", addr as u8);
-
-        // Scan Address
-        let mut dev = LinuxI2CDevice::new("/dev/i2c-1", addr).unwrap();
-        if (addr >= 0x30 && addr <= 0x37) || (addr >= 0x50 && addr <= 0x57) {
-            match dev.smbus_read_byte() {
-                Ok(_val) => {
-                    println!("Found Address {:#02x}", addr as u8);
-                    lookup(addr as u8);
-                },
-                Err(_err) => {
-                },
+    // The bus itself has to open; after that every address gets its own verdict
+    fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let bus = bus_number(path);
+    let mut found = Vec::new();
+    for addr in (FIRST_ADDRESS..=LAST_ADDRESS).filter(|addr| !skip.contains(addr)) {
+        if let Some(driver) = driver(bus, addr) {
+            found.push((addr, Status::Busy(Some(driver))));
+            continue;
+        }
+        let mut dev = match LinuxI2CDevice::new(path, addr as u16) {
+            Ok(dev) => dev,
+            Err(err) => {
+                match errno(err) {
+                    (Some(libc::EBUSY), _) => found.push((addr, Status::Busy(None))),
+                    (_, err) => found.push((addr, Status::Error(err))),
+                }
+                continue;
+            }
+        };
+        // Reading is safer for EEPROMs and write-only chips that a quick
+        // write could upset, as i2cdetect does
+        let probe = if options.read_only || (0x30..=0x37).contains(&addr) || (0x50..=0x5f).contains(&addr) {
+            dev.smbus_read_byte().map(|_| ())
+        } else {
+            dev.smbus_write_quick(false)
+        };
+        match probe.map_err(errno) {
+            Ok(()) if options.identify => {
+                found.push((addr, Status::Found(identify::identify(&mut dev, addr, options.read_only))))
+            }
+            Ok(()) => found.push((addr, Status::Found(None))),
+            // No answer
+            Err((Some(libc::ENXIO | libc::EREMOTEIO | libc::EIO | libc::ETIMEDOUT | libc::EAGAIN), _)) => {}
+            Err((_, err)) => found.push((addr, Status::Error(err))),
+        }
+    }
+    Ok(found)
+}
+
+fn scan_bus(path: &str, options: Options) -> io::Result<BusScan> {
+    let found = scan(path, options, &[])?;
+    let muxes = if options.mux { muxes(path, &found, options) } else { Vec::new() };
+    Ok(BusScan { found, muxes })
+}
+
+// Scans behind every mux on the bus, leaving the ones switched by hand with
+// all channels off
+fn muxes(path: &str, found: &[(u8, Status)], options: Options) -> Vec<Mux> {
+    let on_bus: Vec<u8> = found.iter().map(|(addr, _)| *addr).collect();
+    let candidates = found.iter().filter(|(addr, _)| mux::MUX_ADDRESSES.contains(addr));
+    let mut muxes = Vec::new();
+
+    // The kernel's muxes show up as claimed, with an adapter per channel
+    let sysfs = Path::new(SYSFS_I2C_DEVICES);
+    for (addr, _) in candidates.clone().filter(|(_, status)| matches!(status, Status::Busy(_))) {
+        let channels = bus_number(path).map_or_else(Vec::new, |bus| mux::kernel_channels(sysfs, bus, *addr));
+        if channels.is_empty() {
+            continue;
+        }
+        let channels = channels
+            .into_iter()
+            .map(|(number, adapter)| {
+                let bus = format!("/dev/i2c-{}", adapter);
+                Channel { number, found: scan(&bus, options, &on_bus), bus: Some(bus) }
+            })
+            .collect();
+        muxes.push(Mux { addr: *addr, channels });
+    }
+
+    // The rest are switched by hand. Every one is switched off before any is
+    // scanned, so that one's channels do not show up behind another.
+    // Switching writes to whatever answers at 0x70-0x77, so not when reading
+    // only, and never to a part that has been identified.
+    let mut switches: Vec<(u8, mux::Switch<LinuxI2CDevice>)> = candidates
+        .filter(|_| !options.read_only)
+        .filter(|(_, status)| matches!(status, Status::Found(None)))
+        .filter_map(|(addr, _)| {
+            let dev = LinuxI2CDevice::new(path, *addr as u16).ok()?;
+            Some((*addr, mux::Switch::open(dev)?))
+        })
+        .collect();
+    for (addr, switch) in &mut switches {
+        let mut channels = Vec::new();
+        for number in 0..mux::CHANNELS {
+            match switch.select(number) {
+                Ok(()) => channels.push(Channel { number, bus: None, found: scan(path, options, &on_bus) }),
+                // A 4 channel PCA9546 or PCA9545 ignores the upper bits
+                Err(_) if number == 4 => break,
+                Err(e) => channels.push(Channel { number, bus: None, found: Err(e) }),
+            }
+        }
+        muxes.push(Mux { addr: *addr, channels });
+    }
+    // Dropping the switches turns all their channels off
+    drop(switches);
+    muxes.sort_by_key(|mux| mux.addr);
+    muxes
+}
+
+// An address in the tree: the part when identified
+fn node(addr: u8, status: &Status) -> String {
+    match status {
+        Status::Found(Some(fingerprint)) => format!("{:#04x} {}", addr, fingerprint.part),
+        Status::Found(None) => format!("{:#04x}", addr),
+        Status::Busy(Some(driver)) => format!("{:#04x} UU {}", addr, driver),
+        Status::Busy(None) => format!("{:#04x} UU", addr),
+        Status::Error(err) => format!("{:#04x} error: {}", addr, err),
+    }
+}
+
+// bus → mux channel → devices
+fn tree(bus: &str, scanned: &BusScan) -> String {
+    let mut tree = format!("{}\n", bus);
+    let count = scanned.found.len();
+    for (i, (addr, status)) in scanned.found.iter().enumerate() {
+        let (branch, indent) = if i + 1 == count { ("└── ", "    ") } else { ("├── ", "│   ") };
+        let mux = scanned.muxes.iter().find(|mux| mux.addr == *addr);
+        match mux {
+            Some(_) => tree.push_str(&format!("{}{} mux\n", branch, node(*addr, status))),
+            None => tree.push_str(&format!("{}{}\n", branch, node(*addr, status))),
+        }
+        let channels = mux.map_or(&[][..], |mux| &mux.channels[..]);
+        for (j, channel) in channels.iter().enumerate() {
+            let (branch, inner) = if j + 1 == channels.len() { ("└── ", "    ") } else { ("├── ", "│   ") };
+            let adapter = channel.bus.as_ref().map_or(String::new(), |bus| format!(" ({})", bus));
+            match &channel.found {
+                Ok(found) => {
+                    tree.push_str(&format!("{}{}channel {}{}\n", indent, branch, channel.number, adapter));
+                    for (k, (addr, status)) in found.iter().enumerate() {
+                        let leaf = if k + 1 == found.len() { "└── " } else { "├── " };
+                        tree.push_str(&format!("{}{}{}{}\n", indent, inner, leaf, node(*addr, status)));
+                    }
+                }
+                Err(e) => tree.push_str(&format!("{}{}channel {}{}: {}\n", indent, branch, channel.number, adapter, e)),
+            }
+        }
+    }
+    tree
+}
+
+// i2cdetect's table, byte for byte: the address of each device that answered, UU for one
+// a driver has claimed, -- for none and blanks outside the scanned range
+fn grid(found: &[(u8, Status)]) -> String {
+    let mut grid = String::from("     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f\n");
+    for row in (0..128u8).step_by(16) {
+        grid.push_str(&format!("{:02x}: ", row));
+        for addr in row..row + 16 {
+            let cell = match found.iter().find(|(a, _)| *a == addr) {
+                _ if !(FIRST_ADDRESS..=LAST_ADDRESS).contains(&addr) => "   ".to_string(),
+                Some((_, Status::Found(_))) => format!("{:02x} ", addr),
+                Some((_, Status::Busy(_))) => "UU ".to_string(),
+                Some((_, Status::Error(_))) | None => "-- ".to_string(),
+            };
+            grid.push_str(&cell);
+        }
+        grid.push('\n');
+    }
+    grid
+}
+
+// The known devices at an address, narrowed to the identified part
+fn candidates(addr: u8, fingerprint: Option<&'static Fingerprint>) -> impl Iterator<Item = &'static I2cDeviceInfo> {
+    lookup(addr).filter(move |(name, _, _)| fingerprint.is_none_or(|fingerprint| fingerprint.names.contains(name)))
+}
+
+fn print_bus(found: &[(u8, Status)]) {
+    print!("{}", grid(found));
+    for (addr, status) in found {
+        match status {
+            Status::Found(fingerprint) => {
+                match fingerprint {
+                    Some(fingerprint) => println!("Found Address {:#02x}: {}", addr, fingerprint.part),
+                    None => println!("Found Address {:#02x}", addr),
+                }
+                for (name, description, _) in candidates(*addr, *fingerprint) {
+                    println!("  {}:  {}", name, description);
+                }
+            }
+            Status::Busy(Some(driver)) => println!("Busy Address {:#02x} (driver {})", addr, driver),
+            Status::Busy(None) => println!("Busy Address {:#02x} (in use by a kernel driver)", addr),
+            Status::Error(err) => println!("Error at Address {:#02x}: {}", addr, err),
+        }
+    }
+}
+
+fn addresses_json(found: &[(u8, Status)], muxes: &[Mux]) -> Vec<Value> {
+    found
+        .iter()
+        .map(|(addr, status)| {
+            let mut entry = json!({ "address": format!("{:#04x}", addr) });
+            match status {
+                Status::Found(fingerprint) => {
+                    entry["status"] = json!("found");
+                    entry["identified"] = json!(fingerprint.map(|fingerprint| fingerprint.part));
+                    entry["candidates"] = candidates(*addr, *fingerprint)
+                        .map(|(name, description, _)| json!({ "name": name, "description": description }))
+                        .collect();
+                }
+                Status::Busy(driver) => {
+                    entry["status"] = json!("busy");
+                    entry["driver"] = json!(driver);
+                }
+                Status::Error(err) => {
+                    entry["status"] = json!("error");
+                    entry["error"] = json!(err);
+                }
+            }
+            if let Some(mux) = muxes.iter().find(|mux| mux.addr == *addr) {
+                let channels: Vec<Value> = mux
+                    .channels
+                    .iter()
+                    .map(|channel| {
+                        let mut entry = json!({ "channel": channel.number, "bus": channel.bus });
+                        match &channel.found {
+                            Ok(found) => entry["addresses"] = json!(addresses_json(found, &[])),
+                            Err(e) => entry["error"] = json!(e.to_string()),
+                        }
+                        entry
+                    })
+                    .collect();
+                entry["channels"] = json!(channels);
+            }
+            entry
+        })
+        .collect()
+}
+
+fn bus_json(bus: &str, scanned: &io::Result<BusScan>) -> Value {
+    match scanned {
+        Ok(scanned) => json!({ "bus": bus, "addresses": addresses_json(&scanned.found, &scanned.muxes) }),
+        Err(e) => json!({ "bus": bus, "error": e.to_string() }),
+    }
+}
+
+fn main() {
+    let mut buses = Vec::new();
+    let mut all = false;
+    let mut as_json = false;
+    let mut options = Options { identify: false, read_only: false, mux: false };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-b" | "--bus" => match args.next() {
+                Some(bus) => buses.push(bus_path(&bus)),
+                None => usage(&format!("{} needs a bus number or path", arg)),
+            },
+            "-a" | "--all" => all = true,
+            "-i" | "--identify" => options.identify = true,
+            "-r" | "--read-only" => options.read_only = true,
+            "-m" | "--mux" => options.mux = true,
+            "--json" => as_json = true,
+            _ => usage(&format!("unknown option {}", arg)),
+        }
+    }
+    if all {
+        if !buses.is_empty() {
+            usage("--all scans every bus, --bus only one");
+        }
+        buses = match all_buses() {
+            // The channels of the kernel's muxes are shown under them
+            Ok(buses) if options.mux => buses
+                .into_iter()
+                .filter(|bus| bus_number(bus).is_none_or(|n| !mux::is_mux_channel(Path::new(SYSFS_I2C_DEVICES), n)))
+                .collect(),
+            Ok(buses) if !buses.is_empty() => buses,
+            Ok(_) => {
+                eprintln!("no /dev/i2c-* buses (is i2c-dev loaded?)");
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("/dev: {}", e);
+                process::exit(1);
             }
+        };
+    }
+    if buses.is_empty() {
+        buses.push(DEFAULT_BUS.to_string());
+    }
+
+    let mut failed = false;
+    let mut report = Vec::new();
+    for bus in &buses {
+        let scanned = scan_bus(bus, options);
+        if let Err(e) = &scanned {
+            eprintln!("{}: {}", bus, e);
+            failed = true;
+        }
+        if as_json {
+            report.push(bus_json(bus, &scanned));
+            continue;
         }
-        else {
-            match dev.smbus_write_quick(false) {
-                Ok(_val) => {
-                    println!("Found Address {:#02x}", addr as u8);
-                    lookup(addr as u8);
-                },
-                Err(_err) => {
-                },
+        if buses.len() > 1 {
+            println!("Bus {}", bus);
+        }
+        if let Ok(scanned) = &scanned {
+            print_bus(&scanned.found);
+            if !scanned.muxes.is_empty() {
+                print!("{}", tree(bus, scanned));
             }
         }
     }
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(&json!({ "buses": report })).unwrap());
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid_and_json() {
+        let found = vec![
+            (0x1a, Status::Busy(Some("wm8960".to_string()))),
+            (0x68, Status::Found(None)),
+            (0x76, Status::Found(identify::FINGERPRINTS.iter().find(|fingerprint| fingerprint.part == "BME280"))),
+            (0x77, Status::Error("Operation not supported".to_string())),
+        ];
+        let grid = grid(&found);
+        let lines: Vec<&str> = grid.lines().collect();
+        assert_eq!(9, lines.len());
+        assert_eq!("00:          -- -- -- -- -- -- -- -- -- -- -- -- -- ", lines[1]);
+        assert_eq!("10: -- -- -- -- -- -- -- -- -- -- UU -- -- -- -- -- ", lines[2]);
+        assert_eq!("60: -- -- -- -- -- -- -- -- 68 -- -- -- -- -- -- -- ", lines[7]);
+        assert_eq!("70: -- -- -- -- -- -- 76 --                         ", lines[8]);
+
+        let report = bus_json("/dev/i2c-1", &Ok(BusScan { found, muxes: Vec::new() }));
+        assert_eq!(json!("0x1a"), report["addresses"][0]["address"]);
+        assert_eq!(json!("wm8960"), report["addresses"][0]["driver"]);
+        let candidates = report["addresses"][1]["candidates"].as_array().unwrap();
+        assert!(candidates.iter().any(|c| c["name"] == "DS3231"));
+        assert_eq!(json!(null), report["addresses"][1]["identified"]);
+        // Identified, only the part itself is left of the 14 candidates
+        assert_eq!(json!("BME280"), report["addresses"][2]["identified"]);
+        assert_eq!(json!([{ "name": "BME280", "description": "Temp/Barometric/Humidity" }]), report["addresses"][2]["candidates"]);
+        assert_eq!(json!("error"), report["addresses"][3]["status"]);
+        let missing = bus_json("/dev/i2c-9", &Err(io::Error::from(io::ErrorKind::NotFound)));
+        assert!(missing["error"].is_string());
+    }
+
+    #[test]
+    fn test_mux_tree_and_json() {
+        let scanned = BusScan {
+            found: vec![(0x40, Status::Found(None)), (0x70, Status::Found(None))],
+            muxes: vec![Mux {
+                addr: 0x70,
+                channels: vec![
+                    Channel { number: 0, bus: None, found: Ok(vec![(0x76, Status::Found(None))]) },
+                    Channel { number: 1, bus: None, found: Ok(Vec::new()) },
+                    Channel { number: 2, bus: None, found: Err(io::Error::from(io::ErrorKind::InvalidData)) },
+                ],
+            }],
+        };
+        let tree = tree("/dev/i2c-1", &scanned);
+        let lines: Vec<&str> = tree.lines().collect();
+        assert_eq!("/dev/i2c-1", lines[0]);
+        assert_eq!("├── 0x40", lines[1]);
+        assert_eq!("└── 0x70 mux", lines[2]);
+        assert_eq!("    ├── channel 0", lines[3]);
+        assert_eq!("    │   └── 0x76", lines[4]);
+        assert_eq!("    ├── channel 1", lines[5]);
+        assert!(lines[6].starts_with("    └── channel 2: "));
+
+        let report = bus_json("/dev/i2c-1", &Ok(scanned));
+        assert!(report["addresses"][0].get("channels").is_none());
+        let channels = &report["addresses"][1]["channels"];
+        assert_eq!(json!("0x76"), channels[0]["addresses"][0]["address"]);
+        assert_eq!(json!(null), channels[0]["bus"]);
+        assert!(channels[2]["error"].is_string());
+    }
 }
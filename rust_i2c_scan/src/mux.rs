@@ -0,0 +1,204 @@
+use crate::identify::Registers;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::{fs,io};
+
+// Where the TCA9548A and PCA9548 live
+pub const MUX_ADDRESSES: RangeInclusive<u8> = 0x70..=0x77;
+pub const CHANNELS: u8 = 8;
+
+/// A mux switched by hand through its control register, one bit per
+/// channel. All channels are switched off again when it is dropped.
+pub struct Switch<R: Registers> {
+    dev: R,
+}
+
+impl<R: Registers> Switch<R> {
+    /// The device as a mux, if it behaves like one: it answers a plain read
+    /// and reads back what was written, both all channels off and channel 0
+    /// on. A chip with a register pointer reads back 0x00 from many a
+    /// register 0x00, but not 0x01 from register 0x01 as well.
+    ///
+    /// Anything else is left with the 0x00 a mux would take as all channels
+    /// off: for other chips a written byte may be a command, so the byte
+    /// read at the start is never written back.
+    pub fn open(mut dev: R) -> Option<Switch<R>> {
+        dev.read(&mut [0u8]).ok()?;
+        set(&mut dev, 0).ok()?;
+        if set(&mut dev, 1).and_then(|()| set(&mut dev, 0)).is_err() {
+            // Not a mux: take back the 0x01
+            let _ = dev.write(&[0x00]);
+            return None;
+        }
+        Some(Switch { dev })
+    }
+
+    pub fn select(&mut self, channel: u8) -> io::Result<()> {
+        set(&mut self.dev, 1 << channel)
+    }
+}
+
+impl<R: Registers> Drop for Switch<R> {
+    fn drop(&mut self) {
+        if let Err(e) = set(&mut self.dev, 0) {
+            eprintln!("could not switch the mux's channels off: {}", e);
+        }
+    }
+}
+
+// Writes the control register and reads it back
+fn set<R: Registers>(dev: &mut R, channels: u8) -> io::Result<()> {
+    dev.write(&[channels])?;
+    let mut control = [0u8];
+    dev.read(&mut control)?;
+    if control[0] != channels {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("control register reads {:#04x}, not {:#04x}", control[0], channels),
+        ));
+    }
+    Ok(())
+}
+
+/// The channels of a mux the kernel drives (i2c-mux-pca954x), as the bus
+/// number of each channel's adapter: sysfs links `BUS-00ADDR/channel-N` to
+/// `i2c-M`.
+pub fn kernel_channels(sysfs: &Path, bus: u32, addr: u8) -> Vec<(u8, u32)> {
+    let mut channels = Vec::new();
+    let entries = match fs::read_dir(sysfs.join(format!("{}-{:04x}", bus, addr))) {
+        Ok(entries) => entries,
+        Err(_) => return channels,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let channel = match name.strip_prefix("channel-").and_then(|n| n.parse::<u8>().ok()) {
+            Some(channel) => channel,
+            None => continue,
+        };
+        let adapter = fs::read_link(entry.path()).ok().and_then(|link| {
+            link.file_name()?.to_string_lossy().strip_prefix("i2c-")?.parse::<u32>().ok()
+        });
+        if let Some(adapter) = adapter {
+            channels.push((channel, adapter));
+        }
+    }
+    channels.sort();
+    channels
+}
+
+/// Whether a bus is a channel of a kernel-driven mux, and so shown under it.
+pub fn is_mux_channel(sysfs: &Path, bus: u32) -> bool {
+    // A link to the mux, which need not resolve here
+    fs::symlink_metadata(sysfs.join(format!("i2c-{}", bus)).join("mux_device")).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use std::{env,process};
+
+    // A TCA9548A's control register, or a device that ignores writes
+    struct Fake {
+        control: u8,
+        writable: bool,
+        writes: Vec<u8>,
+    }
+
+    impl Registers for &mut Fake {
+        fn read_byte_data(&mut self, _register: u8) -> io::Result<u8> {
+            Ok(self.control)
+        }
+
+        fn read_word_be(&mut self, _register: u8) -> io::Result<u16> {
+            Ok(self.control as u16)
+        }
+
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.writes.push(data[0]);
+            if self.writable {
+                self.control = data[0];
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, data: &mut [u8]) -> io::Result<()> {
+            data.fill(self.control);
+            Ok(())
+        }
+    }
+
+    // A chip whose first written byte sets the register that is read
+    struct Pointer {
+        pointer: u8,
+        registers: [u8; 256],
+        writes: Vec<u8>,
+    }
+
+    impl Registers for &mut Pointer {
+        fn read_byte_data(&mut self, register: u8) -> io::Result<u8> {
+            Ok(self.registers[register as usize])
+        }
+
+        fn read_word_be(&mut self, register: u8) -> io::Result<u16> {
+            Ok(self.registers[register as usize] as u16)
+        }
+
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.writes.push(data[0]);
+            self.pointer = data[0];
+            Ok(())
+        }
+
+        fn read(&mut self, data: &mut [u8]) -> io::Result<()> {
+            data.fill(self.registers[self.pointer as usize]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_switch() {
+        // Left with channel 2 on; switched off on open and again when done
+        let mut mux = Fake { control: 0x04, writable: true, writes: Vec::new() };
+        {
+            let mut switch = Switch::open(&mut mux).unwrap();
+            for channel in 0..CHANNELS {
+                switch.select(channel).unwrap();
+            }
+        }
+        assert_eq!(vec![0x00, 0x01, 0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x00], mux.writes);
+        assert_eq!(0x00, mux.control);
+
+        // Something else at 0x70 only ever sees 0x00
+        let mut other = Fake { control: 0x5a, writable: false, writes: Vec::new() };
+        assert!(Switch::open(&mut other).is_none());
+        assert_eq!(vec![0x00], other.writes);
+
+        // A BME280 at 0x76: register 0x00 reads 0, register 0x01 does not
+        // read 1
+        let mut registers = [0u8; 256];
+        registers[0x01] = 0x6b;
+        registers[0xd0] = 0x60;
+        let mut bme280 = Pointer { pointer: 0xd0, registers, writes: Vec::new() };
+        assert!(Switch::open(&mut bme280).is_none());
+        assert_eq!(vec![0x00, 0x01, 0x00], bme280.writes);
+    }
+
+    #[test]
+    fn test_kernel_channels() {
+        let sysfs = env::temp_dir().join(format!("rust_i2c_scan-sysfs-{}", process::id()));
+        let _ = fs::remove_dir_all(&sysfs);
+        fs::create_dir_all(sysfs.join("1-0070")).unwrap();
+        fs::create_dir_all(sysfs.join("i2c-11")).unwrap();
+        symlink("../i2c-11", sysfs.join("1-0070/channel-0")).unwrap();
+        symlink("../i2c-12", sysfs.join("1-0070/channel-1")).unwrap();
+        symlink("../../devices/platform/1-0070", sysfs.join("i2c-11/mux_device")).unwrap();
+        fs::write(sysfs.join("1-0070/name"), "pca9548\n").unwrap();
+
+        assert_eq!(vec![(0, 11), (1, 12)], kernel_channels(&sysfs, 1, 0x70));
+        assert!(kernel_channels(&sysfs, 1, 0x71).is_empty());
+        assert!(is_mux_channel(&sysfs, 11));
+        assert!(!is_mux_channel(&sysfs, 1));
+        fs::remove_dir_all(sysfs).unwrap();
+    }
+}
@@ -0,0 +1,493 @@
+use crate::bus::{Publisher, Sentence};
+use crate::derived::DEFAULT_TALKER;
+use crate::http::Request;
+use crate::nmea;
+use crate::signalk;
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::time::{interval, Duration};
+
+pub const DEFAULT_RADIUS: f64 = 50.0; // m
+pub const DEFAULT_TRACK_LEN: usize = 8640; // a day at one point every 10 seconds
+
+// The number of the one alarm this server raises, in ALR, ALF and ACK
+const ALERT_ID: u32 = 1;
+const TRACK_INTERVAL: TimeDelta = TimeDelta::seconds(10);
+// An active alarm is sent again this often until it is acknowledged
+const REPEAT: TimeDelta = TimeDelta::seconds(30);
+// Without a position for this long the watch is blind, which is an alarm too
+const NO_FIX: TimeDelta = TimeDelta::seconds(30);
+const EARTH_RADIUS: f64 = 6_371_000.0; // m
+
+// The running watch, shared with the status endpoint; None without an
+// anchor source
+static WATCH: Mutex<Option<AnchorWatch>> = Mutex::new(None);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Fix {
+    time: DateTime<Utc>,
+    latitude: f64,
+    longitude: f64,
+}
+
+impl Fix {
+    // Great circle distance in metres
+    fn distance(&self, other: &Fix) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().asin()
+    }
+}
+
+#[derive(Debug)]
+struct Anchor {
+    fix: Fix,
+    radius: f64,
+}
+
+#[derive(Debug)]
+struct Alarm {
+    text: String,
+    since: DateTime<Utc>,
+    sent: DateTime<Utc>,
+    acknowledged: bool,
+}
+
+/// What the watch wants sent after an update: sentences for the bus and a
+/// desktop notification.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub sentences: Vec<String>,
+    pub notification: Option<String>,
+}
+
+/// Watches the swing circle around the anchor point and keeps the track
+/// since the anchor went down.
+#[derive(Debug)]
+pub struct AnchorWatch {
+    radius: f64,
+    track_len: usize,
+    anchor: Option<Anchor>,
+    position: Option<Fix>,
+    track: VecDeque<Fix>,
+    alarm: Option<Alarm>,
+    // ALF revision counter, bumped on every change of the alert
+    revision: u32,
+}
+
+impl AnchorWatch {
+    pub fn new(radius: f64, track_len: usize) -> AnchorWatch {
+        AnchorWatch {
+            radius,
+            track_len,
+            anchor: None,
+            position: None,
+            track: VecDeque::new(),
+            alarm: None,
+            revision: 0,
+        }
+    }
+
+    /// Drops the anchor at the current position, with the default radius
+    /// unless one is given. A position as old as the no-position alarm is
+    /// not the current one.
+    pub fn drop_anchor(&mut self, radius: Option<f64>, now: DateTime<Utc>) -> Result<(), String> {
+        let fix = match self.position {
+            Some(fix) if now - fix.time < NO_FIX => fix,
+            Some(_) => return Err(format!("no position for {} s", NO_FIX.num_seconds())),
+            None => return Err("no position yet".to_string()),
+        };
+        let radius = radius.unwrap_or(self.radius);
+        if !(radius > 0.0 && radius.is_finite()) {
+            return Err(format!("bad radius {}", radius));
+        }
+        self.anchor = Some(Anchor { fix, radius });
+        self.track.clear();
+        self.track.push_back(fix);
+        Ok(())
+    }
+
+    /// Stops watching; an active alarm goes back to normal on the next tick.
+    pub fn raise_anchor(&mut self) {
+        self.anchor = None;
+    }
+
+    /// Takes in a sentence from the bus: positions, and acknowledgements of
+    /// the alarm (ACK, or ACN with command A).
+    pub fn update(&mut self, line: &str, now: DateTime<Utc>) -> Report {
+        let fields = nmea::fields(line);
+        let acknowledged = match nmea::sentence_type(nmea::address(line)) {
+            "ACK" => fields.first().and_then(|id| id.parse().ok()) == Some(ALERT_ID),
+            "ACN" => fields.get(2).and_then(|id| id.parse().ok()) == Some(ALERT_ID) && fields.get(4) == Some(&"A"),
+            _ => false,
+        };
+        if acknowledged {
+            if let Some(alarm) = self.alarm.as_mut().filter(|alarm| !alarm.acknowledged) {
+                alarm.acknowledged = true;
+                self.revision += 1;
+                return Report { sentences: self.alarm_sentences(now), notification: None };
+            }
+        }
+
+        let position = signalk::values(line).into_iter().find(|(path, _)| path == "navigation.position");
+        if let Some((_, position)) = position {
+            if let (Some(latitude), Some(longitude)) = (position["latitude"].as_f64(), position["longitude"].as_f64()) {
+                let fix = Fix { time: now, latitude, longitude };
+                self.position = Some(fix);
+                if self.anchor.is_some() && self.track.back().is_none_or(|last| now - last.time >= TRACK_INTERVAL) {
+                    if self.track.len() >= self.track_len {
+                        self.track.pop_front();
+                    }
+                    self.track.push_back(fix);
+                }
+            }
+        }
+        self.check(now)
+    }
+
+    /// Called every second, so a lost position and repeats are noticed
+    /// without any sentences coming in.
+    pub fn tick(&mut self, now: DateTime<Utc>) -> Report {
+        self.check(now)
+    }
+
+    fn condition(&self, now: DateTime<Utc>) -> Option<String> {
+        let anchor = self.anchor.as_ref()?;
+        let position = match self.position {
+            Some(fix) if now - fix.time < NO_FIX => fix,
+            _ => return Some(format!("Anchor watch has no position for {} s", NO_FIX.num_seconds())),
+        };
+        let distance = anchor.fix.distance(&position);
+        (distance > anchor.radius).then(|| format!("Anchor dragging {:.0} m from the anchor (radius {:.0} m)", distance, anchor.radius))
+    }
+
+    fn check(&mut self, now: DateTime<Utc>) -> Report {
+        match (self.condition(now), self.alarm.as_mut()) {
+            (Some(text), None) => {
+                self.alarm = Some(Alarm { text: text.clone(), since: now, sent: now, acknowledged: false });
+                self.revision += 1;
+                Report { sentences: self.alarm_sentences(now), notification: Some(text) }
+            }
+            (Some(text), Some(alarm)) => {
+                alarm.text = text;
+                if alarm.acknowledged || now - alarm.sent < REPEAT {
+                    return Report::default();
+                }
+                alarm.sent = now;
+                Report { sentences: self.alarm_sentences(now), notification: None }
+            }
+            (None, Some(_)) => {
+                self.alarm = None;
+                self.revision += 1;
+                Report { sentences: self.alarm_sentences(now), notification: None }
+            }
+            (None, None) => Report::default(),
+        }
+    }
+
+    // ALR for older displays and ALF for bridge alert management, both
+    // saying the same thing
+    fn alarm_sentences(&self, now: DateTime<Utc>) -> Vec<String> {
+        let time = format!("{}.{:02}", now.format("%H%M%S"), now.timestamp_subsec_millis() / 10);
+        let (active, acknowledged, state, text, short) = match &self.alarm {
+            Some(alarm) if alarm.acknowledged => ("A", "A", "A", alarm.text.as_str(), short_text(&alarm.text)),
+            Some(alarm) => ("A", "V", "V", alarm.text.as_str(), short_text(&alarm.text)),
+            None => ("V", "V", "N", "Anchor watch normal", "Anchor normal"),
+        };
+        let id = format!("{:03}", ALERT_ID);
+        // 1 to 99, then round again
+        let revision = ((self.revision.max(1) - 1) % 99 + 1).to_string();
+        vec![
+            nmea::sentence(&format!("{}ALR", DEFAULT_TALKER), &[&time, &id, active, acknowledged, text]),
+            nmea::sentence(
+                &format!("{}ALF", DEFAULT_TALKER),
+                // One sentence, category B, priority alarm, no manufacturer, instance 1
+                &[
+                    "1", "1", "0", &time, "B", "A", state, "", &ALERT_ID.to_string(), "1", &revision, "0", short,
+                ],
+            ),
+        ]
+    }
+
+    /// The anchor, the current distance from it and the track, for the status endpoint.
+    pub fn report(&self, now: DateTime<Utc>) -> Value {
+        let time = |fix: &Fix| fix.time.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let anchor = self.anchor.as_ref().map(|anchor| {
+            json!({
+                "latitude": anchor.fix.latitude,
+                "longitude": anchor.fix.longitude,
+                "radius": anchor.radius,
+                "dropped": time(&anchor.fix),
+            })
+        });
+        let distance = |fix: &Fix| self.anchor.as_ref().map(|anchor| (anchor.fix.distance(fix) * 10.0).round() / 10.0);
+        json!({
+            "anchor": anchor,
+            "position": self.position.map(|fix| json!({
+                "latitude": fix.latitude,
+                "longitude": fix.longitude,
+                "time": time(&fix),
+                "distance": distance(&fix),
+            })),
+            "alarm": self.alarm.as_ref().map(|alarm| json!({
+                "text": alarm.text,
+                "since": alarm.since.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "acknowledged": alarm.acknowledged,
+            })),
+            "checked": now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "track": self.track.iter().map(|fix| json!([time(fix), fix.latitude, fix.longitude, distance(fix)])).collect::<Vec<_>>(),
+        })
+    }
+}
+
+// ALF text is meant for small displays
+fn short_text(text: &str) -> &str {
+    match text.starts_with("Anchor dragging") {
+        true => "Anchor dragging",
+        false => "No position",
+    }
+}
+
+fn notify(text: &str) {
+    use notify_rust::{Hint, Notification, Urgency};
+    // No desktop session (a headless boat computer) is not a problem
+    if let Err(e) = Notification::new()
+        .summary("Anchor watch")
+        .body(text)
+        .icon("dialog-warning")
+        .appname("Anchor watch")
+        .hint(Hint::Urgency(Urgency::Critical))
+        .hint(Hint::SuppressSound(false))
+        .timeout(60_000_000)
+        .show()
+    {
+        eprintln!("anchor watch: notification failed: {}", e);
+    }
+}
+
+fn send(report: Report, publisher: &mut Publisher) {
+    for line in &report.sentences {
+        publisher.publish(line);
+    }
+    if let Some(text) = report.notification {
+        println!("{}: {}", publisher.source(), text);
+        // Talks to the session bus synchronously
+        tokio::task::spawn_blocking(move || notify(&text));
+    }
+}
+
+/// Runs the watch (or, after a reload, keeps running the one that is there,
+/// so the anchor point survives a change of radius) until the bus closes.
+pub async fn run(radius: f64, track_len: usize, mut receiver: Receiver<Sentence>, publisher: &mut Publisher) {
+    {
+        let mut watch = WATCH.lock().unwrap();
+        match watch.as_mut() {
+            Some(watch) => (watch.radius, watch.track_len) = (radius, track_len),
+            None => *watch = Some(AnchorWatch::new(radius, track_len)),
+        }
+    }
+    let mut ticks = interval(Duration::from_secs(1));
+    loop {
+        let report = tokio::select! {
+            result = receiver.recv() => match result {
+                Ok(sentence) => with_watch(|watch| watch.update(&sentence.line, Utc::now())),
+                Err(RecvError::Lagged(n)) => {
+                    eprintln!("{}: lagging, skipped {} messages", publisher.source(), n);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = ticks.tick() => with_watch(|watch| watch.tick(Utc::now())),
+        };
+        send(report.unwrap_or_default(), publisher);
+    }
+}
+
+/// Calls `f` with the running watch; None when there is none.
+pub fn with_watch<T>(f: impl FnOnce(&mut AnchorWatch) -> T) -> Option<T> {
+    WATCH.lock().unwrap().as_mut().map(f)
+}
+
+// The status listener has no users, so only the boat computer itself may
+// drop or raise the anchor: anyone on the marina's Wi-Fi could otherwise
+// silence a dragging alarm
+fn allowed(method: &str, peer: IpAddr) -> bool {
+    method == "GET" || peer.to_canonical().is_loopback()
+}
+
+/// Serves `GET /anchor` (the watch and its track), `POST /anchor/drop`
+/// (optionally `?radius=METRES`) and `POST /anchor/up` on the status
+/// listener. Returns the HTTP status and body.
+pub fn http(request: &Request, peer: IpAddr) -> (&'static str, Value) {
+    let error = |status, message: &str| (status, json!({ "error": message }));
+    if !allowed(&request.method, peer) {
+        return error("403 Forbidden", "the anchor can only be dropped or raised from the server itself");
+    }
+    let radius = request.query.split('&').find_map(|pair| pair.strip_prefix("radius="));
+    let radius = match radius.map(str::parse::<f64>) {
+        Some(Ok(radius)) => Some(radius),
+        Some(Err(_)) => return error("400 Bad Request", "radius must be a number of metres"),
+        None => None,
+    };
+    let result = with_watch(|watch| match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/anchor") => ("200 OK", watch.report(Utc::now())),
+        ("POST", "/anchor/drop") => match watch.drop_anchor(radius, Utc::now()) {
+            Ok(()) => ("200 OK", watch.report(Utc::now())),
+            Err(e) => error("409 Conflict", &e),
+        },
+        ("POST", "/anchor/up") => {
+            watch.raise_anchor();
+            ("200 OK", watch.report(Utc::now()))
+        }
+        (_, "/anchor" | "/anchor/drop" | "/anchor/up") => error("405 Method Not Allowed", "method not allowed"),
+        _ => error("404 Not Found", "not found"),
+    });
+    result.unwrap_or_else(|| error("404 Not Found", "anchor watch is off"))
+}
+
+/// Forgets the watch when its source is removed from the configuration.
+pub fn remove() {
+    *WATCH.lock().unwrap() = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ListenerConfig, ListenerKind, SourceConfig, SourceKind};
+    use crate::shutdown::ShutdownHandle;
+    use crate::{listeners, sources};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::broadcast::channel;
+    use tokio::time::{sleep, timeout};
+
+    fn fields(line: &str) -> Vec<&str> {
+        assert!(nmea::validate(line).is_ok(), "{}", line);
+        nmea::fields(line)
+    }
+
+    #[test]
+    fn test_anchor_watch() {
+        let start = DateTime::parse_from_rfc3339("2024-06-01T22:00:00Z").unwrap().to_utc();
+        let at = |seconds| start + TimeDelta::seconds(seconds);
+        let mut watch = AnchorWatch::new(40.0, 3);
+        assert!(watch.drop_anchor(None, at(0)).is_err());
+
+        // 1/1000 of a minute of latitude is 1.852 m
+        let gga = |minutes: f64| format!("$GPGGA,220000,50{:06.3},N,00100.000,W,1,08,1.0,10.0,M,47.0,M,,*00", minutes);
+        assert_eq!(Report::default(), watch.update(&gga(0.0), at(0)));
+        watch.drop_anchor(None, at(0)).unwrap();
+        // Swinging within the circle, and one point every 10 seconds
+        for second in 1..=25 {
+            assert_eq!(Report::default(), watch.update(&gga(0.015), at(second)));
+        }
+        assert_eq!(3, watch.track.len());
+
+        // 42.6 m out
+        let report = watch.update(&gga(0.023), at(30));
+        assert_eq!("Anchor dragging 43 m from the anchor (radius 40 m)", report.notification.unwrap());
+        assert_eq!(vec!["220030.00", "001", "A", "V", "Anchor dragging 43 m from the anchor (radius 40 m)"], fields(&report.sentences[0]));
+        assert_eq!(
+            vec!["1", "1", "0", "220030.00", "B", "A", "V", "", "1", "1", "1", "0", "Anchor dragging"],
+            fields(&report.sentences[1])
+        );
+        assert_eq!(3, watch.track.len());
+        assert_eq!(json!(42.6), watch.report(at(30))["position"]["distance"]);
+
+        // Repeated until acknowledged
+        assert_eq!(Report::default(), watch.tick(at(45)));
+        assert_eq!(2, watch.tick(at(60)).sentences.len());
+        let report = watch.update("$IIACK,001*00", at(61));
+        assert_eq!("A", fields(&report.sentences[0])[3]);
+        assert_eq!(Report::default(), watch.tick(at(120)));
+
+        // Back inside, then the GPS goes quiet
+        let report = watch.update(&gga(0.01), at(121));
+        assert_eq!("N", fields(&report.sentences[1])[6]);
+        let report = watch.tick(at(152));
+        assert_eq!("Anchor watch has no position for 30 s", report.notification.unwrap());
+
+        // A bigger circle from the same spot; raising the anchor ends the alarm
+        // A position from before the GPS went quiet will not do
+        assert_eq!(Err("no position for 30 s".to_string()), watch.drop_anchor(Some(100.0), at(152)));
+        watch.update(&gga(0.01), at(153));
+        watch.drop_anchor(Some(100.0), at(153)).unwrap();
+        assert_eq!(json!(100.0), watch.report(at(153))["anchor"]["radius"]);
+        assert!(watch.drop_anchor(Some(-1.0), at(153)).is_err());
+        watch.update(&gga(0.5), at(154));
+        watch.raise_anchor();
+        assert_eq!("V", fields(&watch.tick(at(155)).sentences[0])[2]);
+        assert_eq!(Value::Null, watch.report(at(155))["anchor"]);
+    }
+
+    async fn request(port: u16, request: &str) -> (String, Value) {
+        let mut http = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        http.write_all(format!("{} HTTP/1.1\r\n\r\n", request).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), serde_json::from_str(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_anchor_endpoint() {
+        let (tx, mut bus) = channel::<Sentence>(16);
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let status = ListenerConfig::new(ListenerKind::Status { bind: format!("127.0.0.1:{}", port), stale_after: Duration::from_secs(10) });
+        listeners::start(&status, &tx, &ShutdownHandle::default()).await.unwrap();
+        let source = sources::spawn(SourceConfig::new(SourceKind::Anchor { radius: 50.0, track_len: 10 }), tx.clone());
+        sleep(Duration::from_millis(100)).await;
+
+        assert_eq!("HTTP/1.1 409 Conflict", request(port, "POST /anchor/drop").await.0);
+        let gps: Arc<str> = Arc::from("gps");
+        tx.send(Sentence::new(&gps, "$GPGGA,220000,5000.000,N,00100.000,W,1,08,1.0,10.0,M,47.0,M,,*00")).unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!("HTTP/1.1 405 Method Not Allowed", request(port, "GET /anchor/drop").await.0);
+        let (status, watch) = request(port, "POST /anchor/drop?radius=20").await;
+        assert_eq!("HTTP/1.1 200 OK", status);
+        assert_eq!(json!(20.0), watch["anchor"]["radius"]);
+
+        // 27.8 m away
+        tx.send(Sentence::new(&gps, "$GPGGA,220001,5000.015,N,00100.000,W,1,08,1.0,10.0,M,47.0,M,,*00")).unwrap();
+        let alarm = timeout(Duration::from_secs(2), async {
+            loop {
+                let sentence = bus.recv().await.unwrap();
+                if &*sentence.source == "anchor" {
+                    return sentence.line;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(alarm.starts_with("$IIALR,"));
+        let (_, watch) = request(port, "GET /anchor").await;
+        assert_eq!(json!(27.8), watch["position"]["distance"]);
+        assert_eq!(json!(false), watch["alarm"]["acknowledged"]);
+        // Only the drop point: the track takes one point every 10 seconds
+        assert_eq!(1, watch["track"].as_array().unwrap().len());
+
+        // Without the source there is nothing to ask
+        source.abort();
+        remove();
+        assert_eq!("HTTP/1.1 404 Not Found", request(port, "GET /anchor").await.0);
+    }
+
+    #[test]
+    fn test_anchor_remote() {
+        let marina: IpAddr = "192.168.1.50".parse().unwrap();
+        assert!(allowed("GET", marina));
+        assert!(!allowed("POST", marina));
+        assert!(!allowed("POST", "::ffff:192.168.1.50".parse().unwrap()));
+        assert!(allowed("POST", "127.0.0.1".parse().unwrap()));
+        assert!(allowed("POST", "::ffff:127.0.0.1".parse().unwrap()));
+        assert!(allowed("POST", "::1".parse().unwrap()));
+    }
+}
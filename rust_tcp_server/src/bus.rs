@@ -0,0 +1,120 @@
+use crate::filter::{ActiveFilter, Filter};
+use crate::nmea::{self, SentenceError};
+use crate::shutdown::ShutdownHandle;
+use crate::stats::Counters;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::broadcast::{Receiver, Sender};
+
+/// A validated sentence travelling over the broadcast channel, tagged with
+/// the name of the source or client it came from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sentence {
+    pub source: Arc<str>,
+    pub line: String, // without the trailing CR/LF
+}
+
+impl Sentence {
+    pub fn new(source: &Arc<str>, line: &str) -> Sentence {
+        Sentence {
+            source: source.clone(),
+            line: line.to_string(),
+        }
+    }
+
+    /// The sentence as sent to clients, terminated by CR/LF.
+    pub fn to_wire(&self) -> String {
+        format!("{}\r\n", self.line)
+    }
+}
+
+/// Validates framed lines from one source, applies the source's filter and
+/// fans them out to the bus.
+pub struct Publisher {
+    source: Arc<str>,
+    filter: ActiveFilter,
+    tx: Sender<Sentence>,
+    counters: Arc<Counters>,
+}
+
+impl Publisher {
+    pub fn new(source: &str, filter: &Filter, tx: Sender<Sentence>, counters: Arc<Counters>) -> Publisher {
+        Publisher {
+            source: Arc::from(source),
+            filter: filter.start(),
+            tx,
+            counters,
+        }
+    }
+
+    pub fn source(&self) -> &Arc<str> {
+        &self.source
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Counts raw bytes read from the source, before framing.
+    pub fn received(&self, bytes: usize) {
+        self.counters.bytes_in(bytes);
+    }
+
+    pub fn publish(&mut self, line: &str) {
+        match nmea::validate(line) {
+            Ok(line) => {
+                self.counters.sentence();
+                let sentence = Sentence::new(&self.source, line);
+                if self.filter.accept(&sentence) {
+                    // No subscribers just means nobody is connected yet
+                    let _ = self.tx.send(sentence);
+                }
+            }
+            Err(e) => {
+                match e {
+                    SentenceError::BadChecksum { .. } => self.counters.checksum_error(),
+                    _ => self.counters.invalid(),
+                }
+                eprintln!("{}: dropping {:?}: {}", self.source, line, e)
+            }
+        }
+    }
+}
+
+/// A receiver that ends when the server shuts down, after handing out what
+/// was still queued for it, so writers finish without losing sentences.
+pub struct Subscription {
+    receiver: Receiver<Sentence>,
+    shutdown: ShutdownHandle,
+    stopping: bool,
+}
+
+impl Subscription {
+    pub fn new(receiver: Receiver<Sentence>, shutdown: ShutdownHandle) -> Subscription {
+        Subscription {
+            receiver,
+            shutdown,
+            stopping: false,
+        }
+    }
+
+    /// Like `Receiver::recv`; `Closed` once shutting down and drained.
+    pub async fn recv(&mut self) -> Result<Sentence, RecvError> {
+        if !self.stopping {
+            tokio::select! {
+                result = self.receiver.recv() => return result,
+                _ = self.shutdown.stopping() => self.stopping = true,
+            }
+        }
+        match self.receiver.try_recv() {
+            Ok(sentence) => Ok(sentence),
+            Err(TryRecvError::Lagged(n)) => Err(RecvError::Lagged(n)),
+            Err(TryRecvError::Empty | TryRecvError::Closed) => Err(RecvError::Closed),
+        }
+    }
+
+    /// Whether the subscription ended because the server is shutting down.
+    pub fn stopping(&self) -> bool {
+        self.stopping
+    }
+}
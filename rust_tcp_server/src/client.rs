@@ -0,0 +1,583 @@
+use crate::bus::{Publisher, Sentence, Subscription};
+use crate::config::{Access, User};
+use crate::filter::{ActiveFilter, Filter};
+use crate::nmea::Framer;
+use crate::shutdown::ShutdownHandle;
+use crate::stats::{self, ClientStats, Counters};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+
+// How long a client has to complete the TLS handshake and log in
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    // Messages buffered per client before the oldest ones are dropped,
+    // 0 writes straight from the broadcast channel
+    pub queue_len: usize,
+    pub access: Access,
+    pub filter: Filter, // set on the listener
+    pub users: Vec<User>, // empty when clients need not log in
+    pub tls: bool, // for the links Signal K discovery hands out
+    pub shutdown: ShutdownHandle,
+}
+
+/// Bounded per-client queue that drops the oldest message when full, so a
+/// slow client only loses data instead of holding back the broadcast channel.
+pub struct ClientQueue {
+    messages: Mutex<VecDeque<String>>,
+    capacity: usize,
+    notify: Notify,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+    counters: Arc<Counters>, // the client's, so /status shows the drops
+}
+
+impl ClientQueue {
+    pub fn new(capacity: usize, counters: Arc<Counters>) -> ClientQueue {
+        ClientQueue {
+            messages: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            notify: Notify::new(),
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            counters,
+        }
+    }
+
+    pub fn push(&self, message: String) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() >= self.capacity {
+            messages.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            self.counters.dropped();
+        }
+        messages.push_back(message);
+        self.notify.notify_one();
+    }
+
+    /// The oldest message; None once the queue is closed and empty.
+    pub async fn pop(&self) -> Option<String> {
+        loop {
+            if let Some(message) = self.messages.lock().unwrap().pop_front() {
+                return Some(message);
+            }
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Lets the writer finish what is queued, then stop.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// What a read from the client gives: the bytes read and the lines in them,
+/// or errors to reply with; None once the client has gone.
+pub type Input = Option<(usize, Vec<Result<String, String>>)>;
+
+/// The client's end of a connection, as its protocol has it: TCP clients
+/// send and receive lines, WebSocket clients text messages.
+pub trait ClientStream: Send {
+    /// The next lines from the client.
+    fn read(&mut self) -> impl Future<Output = io::Result<Input>> + Send;
+
+    /// A sentence from the bus as the client receives it.
+    fn encode(&self, sentence: &Sentence) -> String;
+
+    /// A reply to a bad line or a failed login.
+    fn error(&self, message: &str) -> String;
+}
+
+/// Where messages to the client go.
+pub trait ClientSink: Send + 'static {
+    fn send(&mut self, message: String) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Ends the connection properly.
+    fn close(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+struct Lines<R> {
+    reader: R,
+    framer: Framer,
+    buf: [u8; 1024],
+}
+
+impl<R: AsyncRead + Unpin + Send> ClientStream for Lines<R> {
+    async fn read(&mut self) -> io::Result<Input> {
+        let n = self.reader.read(&mut self.buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        Ok(Some((n, self.framer.push(&self.buf[..n]).into_iter().map(Ok).collect())))
+    }
+
+    fn encode(&self, sentence: &Sentence) -> String {
+        sentence.to_wire()
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("#ERROR {}\r\n", message)
+    }
+}
+
+impl<S: AsyncWrite + Send + 'static> ClientSink for WriteHalf<S> {
+    async fn send(&mut self, message: String) -> io::Result<()> {
+        self.write_all(message.as_bytes()).await
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        self.shutdown().await
+    }
+}
+
+enum Output<O> {
+    Direct(O),
+    Queued(Arc<ClientQueue>),
+}
+
+impl<O: ClientSink> Output<O> {
+    async fn send(&mut self, message: String) -> io::Result<()> {
+        match self {
+            Output::Direct(sink) => sink.send(message).await,
+            Output::Queued(queue) => {
+                queue.push(message);
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn drain_queue<O: ClientSink>(queue: Arc<ClientQueue>, mut sink: O) -> io::Result<()> {
+    while let Some(message) = queue.pop().await {
+        sink.send(message).await?;
+    }
+    sink.close().await
+}
+
+/// What TCP and WebSocket clients have in common: access rules, filters and
+/// forwarding the client's own sentences onto the bus.
+pub struct ClientSession {
+    name: String,
+    access: Access,
+    publisher: Publisher,
+    listener_filter: ActiveFilter,
+    client_filter: Option<ActiveFilter>,
+    skipped: u64,
+    ignored: u64,
+    stats: ClientStats,
+    users: Vec<User>,
+    user: Option<String>,
+}
+
+impl ClientSession {
+    pub fn new(addr: SocketAddr, tx: Sender<Sentence>, options: &ClientOptions) -> ClientSession {
+        let stats = stats::client(addr);
+        ClientSession {
+            name: addr.to_string(),
+            access: options.access,
+            // Sentences written by the client go back onto the bus under its own name
+            publisher: Publisher::new(&format!("client:{}", addr), &Filter::default(), tx, stats.counters().clone()),
+            listener_filter: options.filter.start(),
+            client_filter: None,
+            skipped: 0,
+            ignored: 0,
+            stats,
+            users: options.users.clone(),
+            user: None,
+        }
+    }
+
+    /// Whether the client still has to log in before anything else.
+    pub fn needs_login(&self) -> bool {
+        !self.users.is_empty() && self.user.is_none()
+    }
+
+    /// Checks an `#AUTH NAME PASSWORD` or `#AUTH TOKEN` line and takes on the
+    /// user's access.
+    pub fn login(&mut self, line: &str) -> Result<(), String> {
+        let user = authenticate(&self.users, &self.name, line)?;
+        self.access = user.access;
+        self.user = Some(user.name.clone());
+        Ok(())
+    }
+
+    pub fn can_read(&self) -> bool {
+        !self.needs_login() && self.access.can_read()
+    }
+
+    /// Handles one line from the client, returning an error message to send
+    /// back for bad `#FILTER` commands.
+    pub fn receive(&mut self, line: &str) -> Option<String> {
+        if line.starts_with("#AUTH ") {
+            // Switching users on an open connection
+            if self.users.is_empty() {
+                return Some("authentication is not enabled".to_string());
+            }
+            return self.login(line).err();
+        } else if let Some(spec) = line.strip_prefix("#FILTER") {
+            // A client may narrow down what it receives
+            match spec.trim().parse::<Filter>() {
+                Ok(filter) if filter.is_empty() => self.client_filter = None,
+                Ok(filter) => self.client_filter = Some(filter.start()),
+                Err(e) => return Some(e),
+            }
+        } else if self.access.can_write() {
+            self.publisher.publish(line);
+        } else {
+            self.ignored += 1;
+            if self.ignored == 1 {
+                eprintln!("{}: read-only client, ignoring its sentences", self.name);
+            }
+        }
+        None
+    }
+
+    /// Whether a sentence from the bus should be sent to the client.
+    pub fn wants(&mut self, sentence: &Sentence) -> bool {
+        // Never echo a client's own sentences back to it
+        sentence.source != *self.publisher.source()
+            && self.listener_filter.accept(sentence)
+            && self.client_filter.as_mut().is_none_or(|filter| filter.accept(sentence))
+    }
+
+    /// Counts raw bytes read from the client.
+    pub fn received(&self, bytes: usize) {
+        self.stats.counters().bytes_in(bytes);
+    }
+
+    /// Counts a message handed to the client.
+    pub fn sent(&self, bytes: usize) {
+        self.stats.counters().sent(bytes);
+    }
+
+    pub fn lagged(&mut self, n: u64) {
+        // The client could not keep up; carry on with the newest messages
+        self.skipped = self.stats.counters().lagged(n);
+        eprintln!("{}: lagging, skipped {} messages ({} total)", self.name, n, self.skipped);
+    }
+
+    pub fn disconnected(&self, dropped: u64) {
+        if self.skipped > 0 || dropped > 0 {
+            println!("{}: disconnected, skipped {} and dropped {} messages", self.name, self.skipped, dropped);
+        }
+    }
+}
+
+/// The user an `#AUTH NAME PASSWORD` or `#AUTH TOKEN` line logs in as.
+pub fn authenticate<'a>(users: &'a [User], client: &str, line: &str) -> Result<&'a User, String> {
+    let credentials = match line.strip_prefix("#AUTH ") {
+        Some(credentials) => credentials.trim(),
+        None => return Err("authentication required".to_string()),
+    };
+    let user = match credentials.split_once(' ') {
+        Some((name, password)) => {
+            users.iter().find(|user| user.name == name && same_secret(&user.password, password.trim()))
+        }
+        None => users.iter().find(|user| same_secret(&user.password, credentials)),
+    };
+    match user {
+        Some(user) => {
+            println!("{}: logged in as {} ({:?})", client, user.name, user.access);
+            Ok(user)
+        }
+        None => {
+            eprintln!("{}: failed login", client);
+            Err("authentication failed".to_string())
+        }
+    }
+}
+
+// Compares the whole secret, so the time taken does not give away a prefix
+fn same_secret(expected: &str, given: &str) -> bool {
+    expected.len() == given.len() && expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+pub async fn handle_client<S: AsyncRead + AsyncWrite + Send + 'static>(
+    stream: S,
+    addr: SocketAddr,
+    tx: Sender<Sentence>,
+    receiver: Receiver<Sentence>,
+    options: ClientOptions,
+) -> io::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let lines = Lines { reader, framer: Framer::new(), buf: [0u8; 1024] };
+    serve(lines, writer, addr, tx, receiver, options).await
+}
+
+/// Runs a client connection of either kind until the client goes away or
+/// the server shuts down: the login, the client's lines, and the bus
+/// sentences it asked for, through a queue unless `queue_len` is 0.
+pub async fn serve<I: ClientStream, O: ClientSink>(
+    mut input: I,
+    mut sink: O,
+    addr: SocketAddr,
+    tx: Sender<Sentence>,
+    receiver: Receiver<Sentence>,
+    options: ClientOptions,
+) -> io::Result<()> {
+    let mut receiver = Subscription::new(receiver, options.shutdown.clone());
+    let mut shutdown = options.shutdown.clone();
+    let mut stopping = false;
+    let mut session = ClientSession::new(addr, tx, &options);
+
+    // The first line has to log in; whatever follows it is handled as usual
+    let mut pending = Vec::new();
+    if session.needs_login() {
+        let first_lines = async {
+            loop {
+                match input.read().await? {
+                    Some((n, lines)) => {
+                        session.received(n);
+                        if !lines.is_empty() {
+                            return Ok::<_, io::Error>(lines);
+                        }
+                    }
+                    None => return Ok(Vec::new()),
+                }
+            }
+        };
+        pending = match timeout(LOGIN_TIMEOUT, first_lines).await {
+            Ok(lines) => lines?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "no login")),
+        };
+        if pending.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = pending.remove(0).and_then(|line| session.login(&line)) {
+            let _ = sink.send(input.error(&e)).await;
+            let _ = sink.close().await;
+            return Ok(());
+        }
+    }
+
+    // Either write directly, or hand messages to a writer task through the queue
+    let (mut output, mut drain): (Output<O>, Option<JoinHandle<io::Result<()>>>) = if options.queue_len > 0 {
+        let queue = Arc::new(ClientQueue::new(options.queue_len, session.stats.counters().clone()));
+        let drain = tokio::spawn(drain_queue(queue.clone(), sink));
+        (Output::Queued(queue), Some(drain))
+    } else {
+        (Output::Direct(sink), None)
+    };
+
+    let mut lines = pending;
+    let result = 'serve: loop {
+        for reply in lines.drain(..).filter_map(|line| line.map_or_else(Some, |line| session.receive(&line))) {
+            if let Err(e) = output.send(input.error(&reply)).await {
+                break 'serve Err(e);
+            }
+        }
+
+        tokio::select! {
+            // Read from the client
+            result = input.read() => {
+                match result {
+                    Ok(Some((n, read))) => {
+                        session.received(n);
+                        lines = read;
+                    }
+                    Ok(None) => break Ok(()), // Client disconnected
+                    Err(e) => break Err(e),
+                }
+            }
+
+            // Receive broadcast messages
+            result = receiver.recv(), if session.can_read() => {
+                let sentence = match result {
+                    Ok(sentence) => sentence,
+                    Err(RecvError::Lagged(n)) => {
+                        session.lagged(n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break Ok(()),
+                };
+                if !session.wants(&sentence) {
+                    continue;
+                }
+                let message = input.encode(&sentence);
+                session.sent(message.len());
+                if let Err(e) = output.send(message).await {
+                    break Err(e);
+                }
+            }
+
+            // Clients that only send have nothing left to flush
+            _ = shutdown.stopping(), if !session.can_read() => {
+                stopping = true;
+                break Ok(());
+            }
+
+            // The writer task only finishes when writing to the client failed
+            result = async { drain.as_mut().unwrap().await }, if drain.is_some() => {
+                drain = None;
+                break result.unwrap_or_else(|e| Err(io::Error::other(e)));
+            }
+        }
+    };
+
+    let dropped = match &output {
+        Output::Queued(queue) => queue.dropped(),
+        Output::Direct(_) => 0,
+    };
+    if stopping || receiver.stopping() {
+        // Send what is still queued, then close the connection properly
+        match (output, drain) {
+            (Output::Queued(queue), Some(drain)) => {
+                queue.close();
+                let _ = drain.await;
+            }
+            (Output::Direct(mut sink), _) => {
+                let _ = sink.close().await;
+            }
+            _ => {}
+        }
+    } else if let Some(drain) = drain {
+        drain.abort();
+    }
+    session.disconnected(dropped);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::broadcast::channel;
+
+    #[tokio::test]
+    async fn test_queue_drops_oldest() {
+        let client = stats::client("192.168.1.11:51234".parse().unwrap());
+        let queue = ClientQueue::new(2, client.counters().clone());
+        queue.push("1".to_string());
+        queue.push("2".to_string());
+        queue.push("3".to_string());
+        queue.push("4".to_string());
+        assert_eq!(2, queue.dropped());
+        let report = stats::report(Duration::from_secs(10));
+        let entry = report["clients"].as_array().unwrap().iter().find(|c| c["address"] == "192.168.1.11:51234");
+        assert_eq!(2, entry.unwrap()["dropped"]);
+        assert_eq!(Some("3".to_string()), queue.pop().await);
+        queue.close();
+        assert_eq!(Some("4".to_string()), queue.pop().await);
+        assert_eq!(None, queue.pop().await);
+    }
+
+    fn options(queue_len: usize, access: Access) -> ClientOptions {
+        ClientOptions {
+            queue_len,
+            access,
+            filter: Filter::default(),
+            users: Vec::new(),
+            tls: false,
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    async fn connect(tx: &Sender<Sentence>, receiver: Receiver<Sentence>, options: ClientOptions) -> BufReader<TcpStream> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        tokio::spawn(handle_client(stream, addr, tx.clone(), receiver, options));
+        BufReader::new(client)
+    }
+
+    async fn read_line(client: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        line
+    }
+
+    #[tokio::test]
+    async fn test_lagging_client_stays_connected() {
+        let source: Arc<str> = Arc::from("test");
+        for options in [options(0, Access::Read), options(4, Access::Read)] {
+            let (tx, receiver) = channel::<Sentence>(2);
+            for i in 0..10 {
+                tx.send(Sentence::new(&source, &format!("$MSG{}", i))).unwrap();
+            }
+            let mut client = connect(&tx, receiver, options).await;
+
+            // The oldest messages were skipped, the newest ones still arrive
+            assert_eq!("$MSG8\r\n", read_line(&mut client).await);
+            assert_eq!("$MSG9\r\n", read_line(&mut client).await);
+
+            tx.send(Sentence::new(&source, "$MSG10")).unwrap();
+            assert_eq!("$MSG10\r\n", read_line(&mut client).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_input_forwarding() {
+        let (tx, mut bus) = channel::<Sentence>(16);
+        let mut plotter = connect(&tx, tx.subscribe(), options(0, Access::ReadWrite)).await;
+        let mut display = connect(&tx, tx.subscribe(), options(0, Access::Read)).await;
+
+        let apb = "$ECAPB,A,A,0.10,R,N,V,V,011,M,DEST,011,M,011,M*2D";
+        plotter.get_mut().write_all(format!("{}\r\n$ECAPB,bad*00\r\n", apb).as_bytes()).await.unwrap();
+
+        // Validated and re-injected into the bus under the client's name
+        let sentence = bus.recv().await.unwrap();
+        assert_eq!(apb, sentence.line);
+        assert!(sentence.source.starts_with("client:127.0.0.1:"));
+        assert_eq!(format!("{}\r\n", apb), read_line(&mut display).await);
+
+        // A read-only client cannot inject sentences
+        display.get_mut().write_all(b"$GPHDT,123.4,T*31\r\n").await.unwrap();
+
+        // The plotter never receives its own sentence back, only other traffic
+        let source: Arc<str> = Arc::from("gps");
+        tx.send(Sentence::new(&source, "$GPHDT,1,T*2A")).unwrap();
+        assert_eq!("$GPHDT,1,T*2A\r\n", read_line(&mut plotter).await);
+        assert_eq!(&*source, &*bus.recv().await.unwrap().source);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        assert!(bus.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_filters() {
+        let (tx, _bus) = channel::<Sentence>(16);
+        let mut filtered = options(0, Access::Read);
+        filtered.filter = "-*GSV".parse().unwrap();
+        let mut client = connect(&tx, tx.subscribe(), filtered).await;
+
+        // Only AIS for this client, on top of the listener's filter
+        client.get_mut().write_all(b"#FILTER AIVDM\r\n#FILTER +AIVDM\r\n").await.unwrap();
+        assert!(read_line(&mut client).await.starts_with("#ERROR"));
+        // Not a rule, and not the end of the connection either
+        client.get_mut().write_all("#FILTER é\r\n".as_bytes()).await.unwrap();
+        assert!(read_line(&mut client).await.starts_with("#ERROR"));
+
+        let source: Arc<str> = Arc::from("gps");
+        for line in ["$GPGSV,3,1,11*7B", "$GPHDT,1,T*2A", "!AIVDM,1*00"] {
+            tx.send(Sentence::new(&source, line)).unwrap();
+        }
+        assert_eq!("!AIVDM,1*00\r\n", read_line(&mut client).await);
+
+        // An empty filter goes back to the listener's
+        client.get_mut().write_all(b"#FILTER\r\n").await.unwrap();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        for line in ["$GPGSV,3,1,11*7B", "$GPHDT,1,T*2A"] {
+            tx.send(Sentence::new(&source, line)).unwrap();
+        }
+        assert_eq!("$GPHDT,1,T*2A\r\n", read_line(&mut client).await);
+    }
+}
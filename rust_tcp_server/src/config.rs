@@ -0,0 +1,1280 @@
+use crate::anchor;
+use crate::derived::{self, Derivation};
+use crate::filter::Filter;
+use crate::recording::LogFormat;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use std::{fmt, fs};
+
+pub const DEFAULT_LISTEN: &str = "0.0.0.0:8080";
+pub const DEFAULT_WS_LISTEN: &str = "0.0.0.0:8081";
+pub const DEFAULT_SIGNALK_LISTEN: &str = "0.0.0.0:3000";
+pub const DEFAULT_STATUS_LISTEN: &str = "0.0.0.0:8082";
+pub const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(10);
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 256;
+const DEFAULT_UDP_BIND: &str = "0.0.0.0:0";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SourceKind {
+    // Written to as well when there is an output filter
+    Serial { path: String, settings: SerialSettings, output: Option<Filter> },
+    TcpClient { addr: String },
+    Udp { bind: String },
+    Stdin,
+    // A recorded log; speed 0 replays as fast as possible
+    Replay { path: String, speed: f64, looping: bool },
+    // Sentences computed from the others on the bus; inputs are smoothed over damping
+    Derived { outputs: Vec<Derivation>, damping: Duration },
+    // Alarms when the boat leaves radius metres around the anchor point
+    Anchor { radius: f64, track_len: usize },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceConfig {
+    pub name: String,
+    pub kind: SourceKind,
+    pub filter: Filter,
+}
+
+impl SourceConfig {
+    pub fn new(kind: SourceKind) -> SourceConfig {
+        let name = match &kind {
+            SourceKind::Serial { path, .. } => format!("serial:{}", path),
+            SourceKind::TcpClient { addr } => format!("tcp:{}", addr),
+            SourceKind::Udp { bind } => format!("udp:{}", bind),
+            SourceKind::Stdin => "stdin".to_string(),
+            SourceKind::Replay { path, .. } => format!("replay:{}", path),
+            SourceKind::Derived { .. } => "derived".to_string(),
+            SourceKind::Anchor { .. } => "anchor".to_string(),
+        };
+        SourceConfig {
+            name,
+            kind,
+            filter: Filter::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+/// Line settings of a serial port, written like `38400,8N1`. The default is
+/// the NMEA 0183 standard of 4800 baud, 8 data bits, no parity, 1 stop bit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SerialSettings {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+}
+
+impl Default for SerialSettings {
+    fn default() -> SerialSettings {
+        SerialSettings {
+            baud: 4800,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+        }
+    }
+}
+
+impl SerialSettings {
+    fn check(self) -> Result<SerialSettings, String> {
+        if self.baud == 0 {
+            return Err("baud rate must be above 0".to_string());
+        }
+        if !(5..=8).contains(&self.data_bits) {
+            return Err(format!("{} data bits, expected 5 to 8", self.data_bits));
+        }
+        if !(1..=2).contains(&self.stop_bits) {
+            return Err(format!("{} stop bits, expected 1 or 2", self.stop_bits));
+        }
+        Ok(self)
+    }
+}
+
+impl fmt::Display for SerialSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+        };
+        write!(f, "{},{}{}{}", self.baud, self.data_bits, parity, self.stop_bits)
+    }
+}
+
+impl FromStr for Parity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Parity, String> {
+        match s {
+            "N" | "n" | "none" => Ok(Parity::None),
+            "O" | "o" | "odd" => Ok(Parity::Odd),
+            "E" | "e" | "even" => Ok(Parity::Even),
+            _ => Err(format!("unknown parity {:?}, expected none, odd or even", s)),
+        }
+    }
+}
+
+impl FromStr for SerialSettings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SerialSettings, String> {
+        let bad = || format!("bad serial settings {:?}, expected e.g. 38400 or 4800,8N1", s);
+        let (baud, frame) = s.split_once(',').unwrap_or((s, "8N1"));
+        let frame = frame.as_bytes();
+        if frame.len() != 3 {
+            return Err(bad());
+        }
+        let digit = |b: u8| (b as char).to_digit(10).map(|d| d as u8).ok_or_else(bad);
+        SerialSettings {
+            baud: baud.parse().map_err(|_| bad())?,
+            data_bits: digit(frame[0])?,
+            parity: (frame[1] as char).to_string().parse()?,
+            stop_bits: digit(frame[2])?,
+        }
+        .check()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenerKind {
+    Tcp { bind: String },
+    // Browsers; the same clients as TCP, framed as WebSocket messages
+    WebSocket { bind: String },
+    // Signal K discovery and delta stream, converted from NMEA 0183
+    SignalK { bind: String },
+    // Unicast, broadcast or multicast, depending on the target address
+    Udp { target: String, bind: String },
+    // Appends everything on the bus to a log file
+    Record { path: String, format: LogFormat },
+    // JSON statistics over HTTP; sources silent for stale_after are flagged
+    Status { bind: String, stale_after: Duration },
+}
+
+/// Somewhere sentences are sent to: a TCP or WebSocket port clients connect
+/// to, or a UDP target. Access, queue, TLS and user settings only apply to
+/// connected clients.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub kind: ListenerKind,
+    pub filter: Filter,
+    pub access: Access,
+    pub client_access: Vec<(IpAddr, Access)>,
+    pub queue_len: usize,
+    pub tls: Option<TlsConfig>,
+    // When set, clients must log in as one of them and get that user's access
+    pub users: Vec<User>,
+}
+
+/// PEM files with the server's certificate chain and private key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+/// A login for `#AUTH NAME PASSWORD`, or `#AUTH PASSWORD` when the password
+/// is a token only this user has. Written like `plotter:rw:s3cret`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub access: Access,
+    pub password: String,
+}
+
+impl FromStr for User {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<User, String> {
+        let mut parts = s.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(access), Some(password)) if !name.is_empty() && !password.is_empty() => Ok(User {
+                name: name.to_string(),
+                access: access.parse()?,
+                password: password.to_string(),
+            }),
+            _ => Err(format!("bad user {:?}, expected NAME:MODE:PASSWORD", s)),
+        }
+    }
+}
+
+impl ListenerConfig {
+    pub fn new(kind: ListenerKind) -> ListenerConfig {
+        let name = match &kind {
+            ListenerKind::Tcp { bind } => format!("tcp-listen:{}", bind),
+            ListenerKind::WebSocket { bind } => format!("ws-listen:{}", bind),
+            ListenerKind::SignalK { bind } => format!("signalk-listen:{}", bind),
+            ListenerKind::Udp { target, .. } => format!("udp-out:{}", target),
+            ListenerKind::Record { path, .. } => format!("record:{}", path),
+            ListenerKind::Status { bind, .. } => format!("status-listen:{}", bind),
+        };
+        ListenerConfig {
+            name,
+            kind,
+            filter: Filter::default(),
+            access: Access::Read,
+            client_access: Vec::new(),
+            queue_len: 0,
+            tls: None,
+            users: Vec::new(),
+        }
+    }
+
+    pub fn access_for(&self, ip: IpAddr) -> Access {
+        self.client_access
+            .iter()
+            .find(|(client_ip, _)| *client_ip == ip)
+            .map_or(self.access, |(_, access)| *access)
+    }
+}
+
+/// What a connected client may do: receive sentences from the bus, send
+/// sentences into it (e.g. APB/RMB from OpenCPN for the autopilot), or both.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn can_read(self) -> bool {
+        self != Access::Write
+    }
+
+    pub fn can_write(self) -> bool {
+        self != Access::Read
+    }
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Access, String> {
+        match s {
+            "r" | "ro" | "read" => Ok(Access::Read),
+            "w" | "wo" | "write" => Ok(Access::Write),
+            "rw" | "read-write" => Ok(Access::ReadWrite),
+            _ => Err(format!("unknown access mode {:?}, expected r, w or rw", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub channel_capacity: usize,
+    pub listeners: Vec<ListenerConfig>,
+    pub sources: Vec<SourceConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            listeners: vec![ListenerConfig::new(ListenerKind::Tcp {
+                bind: DEFAULT_LISTEN.to_string(),
+            })],
+            sources: Vec::new(),
+        }
+    }
+}
+
+pub const USAGE: &str = "\
+Usage: rust_tcp_server [OPTIONS]
+       rust_tcp_server --config FILE
+
+Options:
+  --config FILE        read listeners, sources and filters from a TOML file;
+                       SIGHUP reloads it
+  --listen ADDR        TCP address clients connect to (default 0.0.0.0:8080)
+  --serial PATH[@SETTINGS]
+                       read sentences from a serial device; SETTINGS are the
+                       baud rate and frame, e.g. 38400 or 4800,8N1 (default)
+  --tcp HOST:PORT      read sentences from a remote TCP server
+  --udp ADDR           read sentences from UDP datagrams received on ADDR
+                       (a multicast group address joins that group)
+  --ws ADDR            also accept WebSocket clients on ADDR; they get raw NMEA
+                       or JSON (subprotocol nmea0183/json or ?format=)
+  --signalk ADDR       serve Signal K deltas on ADDR (/signalk discovery and
+                       the /signalk/v1/stream WebSocket)
+  --status ADDR        serve statistics as JSON on http://ADDR/status
+  --stale-after SECS   flag sources silent for SECS as stale (default 10)
+  --udp-out TARGET[@BIND]
+                       send sentences as UDP datagrams to TARGET, which may be
+                       a unicast, broadcast or multicast address; the socket is
+                       bound to BIND (default 0.0.0.0:0)
+  --stdin              read sentences from standard input
+  --record FILE        append every sentence to FILE with its receive time
+  --record-format FORMAT
+                       timestamp (ISO 8601 time before each sentence, the
+                       default) or tag (NMEA TAG block with c: in ms)
+  --replay FILE        feed a recorded log back in with its original timing
+  --replay-speed X     replay X times faster; 0 is as fast as possible
+  --loop               start replays over at the end of the log
+  --derive LIST        compute sentences the instruments do not send:
+                       true-wind (MWV, MWD), hdt, vmg (VPW) and set-drift
+                       (VDR), each with an optional talker (hdt:HC, default II)
+  --damping SECS       smooth the inputs of --derive over SECS (default 0)
+  --anchor-watch METRES
+                       watch the swing circle once the anchor is dropped
+                       (POST /anchor/drop on the --status address) and
+                       raise ALR/ALF alarms outside it
+  --buffer N           messages kept for clients that fall behind (default 256)
+  --client-queue N     give every client its own queue of N messages that
+                       drops the oldest ones when full (default off)
+  --access MODE        what clients may do: r (receive only), w (send only)
+                       or rw (default r)
+  --client-access IP=MODE
+                       access mode for clients connecting from IP
+  --filter SPEC        filter for sentences sent to clients
+  --tls-cert FILE      serve TCP, WebSocket and Signal K clients over TLS with
+  --tls-key FILE       this PEM certificate chain and private key
+  --user NAME:MODE:PASSWORD
+                       require clients to log in with \"#AUTH NAME PASSWORD\"
+                       (or \"#AUTH PASSWORD\") and give NAME access MODE
+  --source-filter NAME=SPEC
+                       filter for sentences read from source NAME
+                       (e.g. serial:/dev/ttyUSB0=-GPGSV)
+  --output-filter NAME=SPEC
+                       filter for sentences written to output NAME
+                       (e.g. udp-out:192.168.1.255:10110=+AIVDM); naming a
+                       serial source writes matching sentences to its port
+                       (e.g. serial:/dev/ttyUSB1=+*APB)
+  -h, --help           print this help
+
+Filters are comma separated rules, the first matching one wins:
+  +PATTERN             pass matching sentences (others are dropped)
+  -PATTERN             drop matching sentences
+  ~PATTERN=HZ          pass at most HZ sentences per second per address
+PATTERN is an address (AIVDM, *GSV, GP*) or talker:XX, type:XXX, src:NAME.
+Clients can set their own filter by sending \"#FILTER SPEC\".";
+
+/// A configuration error, with the place in the configuration it refers to.
+#[derive(Debug, PartialEq)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for ConfigError {}
+
+fn config_error<T>(context: &str, message: impl fmt::Display) -> Result<T, ConfigError> {
+    Err(ConfigError(format!("{}: {}", context, message)))
+}
+
+impl Config {
+    /// Either `--config FILE` alone, or the individual command line options.
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<(Config, Option<String>), Box<dyn Error>> {
+        let args: Vec<String> = args.collect();
+        if let Some(i) = args.iter().position(|arg| arg == "--config") {
+            if args.len() != 2 {
+                return Err("--config FILE cannot be combined with other options".into());
+            }
+            let path = args.get(i + 1).ok_or("--config needs a value")?;
+            return Ok((Config::load(path)?, Some(path.clone())));
+        }
+        Ok((Config::parse_args(args.into_iter())?, None))
+    }
+
+    fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Config, Box<dyn Error>> {
+        let mut config = Config {
+            listeners: Vec::new(),
+            ..Config::default()
+        };
+        let mut tcp = ListenerConfig::new(ListenerKind::Tcp {
+            bind: DEFAULT_LISTEN.to_string(),
+        });
+        let mut ws = None;
+        let mut signalk = None;
+        let mut status = None;
+        let mut stale_after = DEFAULT_STALE_AFTER;
+        let (mut tls_cert, mut tls_key) = (None, None);
+        let mut record_format = LogFormat::Timestamp;
+        let mut replay_speed = 1.0;
+        let mut replay_loop = false;
+        let mut damping = Duration::ZERO;
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--listen" => tcp.kind = ListenerKind::Tcp { bind: value()? },
+                "--serial" => {
+                    let value = value()?;
+                    let (path, settings) = match value.rsplit_once('@') {
+                        Some((path, settings)) => (path, settings.parse()?),
+                        None => (value.as_str(), SerialSettings::default()),
+                    };
+                    config.sources.push(SourceConfig::new(SourceKind::Serial {
+                        path: path.to_string(),
+                        settings,
+                        output: None,
+                    }));
+                }
+                "--tcp" => config.sources.push(SourceConfig::new(SourceKind::TcpClient { addr: value()? })),
+                "--udp" => config.sources.push(SourceConfig::new(SourceKind::Udp { bind: value()? })),
+                "--stdin" => config.sources.push(SourceConfig::new(SourceKind::Stdin)),
+                "--record" => config.listeners.push(ListenerConfig::new(ListenerKind::Record {
+                    path: value()?,
+                    format: LogFormat::Timestamp,
+                })),
+                "--record-format" => record_format = value()?.parse()?,
+                "--replay" => config.sources.push(SourceConfig::new(SourceKind::Replay {
+                    path: value()?,
+                    speed: 1.0,
+                    looping: false,
+                })),
+                "--replay-speed" => replay_speed = parse_speed(&value()?)?,
+                "--loop" => replay_loop = true,
+                "--derive" => config.sources.push(SourceConfig::new(SourceKind::Derived {
+                    outputs: derived::parse_list(&value()?)?,
+                    damping: Duration::ZERO,
+                })),
+                "--damping" => damping = parse_damping(&value()?)?,
+                "--anchor-watch" => config.sources.push(SourceConfig::new(SourceKind::Anchor {
+                    radius: parse_radius(&value()?)?,
+                    track_len: anchor::DEFAULT_TRACK_LEN,
+                })),
+                "--ws" => ws = Some(value()?),
+                "--signalk" => signalk = Some(value()?),
+                "--status" => status = Some(value()?),
+                "--stale-after" => stale_after = parse_seconds(&value()?)?,
+                "--udp-out" => {
+                    let value = value()?;
+                    let (target, bind) = value.split_once('@').unwrap_or((&value, DEFAULT_UDP_BIND));
+                    config.listeners.push(ListenerConfig::new(ListenerKind::Udp {
+                        target: target.to_string(),
+                        bind: bind.to_string(),
+                    }));
+                }
+                "--buffer" => config.channel_capacity = parse_count(&arg, &value()?)?,
+                "--client-queue" => tcp.queue_len = parse_count(&arg, &value()?)?,
+                "--access" => tcp.access = value()?.parse()?,
+                "--filter" => tcp.filter = value()?.parse()?,
+                "--tls-cert" => tls_cert = Some(value()?),
+                "--tls-key" => tls_key = Some(value()?),
+                "--user" => tcp.users.push(value()?.parse()?),
+                "--source-filter" | "--output-filter" => {
+                    let value = value()?;
+                    let (name, spec) = value
+                        .split_once('=')
+                        .ok_or_else(|| format!("{} {}: expected NAME=SPEC", arg, value))?;
+                    let source = config.sources.iter_mut().find(|source| source.name == name);
+                    let filter = if arg == "--source-filter" {
+                        source.map(|source| &mut source.filter)
+                    } else if let Some(SourceKind::Serial { output, .. }) = source.map(|source| &mut source.kind) {
+                        // Serial ports are written to once they have an output filter
+                        Some(output.insert(Filter::default()))
+                    } else {
+                        config.listeners.iter_mut().find(|output| output.name == name).map(|output| &mut output.filter)
+                    };
+                    *filter.ok_or_else(|| format!("{}: nothing named {:?} (declare it first)", arg, name))? = spec.parse()?;
+                }
+                "--client-access" => {
+                    let value = value()?;
+                    let (ip, mode) = value
+                        .split_once('=')
+                        .ok_or_else(|| format!("{} {}: expected IP=MODE", arg, value))?;
+                    let ip = ip.parse().map_err(|e| format!("{} {}: {}", arg, value, e))?;
+                    tcp.client_access.push((ip, mode.parse()?));
+                }
+                _ => return Err(format!("unknown option {}\n\n{}", arg, USAGE).into()),
+            }
+        }
+        for listener in &mut config.listeners {
+            if let ListenerKind::Record { format, .. } = &mut listener.kind {
+                *format = record_format;
+            }
+        }
+        for source in &mut config.sources {
+            if let SourceKind::Replay { speed, looping, .. } = &mut source.kind {
+                (*speed, *looping) = (replay_speed, replay_loop);
+            }
+            if let SourceKind::Derived { damping: source_damping, .. } = &mut source.kind {
+                *source_damping = damping;
+            }
+        }
+        tcp.tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            _ => return Err("--tls-cert and --tls-key go together".into()),
+        };
+        // Named after its final address
+        tcp.name = ListenerConfig::new(tcp.kind.clone()).name;
+        if let Some(bind) = ws {
+            // Browser clients follow the same rules as TCP ones
+            let kind = ListenerKind::WebSocket { bind };
+            let name = ListenerConfig::new(kind.clone()).name;
+            config.listeners.insert(0, ListenerConfig { name, kind, ..tcp.clone() });
+        }
+        if let Some(bind) = signalk {
+            let mut listener = ListenerConfig::new(ListenerKind::SignalK { bind });
+            // Same certificate and logins as the TCP clients
+            listener.filter = tcp.filter.clone();
+            listener.tls = tcp.tls.clone();
+            listener.users = tcp.users.clone();
+            config.listeners.insert(0, listener);
+        }
+        if let Some(bind) = status {
+            config.listeners.push(ListenerConfig::new(ListenerKind::Status { bind, stale_after }));
+        }
+        config.listeners.insert(0, tcp);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let context = path.display().to_string();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return config_error(&context, e),
+        };
+        Config::parse_toml(&text).or_else(|e| config_error(&context, e))
+    }
+
+    pub fn parse_toml(text: &str) -> Result<Config, ConfigError> {
+        let file: FileConfig = match toml::from_str(text) {
+            Ok(file) => file,
+            Err(e) => return Err(ConfigError(e.to_string().trim_end().to_string())),
+        };
+        let config = Config {
+            channel_capacity: file.channel_capacity,
+            listeners: file
+                .listeners
+                .into_iter()
+                .enumerate()
+                .map(|(i, listener)| listener.into_config(i + 1))
+                .collect::<Result<_, _>>()?,
+            sources: file
+                .sources
+                .into_iter()
+                .enumerate()
+                .map(|(i, source)| source.into_config(i + 1))
+                .collect::<Result<_, _>>()?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks what the parsers cannot: address syntax and unique names.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.channel_capacity == 0 {
+            return config_error("channel_capacity", "must be at least 1");
+        }
+        let mut names = HashSet::new();
+        for listener in &self.listeners {
+            if !names.insert(&listener.name) {
+                return config_error(&listener.name, "duplicate listener name");
+            }
+            let bind = match &listener.kind {
+                ListenerKind::Tcp { bind }
+                | ListenerKind::WebSocket { bind }
+                | ListenerKind::SignalK { bind }
+                | ListenerKind::Status { bind, .. }
+                | ListenerKind::Udp { bind, .. } => bind,
+                ListenerKind::Record { .. } => continue,
+            };
+            if let Err(e) = bind.parse::<SocketAddr>() {
+                return config_error(&listener.name, format!("bad bind address {:?}: {}", bind, e));
+            }
+        }
+        names.clear();
+        let mut anchor_watch = false;
+        for source in &self.sources {
+            if !names.insert(&source.name) {
+                return config_error(&source.name, "duplicate source name");
+            }
+            if let SourceKind::Anchor { .. } = source.kind {
+                // There is one anchor to watch
+                if std::mem::replace(&mut anchor_watch, true) {
+                    return config_error(&source.name, "only one anchor watch");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_speed(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(speed) if speed >= 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(format!("bad replay speed {:?}, expected a factor like 2 or 0.5, or 0", value)),
+    }
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(Duration::from_secs_f64(seconds)),
+        _ => Err(format!("bad timeout {:?}, expected a number of seconds like 10 or 2.5", value)),
+    }
+}
+
+fn parse_damping(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds >= 0.0 && seconds.is_finite() => Ok(Duration::from_secs_f64(seconds)),
+        _ => Err(format!("bad damping {:?}, expected a number of seconds like 2, or 0 for none", value)),
+    }
+}
+
+fn parse_radius(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(radius) if radius > 0.0 && radius.is_finite() => Ok(radius),
+        _ => Err(format!("bad radius {:?}, expected metres like 40", value)),
+    }
+}
+
+fn parse_count(arg: &str, value: &str) -> Result<usize, Box<dyn Error>> {
+    value
+        .parse()
+        .map_err(|e| format!("{} {}: {}", arg, value, e).into())
+}
+
+// The TOML layout; everything is checked while converting it into a Config
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    #[serde(default = "default_channel_capacity")]
+    channel_capacity: usize,
+    #[serde(default, rename = "listener")]
+    listeners: Vec<FileListener>,
+    #[serde(default, rename = "source")]
+    sources: Vec<FileSource>,
+}
+
+fn default_channel_capacity() -> usize {
+    DEFAULT_CHANNEL_CAPACITY
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileListener {
+    #[serde(rename = "type")]
+    kind: String,
+    name: Option<String>,
+    bind: Option<String>,
+    target: Option<String>,
+    filter: Option<String>,
+    access: Option<String>,
+    #[serde(default)]
+    clients: BTreeMap<String, String>,
+    queue: Option<usize>,
+    path: Option<String>,
+    format: Option<String>,
+    stale_after: Option<f64>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    #[serde(default)]
+    users: Vec<FileUser>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileUser {
+    name: String,
+    password: String,
+    #[serde(default = "default_user_access")]
+    access: String,
+}
+
+fn default_user_access() -> String {
+    "r".to_string()
+}
+
+impl FileListener {
+    fn into_config(self, index: usize) -> Result<ListenerConfig, ConfigError> {
+        let context = match &self.name {
+            Some(name) => format!("listener {:?}", name),
+            None => format!("listener #{}", index),
+        };
+        let clients_only = |field: &str, set: bool| match set && self.kind != "tcp" && self.kind != "ws" {
+            true => config_error(&context, format!("`{}` only applies to tcp and ws listeners", field)),
+            false => Ok(()),
+        };
+        clients_only("access", self.access.is_some())?;
+        clients_only("clients", !self.clients.is_empty())?;
+        clients_only("queue", self.queue.is_some())?;
+        let secured = |field: &str, set: bool| match set && !matches!(&*self.kind, "tcp" | "ws" | "signalk") {
+            true => config_error(&context, format!("`{}` only applies to tcp, ws and signalk listeners", field)),
+            false => Ok(()),
+        };
+        secured("tls_cert", self.tls_cert.is_some())?;
+        secured("tls_key", self.tls_key.is_some())?;
+        secured("users", !self.users.is_empty())?;
+        if self.target.is_some() && self.kind != "udp" {
+            return config_error(&context, "`target` only applies to udp listeners");
+        }
+        if (self.path.is_some() || self.format.is_some()) && self.kind != "record" {
+            return config_error(&context, "`path` and `format` only apply to record listeners");
+        }
+        if self.stale_after.is_some() && self.kind != "status" {
+            return config_error(&context, "`stale_after` only applies to status listeners");
+        }
+        if self.filter.is_some() && self.kind == "status" {
+            return config_error(&context, "status listeners do not send sentences, `filter` does not apply");
+        }
+
+        let kind = match self.kind.as_str() {
+            "tcp" => ListenerKind::Tcp {
+                bind: self.bind.unwrap_or_else(|| DEFAULT_LISTEN.to_string()),
+            },
+            "ws" => ListenerKind::WebSocket {
+                bind: self.bind.unwrap_or_else(|| DEFAULT_WS_LISTEN.to_string()),
+            },
+            "signalk" => ListenerKind::SignalK {
+                bind: self.bind.unwrap_or_else(|| DEFAULT_SIGNALK_LISTEN.to_string()),
+            },
+            "udp" => match self.target {
+                Some(target) => ListenerKind::Udp {
+                    target,
+                    bind: self.bind.unwrap_or_else(|| DEFAULT_UDP_BIND.to_string()),
+                },
+                None => return config_error(&context, "udp listeners need a `target`"),
+            },
+            "record" => match self.path {
+                Some(path) => ListenerKind::Record {
+                    path,
+                    format: match self.format {
+                        Some(format) => format.parse().or_else(|e| config_error(&context, e))?,
+                        None => LogFormat::Timestamp,
+                    },
+                },
+                None => return config_error(&context, "record listeners need a `path`"),
+            },
+            "status" => ListenerKind::Status {
+                bind: self.bind.unwrap_or_else(|| DEFAULT_STATUS_LISTEN.to_string()),
+                stale_after: match self.stale_after {
+                    Some(seconds) => parse_seconds(&seconds.to_string()).or_else(|e| config_error(&context, e))?,
+                    None => DEFAULT_STALE_AFTER,
+                },
+            },
+            other => {
+                return config_error(
+                    &context,
+                    format!("unknown type {:?}, expected tcp, ws, signalk, udp, record or status", other),
+                )
+            }
+        };
+
+        let mut listener = ListenerConfig::new(kind);
+        if let Some(name) = self.name {
+            listener.name = name;
+        }
+        if let Some(filter) = self.filter {
+            listener.filter = filter.parse().or_else(|e| config_error(&context, e))?;
+        }
+        if let Some(access) = self.access {
+            listener.access = access.parse().or_else(|e| config_error(&context, e))?;
+        }
+        for (ip, access) in self.clients {
+            let ip = ip
+                .parse()
+                .or_else(|e| config_error(&context, format!("bad client address {:?}: {}", ip, e)))?;
+            listener
+                .client_access
+                .push((ip, access.parse().or_else(|e| config_error(&context, e))?));
+        }
+        listener.queue_len = self.queue.unwrap_or(0);
+        listener.tls = match (self.tls_cert, self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            _ => return config_error(&context, "`tls_cert` and `tls_key` go together"),
+        };
+        for user in self.users {
+            let access = user.access.parse().or_else(|e| config_error(&context, e))?;
+            if user.password.is_empty() {
+                return config_error(&context, format!("user {:?} needs a password", user.name));
+            }
+            listener.users.push(User { name: user.name, access, password: user.password });
+        }
+        Ok(listener)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileSource {
+    #[serde(rename = "type")]
+    kind: String,
+    name: Option<String>,
+    path: Option<String>,
+    address: Option<String>,
+    bind: Option<String>,
+    filter: Option<String>,
+    speed: Option<f64>,
+    #[serde(default, rename = "loop")]
+    looping: bool,
+    baud: Option<u32>,
+    data_bits: Option<u8>,
+    parity: Option<String>,
+    stop_bits: Option<u8>,
+    output: Option<String>,
+    derive: Option<String>,
+    damping: Option<f64>,
+    radius: Option<f64>,
+    track: Option<usize>,
+}
+
+impl FileSource {
+    fn into_config(self, index: usize) -> Result<SourceConfig, ConfigError> {
+        let context = match &self.name {
+            Some(name) => format!("source {:?}", name),
+            None => format!("source #{}", index),
+        };
+        let required = |value: Option<String>, field: &str| match value {
+            Some(value) => Ok(value),
+            None => config_error(&context, format!("{} sources need `{}`", self.kind, field)),
+        };
+        let serial = self.baud.is_some()
+            || self.data_bits.is_some()
+            || self.parity.is_some()
+            || self.stop_bits.is_some()
+            || self.output.is_some();
+        if serial && self.kind != "serial" {
+            return config_error(&context, "`baud`, `data_bits`, `parity`, `stop_bits` and `output` only apply to serial sources");
+        }
+        if (self.derive.is_some() || self.damping.is_some()) && self.kind != "derived" {
+            return config_error(&context, "`derive` and `damping` only apply to derived sources");
+        }
+        if (self.radius.is_some() || self.track.is_some()) && self.kind != "anchor" {
+            return config_error(&context, "`radius` and `track` only apply to anchor sources");
+        }
+        let kind = match self.kind.as_str() {
+            "serial" => {
+                let default = SerialSettings::default();
+                let settings = SerialSettings {
+                    baud: self.baud.unwrap_or(default.baud),
+                    data_bits: self.data_bits.unwrap_or(default.data_bits),
+                    parity: match self.parity {
+                        Some(parity) => parity.parse().or_else(|e| config_error(&context, e))?,
+                        None => default.parity,
+                    },
+                    stop_bits: self.stop_bits.unwrap_or(default.stop_bits),
+                };
+                SourceKind::Serial {
+                    path: required(self.path, "path")?,
+                    settings: settings.check().or_else(|e| config_error(&context, e))?,
+                    output: match self.output {
+                        Some(output) => Some(output.parse().or_else(|e| config_error(&context, e))?),
+                        None => None,
+                    },
+                }
+            }
+            "tcp" => SourceKind::TcpClient { addr: required(self.address, "address")? },
+            "udp" => SourceKind::Udp { bind: required(self.bind, "bind")? },
+            "stdin" => SourceKind::Stdin,
+            "derived" => SourceKind::Derived {
+                outputs: derived::parse_list(&required(self.derive, "derive")?).or_else(|e| config_error(&context, e))?,
+                damping: parse_damping(&self.damping.unwrap_or(0.0).to_string()).or_else(|e| config_error(&context, e))?,
+            },
+            "anchor" => SourceKind::Anchor {
+                radius: parse_radius(&self.radius.unwrap_or(anchor::DEFAULT_RADIUS).to_string())
+                    .or_else(|e| config_error(&context, e))?,
+                track_len: match self.track {
+                    Some(0) => return config_error(&context, "`track` must be at least 1"),
+                    Some(track) => track,
+                    None => anchor::DEFAULT_TRACK_LEN,
+                },
+            },
+            "replay" => SourceKind::Replay {
+                path: required(self.path, "path")?,
+                speed: parse_speed(&self.speed.unwrap_or(1.0).to_string()).or_else(|e| config_error(&context, e))?,
+                looping: self.looping,
+            },
+            other => {
+                return config_error(
+                    &context,
+                    format!("unknown type {:?}, expected serial, tcp, udp, stdin, replay, derived or anchor", other),
+                )
+            }
+        };
+        if (self.speed.is_some() || self.looping) && self.kind != "replay" {
+            return config_error(&context, "`speed` and `loop` only apply to replay sources");
+        }
+
+        let mut source = SourceConfig::new(kind);
+        if let Some(name) = self.name {
+            source.name = name;
+        }
+        if let Some(filter) = self.filter {
+            source.filter = filter.parse().or_else(|e| config_error(&context, e))?;
+        }
+        Ok(source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(String::from)
+    }
+
+    fn parse_args(line: &str) -> Result<Config, Box<dyn Error>> {
+        Config::parse_args(args(line))
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = parse_args("--serial /dev/ttyUSB0 --udp 0.0.0.0:10110 --stdin").unwrap();
+        assert_eq!(
+            ListenerKind::Tcp { bind: DEFAULT_LISTEN.to_string() },
+            config.listeners[0].kind
+        );
+        let names: Vec<&str> = config.sources.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(vec!["serial:/dev/ttyUSB0", "udp:0.0.0.0:10110", "stdin"], names);
+
+        assert_eq!(DEFAULT_CHANNEL_CAPACITY, config.channel_capacity);
+        assert_eq!(0, config.listeners[0].queue_len);
+
+        // The autopilot only gets APB over its own port
+        let config = parse_args(
+            "--serial /dev/ttyUSB0@38400 --serial /dev/ttyUSB1@4800,7E2 \
+             --output-filter serial:/dev/ttyUSB1=+*APB",
+        )
+        .unwrap();
+        assert_eq!(
+            SourceKind::Serial {
+                path: "/dev/ttyUSB0".to_string(),
+                settings: SerialSettings { baud: 38400, ..SerialSettings::default() },
+                output: None,
+            },
+            config.sources[0].kind
+        );
+        assert_eq!(
+            SourceKind::Serial {
+                path: "/dev/ttyUSB1".to_string(),
+                settings: SerialSettings { baud: 4800, data_bits: 7, parity: Parity::Even, stop_bits: 2 },
+                output: Some("+*APB".parse().unwrap()),
+            },
+            config.sources[1].kind
+        );
+        assert_eq!("4800,7E2", SerialSettings { data_bits: 7, parity: Parity::Even, stop_bits: 2, ..SerialSettings::default() }.to_string());
+        assert!(parse_args("--serial /dev/ttyUSB0@fast").is_err());
+        assert!(parse_args("--serial /dev/ttyUSB0@4800,9N1").is_err());
+        assert!(parse_args("--serial /dev/ttyUSB0@4800,8X1").is_err());
+
+        let config = parse_args("--listen 127.0.0.1:10110 --buffer 1024 --client-queue 32").unwrap();
+        assert_eq!("tcp-listen:127.0.0.1:10110", config.listeners[0].name);
+        assert_eq!(1024, config.channel_capacity);
+        assert_eq!(32, config.listeners[0].queue_len);
+
+        let config = parse_args("--access rw --client-access 10.0.0.5=r").unwrap();
+        assert_eq!(Access::ReadWrite, config.listeners[0].access_for("10.0.0.7".parse().unwrap()));
+        assert_eq!(Access::Read, config.listeners[0].access_for("10.0.0.5".parse().unwrap()));
+
+        // WebSocket clients share the TCP client settings
+        let config = parse_args("--ws 0.0.0.0:3000 --access rw --filter -*GSV").unwrap();
+        assert_eq!("ws-listen:0.0.0.0:3000", config.listeners[1].name);
+        assert_eq!(ListenerKind::WebSocket { bind: "0.0.0.0:3000".to_string() }, config.listeners[1].kind);
+        assert_eq!(config.listeners[0].filter, config.listeners[1].filter);
+        assert_eq!(Access::ReadWrite, config.listeners[1].access);
+
+        let config = parse_args("--record sail.log --record-format tag --replay old.log --replay-speed 0 --loop").unwrap();
+        assert_eq!(
+            ListenerKind::Record { path: "sail.log".to_string(), format: LogFormat::TagBlock },
+            config.listeners[1].kind
+        );
+        assert_eq!(
+            SourceKind::Replay { path: "old.log".to_string(), speed: 0.0, looping: true },
+            config.sources[0].kind
+        );
+        assert!(parse_args("--replay old.log --replay-speed -1").is_err());
+
+        let config = parse_args("--derive true-wind,hdt:HC --damping 2.5").unwrap();
+        assert_eq!("derived", config.sources[0].name);
+        assert_eq!(
+            SourceKind::Derived {
+                outputs: vec!["true-wind".parse().unwrap(), "hdt:HC".parse().unwrap()],
+                damping: Duration::from_millis(2500),
+            },
+            config.sources[0].kind
+        );
+        assert!(parse_args("--derive depth").is_err());
+        assert!(parse_args("--derive vmg --damping -1").is_err());
+
+        let config = parse_args("--anchor-watch 35").unwrap();
+        assert_eq!(
+            SourceKind::Anchor { radius: 35.0, track_len: anchor::DEFAULT_TRACK_LEN },
+            config.sources[0].kind
+        );
+        assert!(parse_args("--anchor-watch 0").is_err());
+        assert!(parse_args("--record sail.log --record-format csv").is_err());
+
+        let config = parse_args("--signalk 0.0.0.0:3000 --filter -*GSV").unwrap();
+        assert_eq!("signalk-listen:0.0.0.0:3000", config.listeners[1].name);
+        assert_eq!(config.listeners[0].filter, config.listeners[1].filter);
+        let config =
+            parse_args("--signalk 0.0.0.0:3000 --tls-cert boat.crt --tls-key boat.key --user display:r:d1splay-token")
+                .unwrap();
+        assert_eq!(config.listeners[0].tls, config.listeners[1].tls);
+        assert_eq!(config.listeners[0].users, config.listeners[1].users);
+
+        let config = parse_args("--status 127.0.0.1:8082 --stale-after 30").unwrap();
+        assert_eq!(
+            ListenerKind::Status { bind: "127.0.0.1:8082".to_string(), stale_after: Duration::from_secs(30) },
+            config.listeners[1].kind
+        );
+        assert!(parse_args("--status 127.0.0.1:8082 --stale-after -1").is_err());
+
+        // Logins and TLS are shared with the WebSocket listener
+        let config = parse_args("--ws 0.0.0.0:8081 --tls-cert boat.crt --tls-key boat.key --user plotter:rw:pass:word").unwrap();
+        let tls = Some(TlsConfig { cert: "boat.crt".to_string(), key: "boat.key".to_string() });
+        let plotter = User { name: "plotter".to_string(), access: Access::ReadWrite, password: "pass:word".to_string() };
+        for listener in &config.listeners[..2] {
+            assert_eq!(tls, listener.tls);
+            assert_eq!(vec![plotter.clone()], listener.users);
+        }
+        assert!(parse_args("--tls-cert boat.crt").is_err());
+        assert!(parse_args("--user plotter:rw").is_err());
+        assert!(parse_args("--user plotter:admin:secret").is_err());
+
+        let config = parse_args("--stdin --source-filter stdin=-*GSV --filter +AIVDM").unwrap();
+        assert_eq!("-*GSV".parse::<Filter>().unwrap(), config.sources[0].filter);
+        assert_eq!("+AIVDM".parse::<Filter>().unwrap(), config.listeners[0].filter);
+
+        let config = parse_args(
+            "--udp-out 192.168.1.255:10110 --udp-out 239.192.0.1:10110@192.168.1.5:0 \
+             --output-filter udp-out:239.192.0.1:10110=+AIVDM",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                ListenerKind::Udp { target: "192.168.1.255:10110".to_string(), bind: "0.0.0.0:0".to_string() },
+                ListenerKind::Udp { target: "239.192.0.1:10110".to_string(), bind: "192.168.1.5:0".to_string() },
+            ],
+            config.listeners[1..].iter().map(|output| output.kind.clone()).collect::<Vec<_>>()
+        );
+        assert!(config.listeners[1].filter.is_empty());
+        assert_eq!("+AIVDM".parse::<Filter>().unwrap(), config.listeners[2].filter);
+
+        assert!(parse_args("--source-filter stdin=-*GSV").is_err());
+        assert!(parse_args("--output-filter stdin=-*GSV").is_err());
+        assert!(parse_args("--filter GSV").is_err());
+        assert!(parse_args("--access x").is_err());
+        assert!(parse_args("--client-access 10.0.0.5").is_err());
+        assert!(parse_args("--buffer 0").is_err());
+        assert!(parse_args("--buffer lots").is_err());
+        assert!(parse_args("--listen localhost").is_err());
+        assert!(parse_args("--stdin --stdin").is_err());
+        assert!(parse_args("--tcp").is_err());
+        assert!(parse_args("--bogus").is_err());
+
+        assert!(Config::from_args(args("--config boat.toml --stdin")).is_err());
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let config = Config::parse_toml(
+            r#"
+            channel_capacity = 512
+
+            [[listener]]
+            type = "tcp"
+            bind = "0.0.0.0:10110"
+            access = "r"
+            clients = { "192.168.1.10" = "rw" }
+            queue = 64
+            filter = "-GPGSV"
+
+            [[listener]]
+            name = "ais-broadcast"
+            type = "udp"
+            target = "192.168.1.255:10110"
+            filter = "+AIVDM"
+
+            [[listener]]
+            type = "ws"
+            access = "rw"
+            tls_cert = "/etc/nmea/boat.crt"
+            tls_key = "/etc/nmea/boat.key"
+            users = [
+                { name = "plotter", password = "s3cret", access = "rw" },
+                { name = "display", password = "d1splay-token" },
+            ]
+
+            [[source]]
+            name = "gps"
+            type = "serial"
+            path = "/dev/ttyUSB0"
+            filter = "~*GSV=1"
+
+            [[source]]
+            name = "autopilot"
+            type = "serial"
+            path = "/dev/ttyUSB1"
+            baud = 38400
+            parity = "none"
+            output = "+*APB,+*RMB"
+
+            [[source]]
+            type = "tcp"
+            address = "192.168.1.20:10110"
+
+            [[listener]]
+            type = "record"
+            path = "/var/log/nmea/sail.log"
+            format = "tag"
+
+            [[listener]]
+            type = "status"
+            stale_after = 2.5
+
+            [[source]]
+            type = "derived"
+            derive = "true-wind, vmg:II, set-drift"
+            damping = 3
+
+            [[source]]
+            type = "anchor"
+            radius = 40
+
+            [[source]]
+            type = "replay"
+            path = "/var/log/nmea/old.log"
+            speed = 4
+            loop = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(512, config.channel_capacity);
+        let tcp = &config.listeners[0];
+        assert_eq!("tcp-listen:0.0.0.0:10110", tcp.name);
+        assert_eq!(64, tcp.queue_len);
+        assert_eq!(Access::ReadWrite, tcp.access_for("192.168.1.10".parse().unwrap()));
+        assert_eq!(Access::Read, tcp.access_for("192.168.1.11".parse().unwrap()));
+        assert_eq!("-GPGSV".parse::<Filter>().unwrap(), tcp.filter);
+
+        let udp = &config.listeners[1];
+        assert_eq!("ais-broadcast", udp.name);
+        assert_eq!(
+            ListenerKind::Udp { target: "192.168.1.255:10110".to_string(), bind: "0.0.0.0:0".to_string() },
+            udp.kind
+        );
+
+        let ws = &config.listeners[2];
+        assert_eq!(ListenerKind::WebSocket { bind: DEFAULT_WS_LISTEN.to_string() }, ws.kind);
+        assert_eq!(Access::ReadWrite, ws.access);
+        assert_eq!("/etc/nmea/boat.key", ws.tls.as_ref().unwrap().key);
+        assert_eq!(Access::Read, ws.users[1].access);
+
+        assert_eq!("gps", config.sources[0].name);
+        assert_eq!(
+            SourceKind::Serial {
+                path: "/dev/ttyUSB0".to_string(),
+                settings: SerialSettings::default(),
+                output: None,
+            },
+            config.sources[0].kind
+        );
+        match &config.sources[1].kind {
+            SourceKind::Serial { settings, output, .. } => {
+                assert_eq!("38400,8N1", settings.to_string());
+                assert_eq!(Some("+*APB,+*RMB".parse().unwrap()), *output);
+            }
+            kind => panic!("not a serial source: {:?}", kind),
+        }
+        assert_eq!("tcp:192.168.1.20:10110", config.sources[2].name);
+        assert_eq!("record:/var/log/nmea/sail.log", config.listeners[3].name);
+        assert_eq!(
+            ListenerKind::Status { bind: DEFAULT_STATUS_LISTEN.to_string(), stale_after: Duration::from_millis(2500) },
+            config.listeners[4].kind
+        );
+        assert_eq!(
+            SourceKind::Replay { path: "/var/log/nmea/old.log".to_string(), speed: 4.0, looping: true },
+            config.sources[5].kind
+        );
+        assert_eq!(
+            SourceKind::Anchor { radius: 40.0, track_len: anchor::DEFAULT_TRACK_LEN },
+            config.sources[4].kind
+        );
+        match &config.sources[3].kind {
+            SourceKind::Derived { outputs, damping } => {
+                assert_eq!(3, outputs.len());
+                assert_eq!(Duration::from_secs(3), *damping);
+            }
+            kind => panic!("not a derived source: {:?}", kind),
+        }
+
+        // An empty file is a server without sources or listeners
+        assert_eq!(0, Config::parse_toml("").unwrap().listeners.len());
+    }
+
+    #[test]
+    fn test_toml_errors() {
+        let error = |text: &str| Config::parse_toml(text).unwrap_err().to_string();
+
+        assert!(error("channel_capacity = 0").contains("channel_capacity: must be at least 1"));
+        assert!(error("[[listener]]\ntype = \"tcp\"\nport = 1").contains("unknown field `port`"));
+        assert_eq!(
+            "listener #1: unknown type \"http\", expected tcp, ws, signalk, udp, record or status",
+            error("[[listener]]\ntype = \"http\"")
+        );
+        assert_eq!("listener #1: udp listeners need a `target`", error("[[listener]]\ntype = \"udp\""));
+        assert_eq!(
+            "listener \"ais\": `queue` only applies to tcp and ws listeners",
+            error("[[listener]]\nname = \"ais\"\ntype = \"udp\"\ntarget = \"1.2.3.4:1\"\nqueue = 3")
+        );
+        assert!(error("[[listener]]\ntype = \"tcp\"\nfilter = \"GSV\"").starts_with("listener #1: filter rule"));
+        assert!(error("[[listener]]\ntype = \"tcp\"\nbind = \"localhost\"").contains("bad bind address"));
+        assert!(error("[[listener]]\ntype = \"tcp\"\nclients = { \"boat\" = \"rw\" }").contains("bad client address"));
+        assert_eq!(
+            "tcp-listen:0.0.0.0:8080: duplicate listener name",
+            error("[[listener]]\ntype = \"tcp\"\n[[listener]]\ntype = \"tcp\"")
+        );
+        assert_eq!(
+            "source #2: serial sources need `path`",
+            error("[[source]]\ntype = \"stdin\"\n[[source]]\ntype = \"serial\"")
+        );
+
+        assert!(error("[[source]]\ntype = \"serial\"\npath = \"/dev/ttyS0\"\ndata_bits = 9").contains("9 data bits"));
+        assert!(error("[[source]]\ntype = \"tcp\"\naddress = \"a:1\"\nbaud = 4800").contains("only apply to serial sources"));
+        assert_eq!("listener #1: record listeners need a `path`", error("[[listener]]\ntype = \"record\""));
+        assert_eq!(
+            "source #1: `speed` and `loop` only apply to replay sources",
+            error("[[source]]\ntype = \"stdin\"\nloop = true")
+        );
+        assert_eq!("source #1: derived sources need `derive`", error("[[source]]\ntype = \"derived\""));
+        assert_eq!(
+            "source #1: `derive` and `damping` only apply to derived sources",
+            error("[[source]]\ntype = \"stdin\"\ndamping = 1")
+        );
+        assert_eq!(
+            "second: only one anchor watch",
+            error("[[source]]\ntype = \"anchor\"\n[[source]]\nname = \"second\"\ntype = \"anchor\"")
+        );
+        assert_eq!(
+            "listener #1: `stale_after` only applies to status listeners",
+            error("[[listener]]\ntype = \"tcp\"\nstale_after = 5")
+        );
+        assert!(error("[[listener]]\ntype = \"status\"\nstale_after = 0").contains("bad timeout"));
+        assert_eq!(
+            "listener #1: `tls_cert` and `tls_key` go together",
+            error("[[listener]]\ntype = \"tcp\"\ntls_cert = \"boat.crt\"")
+        );
+        assert_eq!(
+            "listener #1: `users` only applies to tcp, ws and signalk listeners",
+            error("[[listener]]\ntype = \"udp\"\nusers = [{ name = \"a\", password = \"b\" }]")
+        );
+
+        let missing = Config::load("/nonexistent/boat.toml").unwrap_err().to_string();
+        assert!(missing.starts_with("/nonexistent/boat.toml: "));
+    }
+}
@@ -0,0 +1,457 @@
+use crate::bus::{Publisher, Sentence};
+use crate::nmea;
+use crate::signalk::{self, KNOTS};
+use chrono::{Datelike, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Once;
+use std::time::{Duration, Instant};
+use time::{Date, Month};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+// Integrated instrumentation, unless the configuration says otherwise
+pub const DEFAULT_TALKER: &str = "II";
+
+// Inputs older than this are not used, and a sentence another source sent
+// within it is not derived
+const STALE: Duration = Duration::from_secs(5);
+
+static MODEL_EXPIRED: Once = Once::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    TrueWind, // MWV (T) and MWD
+    Heading,  // HDT
+    Vmg,      // VPW
+    SetDrift, // VDR
+}
+
+/// A sentence to derive and the talker to send it with, written like
+/// `true-wind` or `hdt:HC`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Derivation {
+    pub output: Output,
+    pub talker: String,
+}
+
+impl FromStr for Derivation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Derivation, String> {
+        let (name, talker) = s.split_once(':').unwrap_or((s, DEFAULT_TALKER));
+        let output = match name {
+            "true-wind" => Output::TrueWind,
+            "hdt" => Output::Heading,
+            "vmg" => Output::Vmg,
+            "set-drift" => Output::SetDrift,
+            _ => return Err(format!("unknown output {:?}, expected true-wind, hdt, vmg or set-drift", name)),
+        };
+        if talker.len() != 2 || !talker.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) {
+            return Err(format!("bad talker {:?} for {}, expected two letters like II", talker, name));
+        }
+        Ok(Derivation { output, talker: talker.to_string() })
+    }
+}
+
+impl fmt::Display for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.output {
+            Output::TrueWind => "true-wind",
+            Output::Heading => "hdt",
+            Output::Vmg => "vmg",
+            Output::SetDrift => "set-drift",
+        };
+        write!(f, "{}:{}", name, self.talker)
+    }
+}
+
+/// Parses a comma separated list of derivations.
+pub fn parse_list(spec: &str) -> Result<Vec<Derivation>, String> {
+    let outputs = spec.split(',').map(|s| s.trim().parse()).collect::<Result<Vec<Derivation>, _>>()?;
+    for (i, derivation) in outputs.iter().enumerate() {
+        if outputs[..i].iter().any(|earlier| earlier.output == derivation.output) {
+            return Err(format!("{} is listed twice", derivation));
+        }
+    }
+    Ok(outputs)
+}
+
+type Vector = (f64, f64);
+
+fn polar(length: f64, angle: f64) -> Vector {
+    (length * angle.cos(), length * angle.sin())
+}
+
+fn length((x, y): Vector) -> f64 {
+    x.hypot(y)
+}
+
+fn angle((x, y): Vector) -> f64 {
+    y.atan2(x)
+}
+
+// From the boat's frame (forward, starboard) to the earth's (north, east)
+fn rotate((x, y): Vector, heading: f64) -> Vector {
+    let (cos, sin) = (heading.cos(), heading.sin());
+    (x * cos - y * sin, x * sin + y * cos)
+}
+
+// 0.0 to 359.9, without rounding up to 360.0
+fn bearing(angle: f64) -> String {
+    format!("{:.1}", ((angle.to_degrees() * 10.0).round() / 10.0).rem_euclid(360.0))
+}
+
+fn knots(speed: f64) -> String {
+    format!("{:.1}", speed / KNOTS)
+}
+
+/// Magnetic declination from the World Magnetic Model, in radians east.
+pub fn declination(latitude: f64, longitude: f64) -> Option<f64> {
+    // The wmm crate only has WMM2020, valid from 2020.0 to 2025.0 with an
+    // error of about 0.5° (more near the magnetic poles). Later dates use its
+    // last day, and the field's drift of up to a few tenths of a degree a year
+    // adds to that error every year since, which is why a variation from the
+    // bus is used first.
+    let today = Utc::now().date_naive();
+    let date = match today.year() {
+        year if year > 2024 => {
+            MODEL_EXPIRED.call_once(|| eprintln!("derived: WMM2020 ended in 2024, declination may be off by a degree or more"));
+            Date::from_calendar_date(2024, Month::December, 31)
+        }
+        year if year < 2020 => Date::from_calendar_date(2020, Month::January, 1),
+        year => Date::from_ordinal_date(year, today.ordinal() as u16),
+    };
+    let declination = wmm::declination(date.ok()?, latitude as f32, longitude as f32).ok()?;
+    Some((declination as f64).to_radians())
+}
+
+/// A reading smoothed by a first order filter with a time constant of
+/// `damping`. Angles go in as unit vectors, so 350° and 10° average to 0°.
+#[derive(Debug, Default)]
+struct Damped {
+    value: Vector,
+    at: Option<Instant>,
+}
+
+impl Damped {
+    fn update(&mut self, (x, y): Vector, now: Instant, damping: Duration) {
+        let weight = match self.at {
+            Some(at) if !damping.is_zero() && now.saturating_duration_since(at) < STALE => {
+                1.0 - (-now.saturating_duration_since(at).as_secs_f64() / damping.as_secs_f64()).exp()
+            }
+            // The first reading, or the first after a gap
+            _ => 1.0,
+        };
+        self.value.0 += weight * (x - self.value.0);
+        self.value.1 += weight * (y - self.value.1);
+        self.at = Some(now);
+    }
+
+    fn get(&self, now: Instant) -> Option<Vector> {
+        match self.at {
+            Some(at) if now.saturating_duration_since(at) < STALE => Some(self.value),
+            _ => None,
+        }
+    }
+}
+
+/// Derives the sentences the instruments do not send from the ones they do.
+/// Speeds are kept in m/s and angles in radians, like Signal K.
+pub struct Engine {
+    outputs: Vec<Derivation>,
+    damping: Duration,
+    apparent_wind: Damped, // boat frame, the direction the wind comes from
+    speed_through_water: Damped,
+    ground_velocity: Damped, // north, east
+    heading_true: Damped,
+    heading_magnetic: Damped,
+    variation: Option<(f64, Instant)>,
+    position: Option<(f64, f64)>,
+    // When another source last sent each sentence type we derive
+    provided: HashMap<&'static str, Instant>,
+}
+
+impl Engine {
+    pub fn new(outputs: &[Derivation], damping: Duration) -> Engine {
+        Engine {
+            outputs: outputs.to_vec(),
+            damping,
+            apparent_wind: Damped::default(),
+            speed_through_water: Damped::default(),
+            ground_velocity: Damped::default(),
+            heading_true: Damped::default(),
+            heading_magnetic: Damped::default(),
+            variation: None,
+            position: None,
+            provided: HashMap::new(),
+        }
+    }
+
+    /// Takes in a sentence from the bus and returns the sentences derived
+    /// because of it.
+    pub fn update(&mut self, line: &str, now: Instant) -> Vec<String> {
+        let fields = nmea::fields(line);
+        let provided = match (nmea::sentence_type(nmea::address(line)), fields.get(1)) {
+            ("MWV", Some(&"T")) => Some("MWV"),
+            ("HDT", _) => Some("HDT"),
+            ("MWD", _) => Some("MWD"),
+            ("VPW", _) => Some("VPW"),
+            ("VDR", _) => Some("VDR"),
+            _ => None,
+        };
+        if let Some(sentence_type) = provided {
+            self.provided.insert(sentence_type, now);
+        }
+
+        let values = signalk::values(line);
+        let get = |path: &str| values.iter().find(|(p, _)| p == path).and_then(|(_, value)| value.as_f64());
+        let damping = self.damping;
+        let (mut wind, mut heading, mut ground) = (false, false, false);
+        if let (Some(angle), Some(speed)) = (get("environment.wind.angleApparent"), get("environment.wind.speedApparent")) {
+            self.apparent_wind.update(polar(speed, angle), now, damping);
+            wind = true;
+        }
+        if let Some(speed) = get("navigation.speedThroughWater") {
+            self.speed_through_water.update((speed, 0.0), now, damping);
+        }
+        if let (Some(course), Some(speed)) = (get("navigation.courseOverGroundTrue"), get("navigation.speedOverGround")) {
+            self.ground_velocity.update(polar(speed, course), now, damping);
+            ground = true;
+        }
+        if let Some(angle) = get("navigation.headingTrue") {
+            self.heading_true.update(polar(1.0, angle), now, damping);
+        }
+        if let Some(angle) = get("navigation.headingMagnetic") {
+            self.heading_magnetic.update(polar(1.0, angle), now, damping);
+            heading = true;
+        }
+        if let Some(variation) = get("navigation.magneticVariation") {
+            self.variation = Some((variation, now));
+        }
+        if let Some((_, position)) = values.iter().find(|(path, _)| path == "navigation.position") {
+            if let (Some(latitude), Some(longitude)) = (position["latitude"].as_f64(), position["longitude"].as_f64()) {
+                self.position = Some((latitude, longitude));
+            }
+        }
+
+        // Each output goes out at the rate of the input it mostly depends on
+        let mut sentences = Vec::new();
+        for derivation in &self.outputs {
+            let talker = &derivation.talker;
+            match derivation.output {
+                Output::TrueWind if wind => sentences.extend(self.true_wind(talker, now)),
+                Output::Vmg if wind => sentences.extend(self.vmg(talker, now)),
+                Output::Heading if heading => sentences.extend(self.heading(talker, now)),
+                Output::SetDrift if ground => sentences.extend(self.set_drift(talker, now)),
+                _ => {}
+            }
+        }
+        sentences
+    }
+
+    fn missing(&self, sentence_type: &str, now: Instant) -> bool {
+        self.provided
+            .get(sentence_type)
+            .is_none_or(|at| now.saturating_duration_since(*at) >= STALE)
+    }
+
+    // The variation the GPS or compass reports, or else the model's
+    // declination at the last known position (it hardly changes over a
+    // passage)
+    fn declination(&self, now: Instant) -> Option<f64> {
+        match self.variation {
+            Some((variation, at)) if now.saturating_duration_since(at) < STALE => Some(variation),
+            _ => self.position.and_then(|(latitude, longitude)| declination(latitude, longitude)),
+        }
+    }
+
+    fn heading_true(&self, now: Instant) -> Option<f64> {
+        match self.heading_true.get(now) {
+            Some(heading) => Some(angle(heading)),
+            None => Some(angle(self.heading_magnetic.get(now)?) + self.declination(now)?),
+        }
+    }
+
+    // Through the water, or over the ground without a log
+    fn boat_speed(&self, now: Instant) -> Option<f64> {
+        match self.speed_through_water.get(now) {
+            Some((speed, _)) => Some(speed),
+            None => self.ground_velocity.get(now).map(length),
+        }
+    }
+
+    fn true_wind(&self, talker: &str, now: Instant) -> Vec<String> {
+        let mut sentences = Vec::new();
+        let apparent = match self.apparent_wind.get(now) {
+            Some(apparent) => apparent,
+            None => return sentences,
+        };
+        if let Some(speed) = self.boat_speed(now).filter(|_| self.missing("MWV", now)) {
+            // The boat's own speed shows up as wind from ahead
+            let wind = (apparent.0 - speed, apparent.1);
+            let address = format!("{}MWV", talker);
+            sentences.push(nmea::sentence(&address, &[&bearing(angle(wind)), "T", &knots(length(wind)), "N", "A"]));
+        }
+        if let (Some(heading), Some(ground)) = (self.heading_true(now), self.ground_velocity.get(now)) {
+            if self.missing("MWD", now) {
+                let earth = rotate(apparent, heading);
+                let wind = (earth.0 - ground.0, earth.1 - ground.1);
+                let magnetic = self.declination(now).map(|d| bearing(angle(wind) - d)).unwrap_or_default();
+                let speed = length(wind);
+                let address = format!("{}MWD", talker);
+                sentences.push(nmea::sentence(
+                    &address,
+                    &[&bearing(angle(wind)), "T", &magnetic, "M", &knots(speed), "N", &format!("{:.1}", speed), "M"],
+                ));
+            }
+        }
+        sentences
+    }
+
+    // Speed towards the wind, negative when running downwind
+    fn vmg(&self, talker: &str, now: Instant) -> Option<String> {
+        let (apparent, speed) = (self.apparent_wind.get(now)?, self.boat_speed(now)?);
+        if !self.missing("VPW", now) {
+            return None;
+        }
+        let vmg = speed * angle((apparent.0 - speed, apparent.1)).cos();
+        let address = format!("{}VPW", talker);
+        Some(nmea::sentence(&address, &[&knots(vmg), "N", &format!("{:.1}", vmg), "M"]))
+    }
+
+    fn heading(&self, talker: &str, now: Instant) -> Option<String> {
+        let magnetic = angle(self.heading_magnetic.get(now)?);
+        if !self.missing("HDT", now) {
+            return None;
+        }
+        let address = format!("{}HDT", talker);
+        Some(nmea::sentence(&address, &[&bearing(magnetic + self.declination(now)?), "T"]))
+    }
+
+    // The current is what moves the boat over the ground apart from its
+    // own speed through the water (leeway is not accounted for)
+    fn set_drift(&self, talker: &str, now: Instant) -> Option<String> {
+        let ground = self.ground_velocity.get(now)?;
+        let (speed, _) = self.speed_through_water.get(now)?;
+        let water = polar(speed, self.heading_true(now)?);
+        if !self.missing("VDR", now) {
+            return None;
+        }
+        let current = (ground.0 - water.0, ground.1 - water.1);
+        let set = angle(current);
+        let magnetic = self.declination(now).map(|d| bearing(set - d)).unwrap_or_default();
+        let address = format!("{}VDR", talker);
+        Some(nmea::sentence(&address, &[&bearing(set), "T", &magnetic, "M", &knots(length(current)), "N"]))
+    }
+}
+
+/// Derives sentences from everything on the bus, except its own, until the
+/// bus closes.
+pub async fn run(outputs: &[Derivation], damping: Duration, mut receiver: Receiver<Sentence>, publisher: &mut Publisher) {
+    let mut engine = Engine::new(outputs, damping);
+    loop {
+        let sentence = match receiver.recv().await {
+            Ok(sentence) => sentence,
+            Err(RecvError::Lagged(n)) => {
+                eprintln!("{}: lagging, skipped {} messages", publisher.source(), n);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if sentence.source == *publisher.source() {
+            continue;
+        }
+        for line in engine.update(&sentence.line, Instant::now()) {
+            publisher.publish(&line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all() -> Vec<Derivation> {
+        parse_list("true-wind,hdt:HC,vmg,set-drift").unwrap()
+    }
+
+    fn fields(line: &str) -> Vec<&str> {
+        assert!(nmea::validate(line).is_ok(), "{}", line);
+        nmea::fields(line)
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let outputs = all();
+        assert_eq!(Derivation { output: Output::Heading, talker: "HC".to_string() }, outputs[1]);
+        assert_eq!("vmg:II", outputs[2].to_string());
+        assert!(parse_list("true-wind,true-wind:WI").is_err());
+        assert!(parse_list("hdt:hc").is_err());
+        assert!(parse_list("depth").is_err());
+    }
+
+    #[test]
+    fn test_wind_and_current() {
+        let mut engine = Engine::new(&all(), Duration::ZERO);
+        let now = Instant::now();
+        // Heading north at 10 knots through the water and 11 over the ground,
+        // with the wind on the beam
+        assert!(engine.update("$HEHDT,0.0,T*00", now).is_empty());
+        assert!(engine.update("$VWVHW,0.0,T,,M,10.0,N,,K*00", now).is_empty());
+        let current = engine.update("$GPVTG,0.0,T,,M,11.0,N,,K*00", now);
+        assert_eq!(1, current.len());
+        assert_eq!(vec!["0.0", "T", "", "M", "1.0", "N"], fields(&current[0]));
+
+        let wind = engine.update("$WIMWV,90.0,R,10.0,N,A*00", now);
+        assert_eq!(3, wind.len());
+        assert!(wind[0].starts_with("$IIMWV,"));
+        assert_eq!(vec!["135.0", "T", "14.1", "N", "A"], fields(&wind[0]));
+        assert_eq!(vec!["137.7", "T", "", "M", "14.9", "N", "7.6", "M"], fields(&wind[1]));
+        assert_eq!(vec!["-7.1", "N", "-3.6", "M"], fields(&wind[2]));
+
+        // Nothing is derived that another instrument already sends
+        engine.update("$WIMWV,135.0,T,14.1,N,A*00", now);
+        let wind = engine.update("$WIMWV,90.0,R,10.0,N,A*00", now);
+        assert_eq!(2, wind.len());
+        assert!(wind[0].starts_with("$IIMWD,"));
+
+        // Inputs that stopped coming in are not used
+        assert!(engine.update("$WIMWV,90.0,R,10.0,N,A*00", now + STALE * 2).is_empty());
+    }
+
+    #[test]
+    fn test_heading_and_damping() {
+        let mut engine = Engine::new(&all(), Duration::from_secs(1));
+        let now = Instant::now();
+        // No position or variation, no true heading
+        assert!(engine.update("$HCHDM,350.0,M*00", now).is_empty());
+
+        engine.update("$GPGGA,120000,5000.000,N,00100.000,W,1,08,1.0,10.0,M,47.0,M,,*00", now);
+        let declination = declination(50.0, -1.0).unwrap();
+        let later = now + Duration::from_secs(1);
+        let hdt = engine.update("$HCHDM,10.0,M*00", later);
+        assert!(hdt[0].starts_with("$HCHDT,"));
+        // A second into a one second filter it has come 63% of the way
+        let expected = angle(engine.heading_magnetic.value) + declination;
+        assert!((2.0..3.0).contains(&angle(engine.heading_magnetic.value).to_degrees()));
+        assert_eq!(bearing(expected), fields(&hdt[0])[0]);
+
+        // Without damping the reading is taken as is
+        let mut damped = Damped::default();
+        damped.update(polar(1.0, 350f64.to_radians()), now, Duration::ZERO);
+        damped.update(polar(1.0, 10f64.to_radians()), later, Duration::ZERO);
+        assert!((angle(damped.get(later).unwrap()).to_degrees() - 10.0).abs() < 1e-9);
+        assert!(damped.get(later + STALE).is_none());
+
+        // A variation on the bus is preferred to the model
+        let mut engine = Engine::new(&all(), Duration::ZERO);
+        engine.update("$GPGGA,120000,5000.000,N,00100.000,W,1,08,1.0,10.0,M,47.0,M,,*00", now);
+        let hdt = engine.update("$HCHDG,10.0,,,5.0,W*00", now);
+        assert_eq!("5.0", fields(&hdt[0])[0]);
+        // until it goes stale
+        let hdt = engine.update("$HCHDM,10.0,M*00", now + STALE);
+        assert_eq!(bearing(10f64.to_radians() + declination), fields(&hdt[0])[0]);
+    }
+}
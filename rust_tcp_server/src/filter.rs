@@ -0,0 +1,339 @@
+use crate::bus::Sentence;
+use crate::nmea;
+use std::collections::HashMap;
+use std::str::FromStr;
+use tokio::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Pattern {
+    Address(String), // talker + sentence type, e.g. "AIVDM", "*GSV", "GP*"
+    Talker(String),
+    SentenceType(String),
+    Source(String),
+}
+
+impl Pattern {
+    fn matches(&self, sentence: &Sentence) -> bool {
+        let address = nmea::address(&sentence.line);
+        match self {
+            Pattern::Address(p) => glob(p, address),
+            Pattern::Talker(p) => glob(p, nmea::talker(address)),
+            Pattern::SentenceType(p) => glob(p, nmea::sentence_type(address)),
+            Pattern::Source(p) => glob(p, &sentence.source),
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pattern, String> {
+        let pattern = match s.split_once(':') {
+            Some(("talker", p)) => Pattern::Talker(p.to_string()),
+            Some(("type", p)) => Pattern::SentenceType(p.to_string()),
+            Some(("src", p)) => Pattern::Source(p.to_string()),
+            Some((kind, _)) => return Err(format!("unknown filter field {:?}", kind)),
+            None => Pattern::Address(s.to_string()),
+        };
+        match &pattern {
+            Pattern::Address(p) | Pattern::Talker(p) | Pattern::SentenceType(p) | Pattern::Source(p)
+                if p.is_empty() => Err(format!("empty pattern in {:?}", s)),
+            _ => Ok(pattern),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Rule {
+    include: bool,
+    pattern: Pattern,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct RateLimit {
+    pattern: Pattern,
+    interval: Duration,
+}
+
+/// Include/exclude rules plus rate limits, written as a comma separated list:
+///
+/// * `+PATTERN` / `-PATTERN` include or exclude matching sentences. The first
+///   matching rule wins; sentences matching no rule are passed unless the
+///   filter has include rules.
+/// * `~PATTERN=HZ` passes at most `HZ` sentences per second for each address
+///   (talker + type) matching the pattern. Multi-part GSV and AIS messages
+///   count once: the later parts follow the first one in or out.
+///
+/// A pattern is a sentence address (`AIVDM`, `*GSV`, `GP*`) or one of
+/// `talker:XX`, `type:XXX` and `src:NAME`; `*` and `?` work as in file globs.
+/// For example `-GPGSV`, `+AIVDM`, `+*APB,+*RMB,+*HDG` or `~*GSV=1`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    rules: Vec<Rule>,
+    rates: Vec<RateLimit>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.rates.is_empty()
+    }
+
+    fn passes_rules(&self, sentence: &Sentence) -> bool {
+        match self.rules.iter().find(|rule| rule.pattern.matches(sentence)) {
+            Some(rule) => rule.include,
+            None => !self.rules.iter().any(|rule| rule.include),
+        }
+    }
+
+    /// Creates the stateful side of the filter (rate limiter timestamps) for
+    /// one listener, source or client.
+    pub fn start(&self) -> ActiveFilter {
+        ActiveFilter {
+            filter: self.clone(),
+            last_sent: HashMap::new(),
+            groups: HashMap::new(),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let mut filter = Filter::default();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let mut chars = item.chars();
+            let op = chars.next();
+            let rest = chars.as_str();
+            match op {
+                Some(op @ ('+' | '-')) => filter.rules.push(Rule {
+                    include: op == '+',
+                    pattern: rest.parse()?,
+                }),
+                Some('~') => {
+                    let (pattern, hz) = rest
+                        .rsplit_once('=')
+                        .ok_or_else(|| format!("rate limit {:?} needs =HZ", item))?;
+                    let hz: f64 = hz.parse().map_err(|e| format!("rate limit {:?}: {}", item, e))?;
+                    if !(hz > 0.0 && hz.is_finite()) {
+                        return Err(format!("rate limit {:?} must be above 0 Hz", item));
+                    }
+                    filter.rates.push(RateLimit {
+                        pattern: pattern.parse()?,
+                        interval: Duration::from_secs_f64(1.0 / hz),
+                    });
+                }
+                _ => return Err(format!("filter rule {:?} must start with '+', '-' or '~'", item)),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+pub struct ActiveFilter {
+    filter: Filter,
+    last_sent: HashMap<String, Instant>,
+    // Whether the first part of the message group now arriving was passed
+    groups: HashMap<String, bool>,
+}
+
+// Part number and part count of a multi-part sentence, with the key its
+// group goes by: AIS messages carry a sequence ID to tell groups apart
+fn part(line: &str) -> Option<(u32, u32, String)> {
+    let address = nmea::address(line);
+    let fields = nmea::fields(line);
+    let key = match nmea::sentence_type(address) {
+        "GSV" => address.to_string(),
+        "VDM" | "VDO" => format!("{},{}", address, fields.get(2)?),
+        _ => return None,
+    };
+    let total = fields.first()?.parse().ok()?;
+    let number = fields.get(1)?.parse().ok()?;
+    (total > 1).then_some((number, total, key))
+}
+
+impl ActiveFilter {
+    pub fn accept(&mut self, sentence: &Sentence) -> bool {
+        self.accept_at(sentence, Instant::now())
+    }
+
+    fn accept_at(&mut self, sentence: &Sentence, now: Instant) -> bool {
+        if !self.filter.passes_rules(sentence) {
+            return false;
+        }
+        let limit = match self.filter.rates.iter().find(|rate| rate.pattern.matches(sentence)) {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let part = part(&sentence.line);
+        if let Some((number, total, key)) = &part {
+            if *number > 1 {
+                // Never seen the first part, so never passed it either
+                let passed = self.groups.get(key).copied().unwrap_or(false);
+                if number == total {
+                    self.groups.remove(key);
+                }
+                return passed;
+            }
+        }
+        let address = nmea::address(&sentence.line);
+        let passed = match self.last_sent.get(address) {
+            Some(last) if now.duration_since(*last) < limit.interval => false,
+            _ => {
+                self.last_sent.insert(address.to_string(), now);
+                true
+            }
+        };
+        if let Some((_, _, key)) = part {
+            self.groups.insert(key, passed);
+        }
+        passed
+    }
+}
+
+/// Matches `text` against a pattern with `*` and `?` wildcards.
+///
+/// Patterns come from clients, so this keeps only the last `*` to backtrack
+/// to and takes linear time per `*` rather than exponential.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let (p, t) = (pattern.as_bytes(), text.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    // The last `*` seen and where in the text it started matching
+    let mut star: Option<(usize, usize)> = None;
+    while j < t.len() {
+        match p.get(i) {
+            Some(b'*') => {
+                star = Some((i, j));
+                i += 1;
+            }
+            Some(&c) if c == b'?' || c == t[j] => {
+                i += 1;
+                j += 1;
+            }
+            _ => match star {
+                // Let the `*` take one more byte
+                Some((s, matched)) => {
+                    star = Some((s, matched + 1));
+                    i = s + 1;
+                    j = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    p[i..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn sentence(source: &str, line: &str) -> Sentence {
+        Sentence::new(&Arc::from(source), line)
+    }
+
+    fn accepts(spec: &str, line: &str) -> bool {
+        spec.parse::<Filter>().unwrap().start().accept(&sentence("gps", line))
+    }
+
+    #[test]
+    fn test_rules() {
+        assert!(accepts("", "$GPGSV,3,1*00"));
+        assert!(!accepts("-GPGSV", "$GPGSV,3,1*00"));
+        assert!(accepts("-GPGSV", "$GPRMC,1*00"));
+
+        // Only AIS
+        assert!(accepts("+AIVDM", "!AIVDM,1,1*00"));
+        assert!(!accepts("+AIVDM", "$GPRMC,1*00"));
+
+        // Autopilot sentences from any talker
+        let autopilot = "+*APB, +*RMB, +type:HDG";
+        assert!(accepts(autopilot, "$ECAPB,A*00"));
+        assert!(accepts(autopilot, "$HCHDG,1*00"));
+        assert!(!accepts(autopilot, "$GPGGA,1*00"));
+
+        assert!(accepts("+talker:GP", "$GPGGA,1*00"));
+        assert!(!accepts("+talker:GP", "$GLGSV,1*00"));
+        // Proprietary sentences have the talker "P"
+        assert!(accepts("-talker:P", "$GPGGA,1*00"));
+        assert!(!accepts("-talker:P", "$PGRMZ,1*00"));
+
+        // First match wins
+        assert!(accepts("+src:gps,-*", "$GPGGA,1*00"));
+        assert!(!accepts("-src:g*,+*", "$GPGGA,1*00"));
+
+        assert!("AIVDM".parse::<Filter>().is_err());
+        assert!("+foo:AIVDM".parse::<Filter>().is_err());
+        assert!("+src:".parse::<Filter>().is_err());
+        assert!("~*GSV".parse::<Filter>().is_err());
+        assert!("~*GSV=0".parse::<Filter>().is_err());
+        // A client's #FILTER line, lossily decoded
+        assert!("é".parse::<Filter>().is_err());
+        assert!("+AIVDM,é€".parse::<Filter>().is_err());
+        assert!(!accepts("+src:gps€", "$GPGGA,1*00"));
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("*", ""));
+        assert!(glob("GP*", "GPGSV"));
+        assert!(glob("*GSV", "GLGSV"));
+        assert!(glob("G?G*V", "GPGSV"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(!glob("a*b*c", "aXbYbZ"));
+        assert!(!glob("GP?", "GP"));
+        assert!(glob("**", "x"));
+
+        // Would take minutes with backtracking at every `*`
+        let text = "a".repeat(200);
+        assert!(!glob("*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b", &text));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut filter = "~*GSV=1".parse::<Filter>().unwrap().start();
+        let gpgsv = sentence("gps", "$GPGSV,3,1*00");
+        let glgsv = sentence("gps", "$GLGSV,3,1*00");
+        let rmc = sentence("gps", "$GPRMC,1*00");
+        let start = Instant::now();
+
+        assert!(filter.accept_at(&gpgsv, start));
+        assert!(filter.accept_at(&glgsv, start));
+        assert!(filter.accept_at(&rmc, start));
+        assert!(!filter.accept_at(&gpgsv, start + Duration::from_millis(300)));
+        assert!(filter.accept_at(&rmc, start + Duration::from_millis(300)));
+        assert!(filter.accept_at(&gpgsv, start + Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn test_rate_limit_groups() {
+        // Three GSV parts ten times a second: only whole groups, once a second
+        let mut filter = "~*GSV=1".parse::<Filter>().unwrap().start();
+        let start = Instant::now();
+        let mut passed = Vec::new();
+        for tick in 0..20 {
+            let now = start + Duration::from_millis(100 * tick);
+            for number in 1..=3 {
+                let gsv = sentence("gps", &format!("$GPGSV,3,{},11*00", number));
+                if filter.accept_at(&gsv, now) {
+                    passed.push((tick, number));
+                }
+            }
+        }
+        assert_eq!(vec![(0, 1), (0, 2), (0, 3), (10, 1), (10, 2), (10, 3)], passed);
+
+        // Interleaved AIS groups are told apart by their sequence ID
+        let mut filter = "~AIVDM=1".parse::<Filter>().unwrap().start();
+        let first = sentence("ais", "!AIVDM,2,1,3,A,55P5TL01VIaAL@7WKO@mBplU@<PDhh,0*00");
+        let other = sentence("ais", "!AIVDM,2,1,4,B,55P5TL01VIaAL@7WKO@mBplU@<PDhh,0*00");
+        let single = sentence("ais", "!AIVDM,1,1,,A,15M67FC000G?ufbE`FepT@3n00Sa,0*00");
+        let rest = |id: u32| sentence("ais", &format!("!AIVDM,2,2,{},A,88888888880,2*00", id));
+        assert!(filter.accept_at(&first, start));
+        assert!(!filter.accept_at(&other, start));
+        assert!(!filter.accept_at(&rest(4), start));
+        assert!(filter.accept_at(&rest(3), start));
+        assert!(!filter.accept_at(&rest(3), start));
+        assert!(!filter.accept_at(&single, start));
+    }
+}
@@ -0,0 +1,170 @@
+// Just enough HTTP/1.1 for the Signal K discovery document, the status
+// endpoint and the anchor watch, which are a single request each
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::time::{timeout, Duration};
+
+const MAX_REQUEST_LEN: usize = 8192;
+// For the whole request line and headers, so a silent client cannot hold
+// the connection open
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads the request line and headers; bodies are not needed here.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Request> {
+    Ok(read_head_within(stream, REQUEST_TIMEOUT).await?.0)
+}
+
+/// Like `read_request`, but also gives back every byte taken from the
+/// stream, so a `Replay` can hand the request on to a WebSocket handshake.
+pub async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(Request, Vec<u8>)> {
+    read_head_within(stream, REQUEST_TIMEOUT).await
+}
+
+async fn read_head_within<S: AsyncRead + Unpin>(stream: &mut S, wait: Duration) -> io::Result<(Request, Vec<u8>)> {
+    // Never more than MAX_REQUEST_LEN bytes, even on a line without an end
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_LEN as u64));
+    let mut raw = Vec::new();
+    let read_lines = async {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 || !line.ends_with('\n') {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete HTTP request"));
+            }
+            raw.extend_from_slice(line.as_bytes());
+            let line = line.trim_end().to_string();
+            if line.is_empty() {
+                return Ok(lines);
+            }
+            lines.push(line);
+        }
+    };
+    let lines = match timeout(wait, read_lines).await {
+        Ok(lines) => lines?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "HTTP request timed out")),
+    };
+    // Whatever the client sent right after the headers
+    raw.extend_from_slice(reader.buffer());
+
+    let mut request_line = lines.first().map(|line| line.split_whitespace()).into_iter().flatten();
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad HTTP request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers = lines[1..]
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let request = Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+    };
+    Ok((request, raw))
+}
+
+/// A stream that first gives back bytes already read from it.
+pub struct Replay<S> {
+    read: Vec<u8>,
+    pos: usize,
+    stream: S,
+}
+
+impl<S> Replay<S> {
+    pub fn new(read: Vec<u8>, stream: S) -> Replay<S> {
+        Replay { read, pos: 0, stream }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replay<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.pos < self.read.len() {
+            let n = buf.remaining().min(self.read.len() - self.pos);
+            buf.put_slice(&self.read[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replay<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Sends a JSON body and closes the exchange.
+pub async fn respond<S: AsyncWrite + Unpin>(stream: &mut S, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let request = read_request(&mut &b"GET /signalk?x=1 HTTP/1.1\r\nHost: pi.local\r\n\r\n"[..]).await.unwrap();
+        assert_eq!(("GET", "/signalk", "x=1"), (&*request.method, &*request.path, &*request.query));
+        assert_eq!(Some("pi.local"), request.header("host"));
+
+        // One endless header line is cut off at the limit
+        let long = format!("GET / HTTP/1.1\r\nCookie: {}", "a".repeat(1 << 20));
+        let error = read_request(&mut long.as_bytes()).await.err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+
+        // A client that stops halfway through the headers is given up on
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(b"GET / HTTP/1.1\r\nHost: pi").await.unwrap();
+        let error = read_head_within(&mut server, Duration::from_millis(100)).await.err().unwrap();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let mut stream = &b"GET / HTTP/1.1\r\nHost: pi\r\n\r\nhello"[..];
+        let (request, read) = read_head(&mut stream).await.unwrap();
+        assert_eq!("/", request.path);
+        let mut replayed = String::new();
+        Replay::new(read, stream).read_to_string(&mut replayed).await.unwrap();
+        assert_eq!("GET / HTTP/1.1\r\nHost: pi\r\n\r\nhello", replayed);
+    }
+}
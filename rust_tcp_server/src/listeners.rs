@@ -0,0 +1,247 @@
+use crate::bus::{Sentence, Subscription};
+use crate::client::{handle_client, ClientOptions, LOGIN_TIMEOUT};
+use crate::config::{ListenerConfig, ListenerKind};
+use crate::shutdown::ShutdownHandle;
+use crate::signalk::handle_signalk;
+use crate::stats::handle_status;
+use crate::websocket::handle_websocket;
+use crate::{recording, systemd, tls};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+/// Binds the listener's socket, then serves it from a new task. Binding
+/// first lets the caller report a busy port instead of a dead task.
+pub async fn start(listener: &ListenerConfig, tx: &Sender<Sentence>, shutdown: &ShutdownHandle) -> io::Result<JoinHandle<()>> {
+    let listener = listener.clone();
+    match &listener.kind {
+        ListenerKind::Tcp { bind } | ListenerKind::WebSocket { bind } | ListenerKind::SignalK { bind } => {
+            let acceptor = listener.tls.as_ref().map(tls::acceptor).transpose()?;
+            let socket = bind_tcp(bind).await?;
+            let tx = tx.clone();
+            Ok(tokio::spawn(accept_clients(listener, socket, acceptor, tx, shutdown.clone())))
+        }
+        ListenerKind::Status { bind, stale_after } => {
+            let socket = bind_tcp(bind).await?;
+            let stale_after = *stale_after;
+            Ok(tokio::spawn(async move {
+                while let Ok((stream, addr)) = socket.accept().await {
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_status(stream, stale_after).await {
+                            eprintln!("Error handling status request from {}: {}", addr, e);
+                        }
+                    });
+                }
+            }))
+        }
+        ListenerKind::Record { path, format } => {
+            let file = recording::open_log(path).await?;
+            let (format, receiver) = (*format, Subscription::new(tx.subscribe(), shutdown.clone()));
+            Ok(tokio::spawn(async move {
+                if let Err(e) = recording::record(&listener, format, file, receiver).await {
+                    eprintln!("{}: {}", listener.name, e);
+                }
+            }))
+        }
+        ListenerKind::Udp { target, bind } => {
+            let (socket, target) = udp_socket(target, bind).await?;
+            let receiver = Subscription::new(tx.subscribe(), shutdown.clone());
+            Ok(tokio::spawn(async move {
+                if let Err(e) = write_udp(&listener, socket, target, receiver).await {
+                    eprintln!("{}: {}", listener.name, e);
+                }
+            }))
+        }
+    }
+}
+
+// The socket systemd passed in for this address, or a new one
+async fn bind_tcp(bind: &str) -> io::Result<TcpListener> {
+    match systemd::inherited(bind)? {
+        Some(socket) => {
+            socket.set_nonblocking(true)?;
+            TcpListener::from_std(socket)
+        }
+        None => TcpListener::bind(bind).await,
+    }
+}
+
+async fn accept_clients(
+    listener: ListenerConfig,
+    socket: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    tx: Sender<Sentence>,
+    mut shutdown: ShutdownHandle,
+) {
+    // Client tasks are not tied to this one, so they outlive a reload
+    loop {
+        let (stream, addr) = tokio::select! {
+            result = socket.accept() => match result {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            // No new clients once shutting down
+            _ = shutdown.stopping() => break,
+        };
+        let options = ClientOptions {
+            queue_len: listener.queue_len,
+            access: listener.access_for(addr.ip()),
+            filter: listener.filter.clone(),
+            users: listener.users.clone(),
+            tls: acceptor.is_some(),
+            shutdown: shutdown.clone(),
+        };
+        let local = match stream.local_addr() {
+            Ok(local) => local,
+            Err(_) => continue,
+        };
+        let tx_clone = tx.clone();
+        let receiver = tx.subscribe();
+        let kind = listener.kind.clone();
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let result = match acceptor {
+                Some(acceptor) => match timeout(LOGIN_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => serve(&kind, stream, (addr, local), tx_clone, receiver, options).await,
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")),
+                },
+                None => serve(&kind, stream, (addr, local), tx_clone, receiver, options).await,
+            };
+            if let Err(e) = result {
+                eprintln!("Error handling client {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+// Plain TCP or TLS, the clients are the same after the handshake. The
+// addresses are the client's and ours.
+async fn serve<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    kind: &ListenerKind,
+    stream: S,
+    (addr, local): (SocketAddr, SocketAddr),
+    tx: Sender<Sentence>,
+    receiver: Receiver<Sentence>,
+    options: ClientOptions,
+) -> io::Result<()> {
+    match kind {
+        ListenerKind::WebSocket { .. } => handle_websocket(stream, addr, tx, receiver, options).await,
+        ListenerKind::SignalK { .. } => handle_signalk(stream, addr, local, receiver, options).await,
+        _ => handle_client(stream, addr, tx, receiver, options).await,
+    }
+}
+
+async fn udp_socket(target: &str, bind: &str) -> io::Result<(UdpSocket, SocketAddr)> {
+    let target = tokio::net::lookup_host(target)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", target)))?;
+    let socket = UdpSocket::bind(bind).await?;
+    // Harmless for unicast targets, required for broadcast ones
+    socket.set_broadcast(true)?;
+    if target.ip().is_multicast() {
+        match target.ip() {
+            IpAddr::V4(_) => {
+                socket.set_multicast_ttl_v4(1)?; // stay on the boat's network
+                socket.set_multicast_loop_v4(true)?;
+            }
+            IpAddr::V6(_) => socket.set_multicast_loop_v6(true)?,
+        }
+    }
+    Ok((socket, target))
+}
+
+async fn write_udp(
+    output: &ListenerConfig,
+    socket: UdpSocket,
+    target: SocketAddr,
+    mut receiver: Subscription,
+) -> io::Result<()> {
+    let mut filter = output.filter.start();
+    loop {
+        let sentence = match receiver.recv().await {
+            Ok(sentence) => sentence,
+            Err(RecvError::Lagged(n)) => {
+                eprintln!("{}: lagging, skipped {} messages", output.name, n);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        if !filter.accept(&sentence) {
+            continue;
+        }
+        // One datagram per sentence, like most marine apps expect
+        if let Err(e) = socket.send_to(sentence.to_wire().as_bytes(), target).await {
+            // e.g. the network is not up yet; keep going with the next sentence
+            eprintln!("{}: {}", output.name, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{SourceConfig, SourceKind};
+    use crate::sources;
+    use std::sync::Arc;
+    use tokio::sync::broadcast::channel;
+    use tokio::time::{sleep, timeout, Duration};
+
+    async fn roundtrip(receiver: &UdpSocket, target: &str, filter: &str) -> String {
+        let (tx, _rx) = channel::<Sentence>(16);
+        let mut output = ListenerConfig::new(ListenerKind::Udp {
+            target: target.to_string(),
+            bind: "0.0.0.0:0".to_string(),
+        });
+        output.filter = filter.parse().unwrap();
+        let task = start(&output, &tx, &ShutdownHandle::default()).await.unwrap();
+
+        let source: Arc<str> = Arc::from("gps");
+        tx.send(Sentence::new(&source, "$GPGSV,3,1,11*7B")).unwrap();
+        tx.send(Sentence::new(&source, "$GPHDT,123.4,T*31")).unwrap();
+
+        let mut buf = [0u8; 256];
+        let n = timeout(Duration::from_secs(2), receiver.recv(&mut buf)).await.unwrap().unwrap();
+        task.abort();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_udp_unicast() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = receiver.local_addr().unwrap().to_string();
+        assert_eq!("$GPHDT,123.4,T*31\r\n", roundtrip(&receiver, &target, "-*GSV").await);
+    }
+
+    #[tokio::test]
+    async fn test_udp_broadcast() {
+        let receiver = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let target = format!("127.255.255.255:{}", receiver.local_addr().unwrap().port());
+        assert_eq!("$GPGSV,3,1,11*7B\r\n", roundtrip(&receiver, &target, "").await);
+    }
+
+    #[tokio::test]
+    async fn test_udp_multicast() {
+        // Goes through the multicast UDP source, which joins the group
+        let port = std::net::UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        let group = format!("239.255.10.10:{}", port);
+        let (tx, mut bus) = channel::<Sentence>(16);
+        sources::spawn(SourceConfig::new(SourceKind::Udp { bind: group.clone() }), tx);
+        sleep(Duration::from_millis(100)).await;
+
+        let (out_tx, _out_rx) = channel::<Sentence>(16);
+        let output = ListenerConfig::new(ListenerKind::Udp { target: group, bind: "0.0.0.0:0".to_string() });
+        start(&output, &out_tx, &ShutdownHandle::default()).await.unwrap();
+        out_tx.send(Sentence::new(&Arc::from("gps"), "$GPHDT,123.4,T*31")).unwrap();
+
+        let sentence = timeout(Duration::from_secs(2), bus.recv()).await.unwrap().unwrap();
+        assert_eq!("$GPHDT,123.4,T*31", sentence.line);
+    }
+}
@@ -1,66 +1,85 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
-use tokio::sync::broadcast::{channel, Sender};
-use tokio::time::{Duration, interval};
+mod anchor;
+mod bus;
+mod client;
+mod config;
+mod derived;
+mod filter;
+mod http;
+mod listeners;
+mod nmea;
+mod recording;
+mod serial;
+mod server;
+mod shutdown;
+mod signalk;
+mod sources;
+mod stats;
+mod systemd;
+mod tls;
+mod websocket;
+
+use config::{Config, USAGE};
+use server::Server;
+use std::env;
 use std::error::Error;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Duration;
+
+// How long clients get to receive what is still on the bus when stopping
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let (tx, _rx) = channel::<String>(10); // Broadcast channel
-    let listener = TcpListener::bind("0.0.0.0:8080").await?;
-
-    // Spawn a task to send periodic broadcast messages
-    let tx_clone = tx.clone();
-    tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(3)); // Broadcast every 3 seconds
-        loop {
-            interval.tick().await;
-            let message = "Periodic broadcast message!\r\n".to_string();
-            if tx_clone.send(message).is_err() {
-                break; // Stop if there are no more receivers
-            }
-        }
-    });
+    if env::args().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let (config, path) = Config::from_args(env::args().skip(1))?;
 
-    println!("Listening on port 8080");
+    // Listeners use the sockets systemd passed in (socket activation) when the address matches
+    systemd::take_sockets()?;
+    let mut server = Server::new(config.channel_capacity);
+    server.apply(&config).await?;
+    systemd::ready("running");
+    let _watchdog = systemd::spawn_watchdog();
 
-    // Accept incoming connections
-    while let Ok((stream, _addr)) = listener.accept().await {
-        let tx_clone = tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(stream, tx_clone).await {
-                eprintln!("Error handling client: {}", e);
-            }
-        });
+    // SIGHUP reloads the configuration file; a bad file keeps the running setup
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => reload(&mut server, path.as_deref()).await,
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
     }
 
+    println!("Shutting down");
+    systemd::stopping();
+    if !server.shutdown(SHUTDOWN_GRACE).await {
+        eprintln!("Gave up waiting for clients after {} seconds", SHUTDOWN_GRACE.as_secs());
+    }
     Ok(())
 }
 
-async fn handle_client(mut stream: tokio::net::TcpStream, tx: Sender<String>) -> Result<(), Box<dyn Error>> {
-    let mut buf = [0u8; 1024];
-    let mut receiver = tx.subscribe();
-
-    loop {
-        tokio::select! {
-            // Read from the client
-            result = stream.read(&mut buf) => {
-                let n = result?;
-                if n == 0 {
-                    break; // Client disconnected
-                }
-                // Handle client message (if needed)
-                let message = String::from_utf8_lossy(&buf[..n]);
-                println!("Received from client: {}", message);
-            }
-
-            // Receive broadcast messages
-            result = receiver.recv() => {
-                let message = result?;
-                stream.write_all(message.as_bytes()).await?;
+async fn reload(server: &mut Server, path: Option<&str>) {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            eprintln!("SIGHUP: no --config file to reload");
+            return;
+        }
+    };
+    println!("Reloading {}", path);
+    systemd::reloading();
+    match Config::load(path) {
+        Ok(config) => {
+            if let Err(e) = server.apply(&config).await {
+                eprintln!("Reload: {}", e);
             }
         }
+        Err(e) => eprintln!("Reload failed, keeping the current configuration: {}", e),
     }
-
-    Ok(())
+    systemd::ready("running");
 }
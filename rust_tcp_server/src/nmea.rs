@@ -0,0 +1,187 @@
+use std::fmt;
+
+// NMEA 0183 limits a sentence to 82 characters, but plenty of devices send
+// longer proprietary sentences, so only discard lines that are clearly garbage.
+pub const MAX_LINE_LEN: usize = 256;
+
+#[derive(Debug, PartialEq)]
+pub enum SentenceError {
+    Empty,
+    BadStart(char),
+    NotAscii,
+    TooLong(usize),
+    MissingChecksum,
+    BadChecksum { expected: u8, found: String },
+}
+
+impl fmt::Display for SentenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SentenceError::Empty => write!(f, "empty sentence"),
+            SentenceError::BadStart(c) => write!(f, "sentence must start with '$' or '!', got {:?}", c),
+            SentenceError::NotAscii => write!(f, "sentence contains non-ASCII characters"),
+            SentenceError::TooLong(len) => write!(f, "sentence too long ({} bytes)", len),
+            SentenceError::MissingChecksum => write!(f, "missing checksum"),
+            SentenceError::BadChecksum { expected, found } => {
+                write!(f, "bad checksum: expected {:02X}, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SentenceError {}
+
+pub fn calculate_checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, b| sum ^ b)
+}
+
+/// Checks framing and checksum of a single line and returns it without
+/// surrounding whitespace.
+pub fn validate(line: &str) -> Result<&str, SentenceError> {
+    let line = line.trim();
+    let start = match line.chars().next() {
+        Some(c) => c,
+        None => return Err(SentenceError::Empty),
+    };
+    if start != '$' && start != '!' {
+        return Err(SentenceError::BadStart(start));
+    }
+    if !line.is_ascii() {
+        return Err(SentenceError::NotAscii);
+    }
+    if line.len() > MAX_LINE_LEN {
+        return Err(SentenceError::TooLong(line.len()));
+    }
+
+    let (data, checksum) = match line[1..].rsplit_once('*') {
+        Some(parts) => parts,
+        None => return Err(SentenceError::MissingChecksum),
+    };
+    let expected = calculate_checksum(data);
+    match u8::from_str_radix(checksum, 16) {
+        Ok(found) if checksum.len() == 2 && found == expected => Ok(line),
+        _ => Err(SentenceError::BadChecksum {
+            expected,
+            found: checksum.to_string(),
+        }),
+    }
+}
+
+/// The address field of a sentence (talker + sentence type, e.g. `GPGGA`).
+pub fn address(line: &str) -> &str {
+    let data = line.get(1..).unwrap_or_default();
+    let end = data.find([',', '*']).unwrap_or(data.len());
+    &data[..end]
+}
+
+pub fn talker(address: &str) -> &str {
+    // Proprietary sentences ($PGRMZ) only carry the "P"
+    if address.starts_with('P') {
+        &address[..1]
+    } else {
+        address.get(..2).unwrap_or(address)
+    }
+}
+
+pub fn sentence_type(address: &str) -> &str {
+    &address[talker(address).len()..]
+}
+
+/// The data fields after the address, without the checksum; empty fields
+/// are kept so positions stay meaningful.
+pub fn fields(line: &str) -> Vec<&str> {
+    let data = line.get(1..).unwrap_or_default();
+    let data = data.rsplit_once('*').map_or(data, |(data, _)| data);
+    data.split(',').skip(1).collect()
+}
+
+/// Builds a sentence from its address and fields, adding the checksum.
+pub fn sentence(address: &str, fields: &[&str]) -> String {
+    let data = format!("{},{}", address, fields.join(","));
+    format!("${}*{:02X}", data, calculate_checksum(&data))
+}
+
+/// Splits a byte stream into lines. Both CR and LF terminate a line, so
+/// CRLF, bare LF and bare CR senders all work; empty lines are skipped.
+#[derive(Default)]
+pub struct Framer {
+    buf: Vec<u8>,
+    overflow: bool,
+}
+
+impl Framer {
+    pub fn new() -> Framer {
+        Framer::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &b in data {
+            if b == b'\r' || b == b'\n' {
+                if !self.overflow && !self.buf.is_empty() {
+                    lines.push(String::from_utf8_lossy(&self.buf).into_owned());
+                }
+                self.buf.clear();
+                self.overflow = false;
+            } else if self.buf.len() < MAX_LINE_LEN {
+                self.buf.push(b);
+            } else {
+                // Drop everything up to the next line terminator
+                self.overflow = true;
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let gga = "$GPGGA,002153.000,3342.6618,N,11751.3858,W,1,10,1.2,27.0,M,-34.2,M,,0000*5E";
+        assert_eq!(Ok(gga), validate(gga));
+        assert_eq!(Ok(gga), validate(&format!("{}\r\n", gga)));
+        let vdm = "!AIVDM,1,1,,A,13HOI:0P0000VOHLCnHQKwvL05Ip,0*23";
+        assert_eq!(Ok(vdm), validate(vdm));
+
+        assert_eq!(Err(SentenceError::Empty), validate("\r\n"));
+        assert_eq!(Err(SentenceError::BadStart('P')), validate("Periodic broadcast message!"));
+        assert_eq!(Err(SentenceError::MissingChecksum), validate("$GPGGA,002153.000"));
+        assert_eq!(
+            Err(SentenceError::BadChecksum { expected: 0x5E, found: "5A".to_string() }),
+            validate("$GPGGA,002153.000,3342.6618,N,11751.3858,W,1,10,1.2,27.0,M,-34.2,M,,0000*5A")
+        );
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!("GPGGA", address("$GPGGA,002153.000,3342.6618,N*5E"));
+        assert_eq!("AIVDM", address("!AIVDM,1,1,,A,13HOI:0P0000VOHLCnHQKwvL05Ip,0*23"));
+        assert_eq!("GPHDT", address("$GPHDT*00"));
+        assert_eq!("", address(""));
+
+        assert_eq!(("GP", "GGA"), (talker("GPGGA"), sentence_type("GPGGA")));
+        assert_eq!(("P", "GRMZ"), (talker("PGRMZ"), sentence_type("PGRMZ")));
+        assert_eq!(("X", ""), (talker("X"), sentence_type("X")));
+
+        assert_eq!(vec!["123.4", "T"], fields("$GPHDT,123.4,T*31"));
+        assert_eq!(vec!["1", "", "0"], fields("$GPXXX,1,,0*00"));
+        assert!(fields("$GPHDT*00").is_empty());
+
+        assert_eq!("$GPHDT,123.4,T*31", sentence("GPHDT", &["123.4", "T"]));
+    }
+
+    #[test]
+    fn test_framer() {
+        let mut framer = Framer::new();
+        assert!(framer.push(b"$GPGLL,1").is_empty());
+        assert_eq!(vec!["$GPGLL,1,2", "$GPGSV"], framer.push(b",2\r\n$GPGSV\n\n"));
+        assert_eq!(vec!["!AIVDM"], framer.push(b"!AIVDM\r"));
+
+        let mut garbage = vec![b'x'; MAX_LINE_LEN * 2];
+        garbage.extend_from_slice(b"\r\n$GPHDT\r\n");
+        assert_eq!(vec!["$GPHDT"], framer.push(&garbage));
+    }
+}
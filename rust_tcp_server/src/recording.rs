@@ -0,0 +1,242 @@
+use crate::bus::{Publisher, Subscription};
+use crate::config::ListenerConfig;
+use crate::nmea;
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use std::io;
+use std::str::FromStr;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// How recorded sentences are timestamped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    // 2024-06-01T12:00:00.123Z $GPHDT,123.4,T*31
+    Timestamp,
+    // \c:1717243200,m:123*15\$GPHDT,123.4,T*31
+    TagBlock,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "timestamp" => Ok(LogFormat::Timestamp),
+            "tag" => Ok(LogFormat::TagBlock),
+            _ => Err(format!("unknown log format {:?}, expected timestamp or tag", s)),
+        }
+    }
+}
+
+pub fn format_line(format: LogFormat, time: DateTime<Utc>, line: &str) -> String {
+    match format {
+        LogFormat::Timestamp => format!("{} {}\n", time.to_rfc3339_opts(SecondsFormat::Millis, true), line),
+        LogFormat::TagBlock => {
+            // c: is in seconds; the milliseconds go in a field of our own,
+            // which other TAG block readers skip
+            let tag = format!("c:{},m:{:03}", time.timestamp(), time.timestamp_subsec_millis());
+            format!("\\{}*{:02X}\\{}\n", tag, nmea::calculate_checksum(&tag), line)
+        }
+    }
+}
+
+/// Splits a log line into its receive time (if it has one) and the sentence.
+/// Plain NMEA lines are accepted too, they just carry no timing.
+pub fn parse_line(text: &str) -> (Option<DateTime<Utc>>, &str) {
+    let text = text.trim();
+    if let Some(rest) = text.strip_prefix('\\') {
+        return match rest.split_once('\\') {
+            Some((tag, line)) => (tag_time(tag), line),
+            None => (None, text),
+        };
+    }
+    match text.split_once(' ') {
+        Some((time, line)) => match DateTime::parse_from_rfc3339(time) {
+            Ok(time) => (Some(time.with_timezone(&Utc)), line),
+            Err(_) => (None, text),
+        },
+        None => (None, text),
+    }
+}
+
+// The c: field of a TAG block in seconds (NMEA 4.10) plus our m: field's
+// milliseconds, or in milliseconds as earlier recordings have it
+fn tag_time(tag: &str) -> Option<DateTime<Utc>> {
+    let fields = tag.rsplit_once('*').map_or(tag, |(fields, _)| fields);
+    let field = |name: &str| fields.split(',').find_map(|field| field.strip_prefix(name));
+    let value: i64 = field("c:")?.parse().ok()?;
+    if value > 100_000_000_000 {
+        return Utc.timestamp_millis_opt(value).single();
+    }
+    let millis: u32 = field("m:").and_then(|millis| millis.parse().ok()).filter(|millis| *millis < 1000).unwrap_or(0);
+    Utc.timestamp_opt(value, millis * 1_000_000).single()
+}
+
+pub async fn open_log(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path).await
+}
+
+/// Appends every sentence on the bus that passes the listener's filter.
+pub async fn record(listener: &ListenerConfig, format: LogFormat, mut file: File, mut receiver: Subscription) -> io::Result<()> {
+    let mut filter = listener.filter.start();
+    loop {
+        let sentence = match receiver.recv().await {
+            Ok(sentence) => sentence,
+            Err(RecvError::Lagged(n)) => {
+                eprintln!("{}: lagging, skipped {} messages", listener.name, n);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        if filter.accept(&sentence) {
+            file.write_all(format_line(format, Utc::now(), &sentence.line).as_bytes()).await?;
+            file.flush().await?;
+        }
+    }
+}
+
+/// Feeds a log back onto the bus, keeping the recorded gaps between sentences
+/// divided by `speed` (0 sends as fast as possible).
+pub async fn replay(path: &str, speed: f64, looping: bool, publisher: &mut Publisher) -> io::Result<()> {
+    loop {
+        let mut lines = BufReader::new(File::open(path).await?).lines();
+        let mut start: Option<(DateTime<Utc>, Instant)> = None;
+        let mut count = 0;
+        while let Some(text) = lines.next_line().await? {
+            publisher.received(text.len() + 1);
+            let (time, line) = parse_line(&text);
+            if line.is_empty() {
+                continue;
+            }
+            if let (Some(time), true) = (time, speed > 0.0) {
+                match start {
+                    // Going back in time (e.g. concatenated logs) starts over
+                    Some((first, began)) if time >= first => {
+                        let offset = (time - first).to_std().unwrap_or_default();
+                        sleep_until(began + Duration::from_secs_f64(offset.as_secs_f64() / speed)).await;
+                    }
+                    _ => start = Some((time, Instant::now())),
+                }
+            }
+            publisher.publish(line);
+            count += 1;
+            if speed == 0.0 {
+                // Give subscribers a chance to keep up
+                tokio::task::yield_now().await;
+            }
+        }
+        // An empty log would loop without ever waiting
+        if !looping || count == 0 {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Sentence;
+    use crate::config::{ListenerKind, SourceConfig, SourceKind};
+    use crate::shutdown::ShutdownHandle;
+    use crate::{listeners, sources};
+    use std::sync::Arc;
+    use tokio::sync::broadcast::channel;
+    use tokio::time::timeout;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rust_tcp_server-{}-{}", std::process::id(), name));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_log_formats() {
+        let time = Utc.timestamp_millis_opt(1_717_243_200_123).unwrap();
+        let hdt = "$GPHDT,123.4,T*31";
+
+        let line = format_line(LogFormat::Timestamp, time, hdt);
+        assert_eq!("2024-06-01T12:00:00.123Z $GPHDT,123.4,T*31\n", line);
+        assert_eq!((Some(time), hdt), parse_line(&line));
+
+        let line = format_line(LogFormat::TagBlock, time, hdt);
+        assert_eq!("\\c:1717243200,m:123*15\\$GPHDT,123.4,T*31\n", line);
+        assert_eq!((Some(time), hdt), parse_line(&line));
+        // as earlier versions recorded it
+        assert_eq!((Some(time), hdt), parse_line("\\c:1717243200123*6E\\$GPHDT,123.4,T*31"));
+
+        // TAG blocks from other devices, with seconds and more fields
+        let (seconds, line) = parse_line("\\s:r003669945,c:1241544035*4A\\!AIVDM,1,1,,B,15N4cJ`005Jrek0H@9n`DW5608EP,0*13");
+        assert_eq!(Utc.timestamp_opt(1_241_544_035, 0).single(), seconds);
+        assert!(line.starts_with("!AIVDM"));
+
+        assert_eq!((None, hdt), parse_line(hdt));
+        assert!("csv".parse::<LogFormat>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let path = temp_path("record.log");
+        let _ = std::fs::remove_file(&path);
+        let mut listener = ListenerConfig::new(ListenerKind::Record {
+            path: path.clone(),
+            format: LogFormat::TagBlock,
+        });
+        listener.filter = "-*GSV".parse().unwrap();
+        let (tx, _rx) = channel::<Sentence>(16);
+        let task = listeners::start(&listener, &tx, &ShutdownHandle::default()).await.unwrap();
+
+        let source: Arc<str> = Arc::from("gps");
+        tx.send(Sentence::new(&source, "$GPGSV,3,1,11*7B")).unwrap();
+        tx.send(Sentence::new(&source, "$GPHDT,123.4,T*31")).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        task.abort();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = log.lines().map(parse_line).collect();
+        assert_eq!(1, lines.len());
+        assert_eq!("$GPHDT,123.4,T*31", lines[0].1);
+        assert!(lines[0].0.is_some());
+    }
+
+    async fn replay_times(name: &str, speed: f64, looping: bool, count: usize) -> Vec<Duration> {
+        let path = temp_path(name);
+        std::fs::write(
+            &path,
+            "2024-06-01T12:00:00.000Z $GPHDT,1,T*2A\n\
+             2024-06-01T12:00:00.200Z $GPHDT,123.4,T*31\n\
+             2024-06-01T12:00:00.400Z $GPHDT,1,T*2A\n",
+        )
+        .unwrap();
+        let (tx, mut bus) = channel::<Sentence>(16);
+        let began = Instant::now();
+        let task = sources::spawn(
+            SourceConfig::new(SourceKind::Replay { path: path.clone(), speed, looping }),
+            tx,
+        );
+        let mut times = Vec::new();
+        while times.len() < count {
+            // A fast loop easily outruns the channel; skipped sentences are fine here
+            if timeout(Duration::from_secs(2), bus.recv()).await.unwrap().is_ok() {
+                times.push(began.elapsed());
+            }
+        }
+        task.abort();
+        std::fs::remove_file(&path).unwrap();
+        times
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        // Real time keeps the gaps, double speed halves them
+        let times = replay_times("real.log", 1.0, false, 3).await;
+        assert!(times[2] >= Duration::from_millis(400) && times[2] < Duration::from_millis(600));
+        let times = replay_times("double.log", 2.0, false, 3).await;
+        assert!(times[2] >= Duration::from_millis(200) && times[2] < Duration::from_millis(350));
+
+        // As fast as possible, and around again
+        let times = replay_times("loop.log", 0.0, true, 9).await;
+        assert!(times[8] < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::bus::{Publisher, Sentence};
+use crate::config::{Parity, SerialSettings};
+use crate::filter::Filter;
+use crate::nmea::Framer;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio_serial::{DataBits, SerialPortBuilderExt, SerialStream, StopBits};
+
+fn open(path: &str, settings: SerialSettings) -> io::Result<SerialStream> {
+    let data_bits = match settings.data_bits {
+        5 => DataBits::Five,
+        6 => DataBits::Six,
+        7 => DataBits::Seven,
+        _ => DataBits::Eight,
+    };
+    let parity = match settings.parity {
+        Parity::None => tokio_serial::Parity::None,
+        Parity::Odd => tokio_serial::Parity::Odd,
+        Parity::Even => tokio_serial::Parity::Even,
+    };
+    let stop_bits = match settings.stop_bits {
+        2 => StopBits::Two,
+        _ => StopBits::One,
+    };
+    let port = tokio_serial::new(path, settings.baud)
+        .data_bits(data_bits)
+        .parity(parity)
+        .stop_bits(stop_bits)
+        .open_native_async()?;
+    Ok(port)
+}
+
+/// Reads sentences from a serial port and, when the port has an output filter,
+/// writes the matching sentences from the bus to it (e.g. APB to an autopilot).
+/// Returns when the port goes away, e.g. when a USB adapter is unplugged.
+pub async fn run(
+    path: &str,
+    settings: SerialSettings,
+    output: Option<&Filter>,
+    publisher: &mut Publisher,
+    mut receiver: Option<&mut Receiver<Sentence>>,
+) -> io::Result<()> {
+    let port = open(path, settings)?;
+    println!("{}: opened at {}", publisher.source(), settings);
+    let (mut reader, mut writer) = tokio::io::split(port);
+    let mut framer = Framer::new();
+    let mut buf = [0u8; 1024];
+    let mut output = output.map(Filter::start);
+
+    // Whatever queued up while the port was away is stale by now
+    if let Some(receiver) = receiver.as_mut() {
+        **receiver = receiver.resubscribe();
+    }
+
+    loop {
+        tokio::select! {
+            result = reader.read(&mut buf) => {
+                let n = result?;
+                if n == 0 {
+                    return Ok(());
+                }
+                publisher.received(n);
+                for line in framer.push(&buf[..n]) {
+                    publisher.publish(&line);
+                }
+            }
+
+            result = async { receiver.as_mut().unwrap().recv().await }, if receiver.is_some() => {
+                let sentence = match result {
+                    Ok(sentence) => sentence,
+                    Err(RecvError::Lagged(n)) => {
+                        // 4800 baud is slow; drop what the port cannot keep up with
+                        eprintln!("{}: lagging, skipped {} messages", publisher.source(), n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                };
+                // Never echo the port's own sentences back to it
+                if sentence.source == *publisher.source() || !output.as_mut().is_some_and(|filter| filter.accept(&sentence)) {
+                    continue;
+                }
+                let wire = sentence.to_wire();
+                writer.write_all(wire.as_bytes()).await?;
+                publisher.counters().sent(wire.len());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bus::Sentence;
+    use crate::config::{SerialSettings, SourceConfig, SourceKind};
+    use crate::sources;
+    use nix::pty::openpty;
+    use nix::unistd::ttyname;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use tokio::sync::broadcast::channel;
+    use tokio::time::{sleep, timeout, Duration};
+
+    #[tokio::test]
+    async fn test_serial_port() {
+        // The source opens the slave side, the test plays the instruments on the master
+        let pty = openpty(None, None).unwrap();
+        let path = ttyname(&pty.slave).unwrap().to_string_lossy().into_owned();
+        let mut master = File::from(pty.master);
+
+        let (tx, mut bus) = channel::<Sentence>(16);
+        let source = SourceConfig::new(SourceKind::Serial {
+            path: path.clone(),
+            settings: "38400".parse::<SerialSettings>().unwrap(),
+            output: Some("+*APB".parse().unwrap()),
+        });
+        let task = sources::spawn(source, tx.clone());
+        sleep(Duration::from_millis(300)).await;
+
+        master.write_all(b"$GPHDT,123.4,T*31\r\n").unwrap();
+        let sentence = timeout(Duration::from_secs(2), bus.recv()).await.unwrap().unwrap();
+        assert_eq!("$GPHDT,123.4,T*31", sentence.line);
+        assert_eq!(format!("serial:{}", path), &*sentence.source);
+
+        // Only APB goes out to the autopilot
+        let plotter: Arc<str> = Arc::from("client:plotter");
+        tx.send(Sentence::new(&plotter, "$GPHDT,1,T*2A")).unwrap();
+        tx.send(Sentence::new(&plotter, "$ECAPB,A,A,0.10,R,N,V,V,011,M,DEST,011,M,011,M*2D")).unwrap();
+        let written = tokio::task::spawn_blocking(move || {
+            let mut buf = [0u8; 128];
+            let n = master.read(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        });
+        let written = timeout(Duration::from_secs(2), written).await.unwrap().unwrap();
+        assert_eq!("$ECAPB,A,A,0.10,R,N,V,V,011,M,DEST,011,M,011,M*2D\r\n", written);
+        task.abort();
+        drop(pty.slave);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::bus::Sentence;
+use crate::config::{Config, ListenerConfig, ListenerKind, SourceConfig, SourceKind};
+use crate::shutdown::Shutdown;
+use crate::{anchor, listeners, sources, stats};
+use std::io;
+use tokio::sync::broadcast::{channel, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+/// The running sources and listeners. Applying a new configuration only
+/// restarts what changed, so connected clients and unchanged sources carry on.
+pub struct Server {
+    tx: Sender<Sentence>,
+    channel_capacity: usize,
+    listeners: Vec<(ListenerConfig, JoinHandle<()>)>,
+    sources: Vec<(SourceConfig, JoinHandle<()>)>,
+    shutdown: Shutdown,
+}
+
+impl Server {
+    pub fn new(channel_capacity: usize) -> Server {
+        let (tx, _rx) = channel::<Sentence>(channel_capacity); // Broadcast channel
+        Server {
+            tx,
+            channel_capacity,
+            listeners: Vec::new(),
+            sources: Vec::new(),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    pub async fn apply(&mut self, config: &Config) -> io::Result<()> {
+        if config.channel_capacity != self.channel_capacity {
+            eprintln!("channel_capacity only changes on restart, keeping {}", self.channel_capacity);
+        }
+
+        // Stop what is gone or changed first, so its port is free again
+        for (source, handle) in take_stale(&mut self.sources, &config.sources) {
+            println!("Stopping {}", source.name);
+            handle.abort();
+            let _ = handle.await;
+            // A source that is only restarted keeps its statistics
+            if !config.sources.iter().any(|wanted| wanted.name == source.name) {
+                stats::remove_source(&source.name);
+            }
+            // Same for the anchor point, which a new radius does not move
+            if matches!(source.kind, SourceKind::Anchor { .. })
+                && !config.sources.iter().any(|wanted| matches!(wanted.kind, SourceKind::Anchor { .. }))
+            {
+                anchor::remove();
+            }
+        }
+        for (listener, handle) in take_stale(&mut self.listeners, &config.listeners) {
+            println!("Stopping {}", listener.name);
+            handle.abort();
+            let _ = handle.await;
+        }
+
+        // Every input source feeds validated sentences into the broadcast channel
+        for source in &config.sources {
+            if !self.sources.iter().any(|(running, _)| running == source) {
+                println!("Reading from {}", source.name);
+                let handle = sources::spawn(source.clone(), self.tx.clone());
+                self.sources.push((source.clone(), handle));
+            }
+        }
+
+        // Listeners are subscribers to the same channel
+        for listener in &config.listeners {
+            if self.listeners.iter().any(|(running, _)| running == listener) {
+                continue;
+            }
+            let handle = listeners::start(listener, &self.tx, &self.shutdown.handle())
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", listener.name, e)))?;
+            match &listener.kind {
+                ListenerKind::Tcp { bind } => println!("Listening on {}", bind),
+                ListenerKind::WebSocket { bind } => println!("Listening for WebSocket clients on {}", bind),
+                ListenerKind::SignalK { bind } => println!("Serving Signal K on {}", bind),
+                ListenerKind::Status { bind, .. } => println!("Serving status on {}", bind),
+                ListenerKind::Udp { .. } | ListenerKind::Record { .. } => println!("Writing to {}", listener.name),
+            }
+            self.listeners.push((listener.clone(), handle));
+        }
+        Ok(())
+    }
+
+    /// Stops the sources, lets clients, recordings and UDP outputs send what
+    /// is still on the bus and close, waiting up to `grace` for them. Returns
+    /// whether everything finished in time.
+    pub async fn shutdown(self, grace: Duration) -> bool {
+        for (source, handle) in self.sources {
+            println!("Stopping {}", source.name);
+            handle.abort();
+            let _ = handle.await;
+        }
+        let finished = self.shutdown.stop(grace).await;
+        // Signal K and status listeners have nothing to flush
+        for (_, handle) in self.listeners {
+            handle.abort();
+        }
+        finished
+    }
+}
+
+// Removes the running entries that are not in the new configuration as is
+fn take_stale<T: PartialEq>(running: &mut Vec<(T, JoinHandle<()>)>, wanted: &[T]) -> Vec<(T, JoinHandle<()>)> {
+    let (keep, stale) = running.drain(..).partition(|(config, _)| wanted.contains(config));
+    *running = keep;
+    stale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::time::{sleep, timeout};
+
+    #[tokio::test]
+    async fn test_reload() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut config = Config::parse_toml(&format!(
+            "[[listener]]\ntype = \"tcp\"\nbind = \"127.0.0.1:{}\"\n",
+            port
+        ))
+        .unwrap();
+        let mut server = Server::new(config.channel_capacity);
+        server.apply(&config).await.unwrap();
+
+        let mut client = BufReader::new(TcpStream::connect(("127.0.0.1", port)).await.unwrap());
+        sleep(Duration::from_millis(100)).await;
+
+        // Changing the listener rebinds the same port; the connected client stays
+        config.listeners[0].filter = "-*GSV".parse().unwrap();
+        server.apply(&config).await.unwrap();
+        TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+
+        let source: std::sync::Arc<str> = "gps".into();
+        server.tx.send(Sentence::new(&source, "$GPHDT,1,T*2A")).unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        assert_eq!("$GPHDT,1,T*2A\r\n", line);
+
+        // A listener that cannot start is reported, and retried on the next reload
+        let mut busy = config.clone();
+        busy.listeners[0].filter = Default::default();
+        busy.listeners.push(busy.listeners[0].clone());
+        busy.listeners[1].name = "second".to_string();
+        assert!(server.apply(&busy).await.is_err());
+        assert_eq!(1, server.listeners.len());
+
+        // Removing everything stops the listener
+        server.apply(&Config::parse_toml("").unwrap()).await.unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let log = std::env::temp_dir().join(format!("rust_tcp_server-{}-shutdown.log", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        let config = Config::parse_toml(&format!(
+            "[[listener]]\ntype = \"tcp\"\nbind = \"127.0.0.1:{}\"\nqueue = 64\n\
+             [[listener]]\ntype = \"record\"\npath = {:?}\n",
+            port, log
+        ))
+        .unwrap();
+        let mut server = Server::new(config.channel_capacity);
+        server.apply(&config).await.unwrap();
+        let mut client = BufReader::new(TcpStream::connect(("127.0.0.1", port)).await.unwrap());
+        sleep(Duration::from_millis(100)).await;
+
+        // Whatever is on the bus when the signal comes still goes out
+        let source: std::sync::Arc<str> = "gps".into();
+        for _ in 0..20 {
+            server.tx.send(Sentence::new(&source, "$GPHDT,1,T*2A")).unwrap();
+        }
+        assert!(server.shutdown(Duration::from_secs(2)).await);
+
+        let mut received = String::new();
+        timeout(Duration::from_secs(2), client.read_to_string(&mut received)).await.unwrap().unwrap();
+        assert_eq!(20, received.lines().count());
+        assert_eq!(20, std::fs::read_to_string(&log).unwrap().lines().count());
+        std::fs::remove_file(&log).unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+}
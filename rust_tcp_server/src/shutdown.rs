@@ -0,0 +1,91 @@
+use std::future::pending;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{timeout, Duration};
+
+/// Owned by the server: tells every task holding a [`ShutdownHandle`] to
+/// finish up, then waits for them to let go of it.
+pub struct Shutdown {
+    stop: watch::Sender<bool>,
+    finished: mpsc::Receiver<()>,
+    handle: ShutdownHandle,
+}
+
+/// Held by the tasks that write somewhere (clients, recordings, UDP outputs)
+/// for as long as they may still have something to write.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    stopping: watch::Receiver<bool>,
+    _running: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (stop, stopping) = watch::channel(false);
+        let (running, finished) = mpsc::channel(1);
+        Shutdown {
+            stop,
+            finished,
+            handle: ShutdownHandle { stopping, _running: running },
+        }
+    }
+
+    pub fn handle(&self) -> ShutdownHandle {
+        self.handle.clone()
+    }
+
+    /// Signals the tasks and waits up to `grace` for them; returns whether
+    /// they all finished in time.
+    pub async fn stop(self, grace: Duration) -> bool {
+        let Shutdown { stop, mut finished, handle } = self;
+        drop(handle);
+        let _ = stop.send(true);
+        // Every handle holds a sender; recv returns None once the last one is gone
+        timeout(grace, finished.recv()).await.is_ok()
+    }
+}
+
+impl ShutdownHandle {
+    /// Resolves once the server is shutting down. A handle whose server is
+    /// gone without shutting down (as in tests) never resolves.
+    pub async fn stopping(&mut self) {
+        while !*self.stopping.borrow_and_update() {
+            if self.stopping.changed().await.is_err() {
+                pending::<()>().await;
+            }
+        }
+    }
+}
+
+impl Default for ShutdownHandle {
+    // For tasks that only ever get aborted
+    fn default() -> ShutdownHandle {
+        Shutdown::new().handle()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_handles() {
+        let shutdown = Shutdown::new();
+        let mut handle = shutdown.handle();
+        let task = tokio::spawn(async move {
+            handle.stopping().await;
+            // Still writing for a while after the signal
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        });
+        assert!(shutdown.stop(Duration::from_secs(2)).await);
+        assert!(task.is_finished());
+
+        // A task that never lets go makes the shutdown give up after the grace period
+        let shutdown = Shutdown::new();
+        let stuck = shutdown.handle();
+        assert!(!shutdown.stop(Duration::from_millis(100)).await);
+        drop(stuck);
+
+        let mut orphan = ShutdownHandle::default();
+        assert!(timeout(Duration::from_millis(100), orphan.stopping()).await.is_err());
+    }
+}
@@ -0,0 +1,596 @@
+use crate::bus::Sentence;
+use crate::client::{self, ClientOptions, LOGIN_TIMEOUT};
+use crate::config::User;
+use crate::filter;
+use crate::http::{read_head, respond, Replay};
+use crate::nmea;
+use crate::stats;
+use chrono::{SecondsFormat, Utc};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+// Deltas are always about our own boat
+const CONTEXT: &str = "vessels.self";
+const VERSION: &str = "1.7.0";
+
+pub const KNOTS: f64 = 1852.0 / 3600.0; // m/s
+const KMH: f64 = 1.0 / 3.6;
+const MPH: f64 = 0.44704;
+const FEET: f64 = 0.3048;
+const CELSIUS: f64 = 273.15; // Signal K temperatures are in Kelvin
+
+fn number(fields: &[&str], i: usize) -> Option<f64> {
+    fields.get(i)?.parse().ok()
+}
+
+fn field<'a>(fields: &[&'a str], i: usize) -> &'a str {
+    fields.get(i).copied().unwrap_or_default()
+}
+
+fn radians(degrees: f64) -> f64 {
+    degrees.to_radians()
+}
+
+// ddmm.mmmm plus hemisphere, as used in GGA/RMC/GLL
+fn coordinate(value: &str, hemisphere: &str) -> Option<f64> {
+    let value: f64 = value.parse().ok()?;
+    let degrees = (value / 100.0).trunc() + (value % 100.0) / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(degrees),
+        "S" | "W" => Some(-degrees),
+        _ => None,
+    }
+}
+
+fn position(fields: &[&str], i: usize) -> Option<Value> {
+    let latitude = coordinate(field(fields, i), field(fields, i + 1))?;
+    let longitude = coordinate(field(fields, i + 2), field(fields, i + 3))?;
+    Some(json!({ "latitude": latitude, "longitude": longitude }))
+}
+
+// East is positive, west negative
+fn signed(value: Option<f64>, direction: &str) -> Option<f64> {
+    match direction {
+        "W" => value.map(|v| -v),
+        _ => value,
+    }
+}
+
+fn speed(value: Option<f64>, unit: &str) -> Option<f64> {
+    let factor = match unit {
+        "N" => KNOTS,
+        "M" => 1.0,
+        "K" => KMH,
+        "S" => MPH,
+        _ => return None,
+    };
+    value.map(|v| v * factor)
+}
+
+/// The Signal K paths and values (in SI units) carried by a sentence.
+pub fn values(line: &str) -> Vec<(String, Value)> {
+    let address = nmea::address(line);
+    let fields = nmea::fields(line);
+    let f = fields.as_slice();
+    let mut values: Vec<(&str, Option<Value>)> = Vec::new();
+    let mut add = |path, value: Option<f64>| values.push((path, value.map(Value::from)));
+
+    match nmea::sentence_type(address) {
+        "RMC" if field(f, 1) == "A" => {
+            add("navigation.speedOverGround", number(f, 6).map(|v| v * KNOTS));
+            add("navigation.courseOverGroundTrue", number(f, 7).map(radians));
+            add("navigation.magneticVariation", signed(number(f, 9), field(f, 10)).map(radians));
+            values.push(("navigation.position", position(f, 2)));
+        }
+        "GGA" if !matches!(field(f, 5), "" | "0") => values.push(("navigation.position", position(f, 1))),
+        "GLL" if matches!(field(f, 5), "" | "A") => values.push(("navigation.position", position(f, 0))),
+        "VTG" => {
+            add("navigation.courseOverGroundTrue", number(f, 0).map(radians));
+            add("navigation.courseOverGroundMagnetic", number(f, 2).map(radians));
+            let sog = speed(number(f, 4), "N").or_else(|| speed(number(f, 6), "K"));
+            add("navigation.speedOverGround", sog);
+        }
+        "HDT" => add("navigation.headingTrue", number(f, 0).map(radians)),
+        "HDM" => add("navigation.headingMagnetic", number(f, 0).map(radians)),
+        "VHW" => {
+            add("navigation.headingTrue", number(f, 0).map(radians));
+            add("navigation.headingMagnetic", number(f, 2).map(radians));
+            let stw = speed(number(f, 4), "N").or_else(|| speed(number(f, 6), "K"));
+            add("navigation.speedThroughWater", stw);
+        }
+        "HDG" => {
+            // Sensor heading corrected for deviation is the magnetic heading
+            let deviation = signed(number(f, 1), field(f, 2)).unwrap_or(0.0);
+            add("navigation.headingMagnetic", number(f, 0).map(|h| radians(h + deviation)));
+            add("navigation.magneticVariation", signed(number(f, 3), field(f, 4)).map(radians));
+        }
+        "MWV" if field(f, 4) == "A" => {
+            // -π..π, negative to port
+            let angle = number(f, 0).map(|a| radians(if a > 180.0 { a - 360.0 } else { a }));
+            let speed = speed(number(f, 2), field(f, 3));
+            match field(f, 1) {
+                "R" => {
+                    add("environment.wind.angleApparent", angle);
+                    add("environment.wind.speedApparent", speed);
+                }
+                "T" => {
+                    add("environment.wind.angleTrueWater", angle);
+                    add("environment.wind.speedTrue", speed);
+                }
+                _ => {}
+            }
+        }
+        "MWD" => {
+            add("environment.wind.directionTrue", number(f, 0).map(radians));
+            add("environment.wind.directionMagnetic", number(f, 2).map(radians));
+            let speed = speed(number(f, 6), "M").or_else(|| speed(number(f, 4), "N"));
+            add("environment.wind.speedOverGround", speed);
+        }
+        "DBT" => {
+            let depth = number(f, 2).or_else(|| number(f, 0).map(|feet| feet * FEET));
+            add("environment.depth.belowTransducer", depth);
+        }
+        "DPT" => {
+            let depth = number(f, 0);
+            add("environment.depth.belowTransducer", depth);
+            // A positive offset is to the waterline, a negative one to the keel
+            match (depth, number(f, 1)) {
+                (Some(depth), Some(offset)) if offset > 0.0 => add("environment.depth.belowSurface", Some(depth + offset)),
+                (Some(depth), Some(offset)) if offset < 0.0 => add("environment.depth.belowKeel", Some(depth + offset)),
+                _ => {}
+            }
+        }
+        "MTW" if field(f, 1) == "C" => add("environment.water.temperature", number(f, 0).map(|t| t + CELSIUS)),
+        "XDR" => return xdr_values(f),
+        _ => {}
+    }
+    values
+        .into_iter()
+        .filter_map(|(path, value)| Some((path.to_string(), value?)))
+        .collect()
+}
+
+// Transducer quadruplets: type, value, unit, name
+fn xdr_values(fields: &[&str]) -> Vec<(String, Value)> {
+    let mut values = Vec::new();
+    for quad in fields.chunks(4) {
+        let (kind, unit) = (field(quad, 0), field(quad, 2));
+        let name = field(quad, 3).to_lowercase();
+        let value = match number(quad, 1) {
+            Some(value) => value,
+            None => continue,
+        };
+        let (path, value) = match (kind, unit) {
+            ("C", "C") if name.contains("water") => ("environment.water.temperature", value + CELSIUS),
+            ("C", "C") if name.contains("inside") || name.contains("cabin") => ("environment.inside.temperature", value + CELSIUS),
+            ("C", "C") => ("environment.outside.temperature", value + CELSIUS),
+            ("C", "K") => ("environment.outside.temperature", value),
+            ("P", "B") => ("environment.outside.pressure", value * 100_000.0),
+            ("P", "P") => ("environment.outside.pressure", value),
+            ("H", "P") => ("environment.outside.relativeHumidity", value / 100.0),
+            _ => continue,
+        };
+        values.push((path.to_string(), Value::from(value)));
+    }
+    values
+}
+
+/// A Signal K delta for a sentence, or None when it carries nothing we map.
+pub fn delta(sentence: &Sentence, timestamp: &str) -> Option<Value> {
+    let values = values(&sentence.line);
+    if values.is_empty() {
+        return None;
+    }
+    let address = nmea::address(&sentence.line);
+    let talker = nmea::talker(address);
+    Some(json!({
+        "context": CONTEXT,
+        "updates": [{
+            "source": {
+                "label": &*sentence.source,
+                "type": "NMEA0183",
+                "talker": talker,
+                "sentence": nmea::sentence_type(address),
+            },
+            "$source": format!("{}.{}", sentence.source, talker),
+            "timestamp": timestamp,
+            "values": values
+                .into_iter()
+                .map(|(path, value)| json!({ "path": path, "value": value }))
+                .collect::<Vec<_>>(),
+        }],
+    }))
+}
+
+/// The paths a client subscribed to, as globs (`navigation.*`).
+#[derive(Debug, Default, PartialEq)]
+pub struct Subscriptions {
+    paths: Vec<String>,
+}
+
+impl Subscriptions {
+    /// The initial subscription from `?subscribe=self|all|none`.
+    pub fn from_query(query: &str) -> Subscriptions {
+        match query.split('&').find_map(|pair| pair.strip_prefix("subscribe=")) {
+            Some("none") => Subscriptions::default(),
+            _ => Subscriptions { paths: vec!["*".to_string()] },
+        }
+    }
+
+    /// Applies a `subscribe` or `unsubscribe` message; other messages are
+    /// ignored, since this server has nothing to PUT to.
+    pub fn update(&mut self, message: &Value) {
+        let context = message["context"].as_str().unwrap_or(CONTEXT);
+        if !filter::glob(context, CONTEXT) {
+            return;
+        }
+        let paths = |key| {
+            message[key]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|item| item["path"].as_str())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        for path in paths("unsubscribe") {
+            if path == "*" {
+                self.paths.clear();
+            }
+            self.paths.retain(|p| *p != path);
+        }
+        self.paths.extend(paths("subscribe"));
+    }
+
+    /// Drops the values nobody asked for; None when nothing is left.
+    pub fn select(&self, mut delta: Value) -> Option<Value> {
+        let values = delta["updates"][0]["values"].as_array_mut()?;
+        values.retain(|value| {
+            let path = value["path"].as_str().unwrap_or_default();
+            self.paths.iter().any(|pattern| filter::glob(pattern, path))
+        });
+        if values.is_empty() {
+            return None;
+        }
+        Some(delta)
+    }
+}
+
+fn discovery(host: &str, tls: bool) -> Value {
+    let scheme = if tls { "wss" } else { "ws" };
+    json!({
+        "endpoints": {
+            "v1": {
+                "version": VERSION,
+                "signalk-ws": format!("{}://{}/signalk/v1/stream", scheme, host),
+            }
+        },
+        "server": {
+            "id": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        }
+    })
+}
+
+/// Serves one connection: the `/signalk` discovery document over plain HTTP,
+/// or the `/signalk/v1/stream` WebSocket with deltas for our sentences.
+/// `local` is our end of the connection, for clients that send no Host.
+pub async fn handle_signalk<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    mut stream: S,
+    addr: SocketAddr,
+    local: SocketAddr,
+    receiver: Receiver<Sentence>,
+    options: ClientOptions,
+) -> io::Result<()> {
+    let (request, read) = read_head(&mut stream).await?;
+    let upgrade = request.header("Upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+    match request.path.trim_end_matches('/') {
+        // Discovery is a plain GET, which the WebSocket handshake would refuse
+        "/signalk" if !upgrade => {
+            let host = request.header("Host").map_or_else(|| local.to_string(), str::to_string);
+            respond(&mut stream, "200 OK", &discovery(&host, options.tls).to_string()).await
+        }
+        _ if upgrade => {
+            // tungstenite reads the request again and checks the rest of the upgrade
+            #[allow(clippy::result_large_err)] // the signature tungstenite expects
+            let callback = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+                match request.uri().path().trim_end_matches('/') {
+                    "/signalk/v1/stream" => Ok(response),
+                    _ => {
+                        let mut error = ErrorResponse::new(Some(r#"{"error":"not found"}"#.to_string()));
+                        *error.status_mut() = StatusCode::NOT_FOUND;
+                        Err(error)
+                    }
+                }
+            };
+            let websocket = tokio_tungstenite::accept_hdr_async(Replay::new(read, stream), callback)
+                .await
+                .map_err(io::Error::other)?;
+            stream_deltas(websocket, addr, receiver, options, Subscriptions::from_query(&request.query)).await
+        }
+        _ => respond(&mut stream, "404 Not Found", r#"{"error":"not found"}"#).await,
+    }
+}
+
+// A Signal K login request, or the `#AUTH` line our other clients send,
+// as an `#AUTH` line and the request ID to answer with
+fn login_line(text: &str) -> (String, Value) {
+    match serde_json::from_str::<Value>(text) {
+        Ok(message) if message.get("login").is_some() => {
+            let login = &message["login"];
+            let field = |name: &str| login[name].as_str().unwrap_or_default().to_string();
+            (format!("#AUTH {} {}", field("username"), field("password")), message["requestId"].clone())
+        }
+        _ => (text.trim().to_string(), Value::Null),
+    }
+}
+
+// With users configured, the first message has to log in as one that may
+// receive; the reply is a Signal K request response
+async fn login<S: AsyncRead + AsyncWrite + Unpin>(
+    sink: &mut SplitSink<WebSocketStream<S>, Message>,
+    stream: &mut SplitStream<WebSocketStream<S>>,
+    addr: SocketAddr,
+    users: &[User],
+) -> io::Result<bool> {
+    let first_text = async {
+        loop {
+            match stream.next().await {
+                Some(Ok(Message::Text(text))) => return Ok(Some(text)),
+                Some(Ok(Message::Close(_))) | None => return Ok(None),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(io::Error::other(e)),
+            }
+        }
+    };
+    let text = match timeout(LOGIN_TIMEOUT, first_text).await {
+        Ok(text) => text?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "no login")),
+    };
+    let Some(text) = text else { return Ok(false) };
+    let (line, request_id) = login_line(&text);
+    let (status, message) = match client::authenticate(users, &addr.to_string(), &line) {
+        Ok(user) if user.access.can_read() => (200, None),
+        Ok(_) => (403, Some("this user may not receive")),
+        Err(_) => (401, Some("authentication failed")),
+    };
+    let mut reply = json!({ "requestId": request_id, "state": "COMPLETED", "statusCode": status });
+    if let Some(message) = message {
+        reply["message"] = json!(message);
+    }
+    sink.send(Message::Text(reply.to_string())).await.map_err(io::Error::other)?;
+    if status != 200 {
+        let _ = sink.close().await;
+    }
+    Ok(status == 200)
+}
+
+async fn stream_deltas<S: AsyncRead + AsyncWrite + Unpin>(
+    websocket: WebSocketStream<S>,
+    addr: SocketAddr,
+    mut receiver: Receiver<Sentence>,
+    options: ClientOptions,
+    mut subscriptions: Subscriptions,
+) -> io::Result<()> {
+    let (mut sink, mut stream) = websocket.split();
+    let mut filter = options.filter.start();
+    let mut skipped: u64 = 0;
+
+    let hello = json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": VERSION,
+        "self": CONTEXT,
+        "roles": ["master", "main"],
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    });
+    sink.send(Message::Text(hello.to_string())).await.map_err(io::Error::other)?;
+    if !options.users.is_empty() && !login(&mut sink, &mut stream, addr, &options.users).await? {
+        return Ok(());
+    }
+    let stats = stats::client(addr);
+
+    let result = loop {
+        tokio::select! {
+            message = stream.next() => {
+                match message {
+                    Some(Ok(Message::Text(text))) => {
+                        stats.counters().bytes_in(text.len());
+                        match serde_json::from_str::<Value>(&text) {
+                            Ok(message) => subscriptions.update(&message),
+                            Err(e) => eprintln!("{}: ignoring {:?}: {}", addr, text, e),
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => break Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => break Err(io::Error::other(e)),
+                }
+            }
+
+            result = receiver.recv() => {
+                let sentence = match result {
+                    Ok(sentence) => sentence,
+                    Err(RecvError::Lagged(n)) => {
+                        skipped = stats.counters().lagged(n);
+                        eprintln!("{}: lagging, skipped {} messages ({} total)", addr, n, skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break Ok(()),
+                };
+                if !filter.accept(&sentence) {
+                    continue;
+                }
+                let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+                let delta = match delta(&sentence, &timestamp).and_then(|delta| subscriptions.select(delta)) {
+                    Some(delta) => delta,
+                    None => continue,
+                };
+                let delta = delta.to_string();
+                stats.counters().sent(delta.len());
+                if let Err(e) = sink.send(Message::Text(delta)).await {
+                    break Err(io::Error::other(e));
+                }
+            }
+        }
+    };
+    if skipped > 0 {
+        println!("{}: disconnected, skipped {} messages", addr, skipped);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ListenerConfig, ListenerKind};
+    use crate::listeners;
+    use crate::shutdown::ShutdownHandle;
+    use std::f64::consts::PI;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::broadcast::channel;
+    use tokio::time::{timeout, Duration};
+
+    fn value(line: &str, path: &str) -> f64 {
+        let values = values(line);
+        let (_, value) = values.iter().find(|(p, _)| p == path).unwrap_or_else(|| panic!("no {} in {:?}", path, values));
+        value.as_f64().unwrap()
+    }
+
+    fn close(expected: f64, actual: f64) -> bool {
+        (expected - actual).abs() < 1e-6
+    }
+
+    #[test]
+    fn test_values() {
+        let rmc = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A";
+        let position = values(rmc).into_iter().find(|(p, _)| p == "navigation.position").unwrap().1;
+        assert!(close(48.1173, position["latitude"].as_f64().unwrap()));
+        assert!(close(11.0 + 31.0 / 60.0, position["longitude"].as_f64().unwrap()));
+        let south = values("$GPGLL,3342.6618,S,11751.3858,W,002153.000,A*00");
+        assert!(close(-(33.0 + 42.6618 / 60.0), south[0].1["latitude"].as_f64().unwrap()));
+        assert!(close(22.4 * KNOTS, value(rmc, "navigation.speedOverGround")));
+        assert!(close(84.4f64.to_radians(), value(rmc, "navigation.courseOverGroundTrue")));
+        assert!(close(-3.1f64.to_radians(), value(rmc, "navigation.magneticVariation")));
+        // No fix, no values
+        assert!(values("$GPRMC,123519,V,,,,,,,230394,,*00").is_empty());
+
+        assert!(close(PI / 2.0, value("$HCHDT,90.0,T*00", "navigation.headingTrue")));
+        assert!(close(6.2 * KNOTS, value("$VWVHW,,T,271.0,M,6.2,N,11.5,K*00", "navigation.speedThroughWater")));
+        assert_eq!(2, values("$VWVHW,,T,271.0,M,6.2,N,11.5,K*00").len());
+        assert!(close(-PI / 2.0, value("$WIMWV,270.0,R,10.0,N,A*00", "environment.wind.angleApparent")));
+        assert!(close(10.0 * KNOTS, value("$WIMWV,270.0,R,10.0,N,A*00", "environment.wind.speedApparent")));
+        assert!(close(5.0, value("$WIMWV,45.0,T,5.0,M,A*00", "environment.wind.speedTrue")));
+        assert!(values("$WIMWV,45.0,T,5.0,M,V*00").is_empty());
+
+        assert!(close(3.4, value("$SDDBT,11.2,f,3.4,M,1.9,F*00", "environment.depth.belowTransducer")));
+        assert!(close(3.9, value("$SDDPT,3.4,0.5*00", "environment.depth.belowSurface")));
+        assert!(close(291.15, value("$YXMTW,18.0,C*00", "environment.water.temperature")));
+
+        let xdr = "$IIXDR,C,19.5,C,AirTemp,P,1.0132,B,Barometer,H,65,P,Humidity*00";
+        assert!(close(292.65, value(xdr, "environment.outside.temperature")));
+        assert!(close(101_320.0, value(xdr, "environment.outside.pressure")));
+        assert!(close(0.65, value(xdr, "environment.outside.relativeHumidity")));
+
+        assert!(values("!AIVDM,1,1,,A,13HOI:0P0000VOHLCnHQKwvL05Ip,0*23").is_empty());
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let sentence = Sentence::new(&Arc::from("gps"), "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A");
+        let delta = delta(&sentence, "2024-06-01T12:00:00.000Z").unwrap();
+        assert_eq!("vessels.self", delta["context"]);
+        assert_eq!("gps.GP", delta["updates"][0]["$source"]);
+        assert_eq!(4, delta["updates"][0]["values"].as_array().unwrap().len());
+
+        let mut subscriptions = Subscriptions::from_query("subscribe=none");
+        assert_eq!(None, subscriptions.select(delta.clone()));
+        subscriptions.update(&json!({
+            "context": "vessels.self",
+            "subscribe": [{ "path": "navigation.position" }, { "path": "navigation.speed*" }],
+        }));
+        let selected = subscriptions.select(delta.clone()).unwrap();
+        assert_eq!(2, selected["updates"][0]["values"].as_array().unwrap().len());
+
+        // Other vessels are not ours to serve
+        subscriptions.update(&json!({ "context": "vessels.urn:mrn:imo:mmsi:230099999", "unsubscribe": [{ "path": "*" }] }));
+        assert!(subscriptions.select(delta.clone()).is_some());
+        subscriptions.update(&json!({ "context": "*", "unsubscribe": [{ "path": "*" }] }));
+        assert_eq!(None, subscriptions.select(delta.clone()));
+
+        assert!(Subscriptions::from_query("").select(delta).is_some());
+    }
+
+    async fn next_json<S>(client: &mut S) -> Value
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let message = timeout(Duration::from_secs(2), client.next()).await.unwrap().unwrap().unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_signalk_server() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let listener = ListenerConfig::new(ListenerKind::SignalK { bind: format!("127.0.0.1:{}", port) });
+        let (tx, _bus) = channel::<Sentence>(16);
+        listeners::start(&listener, &tx, &ShutdownHandle::default()).await.unwrap();
+
+        // Discovery
+        let mut http = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        http.write_all(b"GET /signalk HTTP/1.1\r\nHost: pi.local:3000\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!("ws://pi.local:3000/signalk/v1/stream", body["endpoints"]["v1"]["signalk-ws"]);
+
+        // Stream: hello, then only the subscribed paths
+        let url = format!("ws://127.0.0.1:{}/signalk/v1/stream?subscribe=none", port);
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        assert_eq!("vessels.self", next_json(&mut client).await["self"]);
+
+        let subscribe = json!({ "context": "vessels.self", "subscribe": [{ "path": "environment.depth.*" }] });
+        client.send(Message::Text(subscribe.to_string())).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let source: Arc<str> = Arc::from("sounder");
+        tx.send(Sentence::new(&source, "$HCHDT,90.0,T*00")).unwrap();
+        tx.send(Sentence::new(&source, "$SDDBT,11.2,f,3.4,M,1.9,F*00")).unwrap();
+        let delta = next_json(&mut client).await;
+        assert_eq!("environment.depth.belowTransducer", delta["updates"][0]["values"][0]["path"]);
+        assert_eq!(3.4, delta["updates"][0]["values"][0]["value"]);
+
+        let mut http = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        http.write_all(b"GET /other HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        // The handshake checks the whole upgrade and where it goes
+        let upgrade = "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+        for (request, status) in [
+            (format!("GET /signalk/v1/stream HTTP/1.1\r\nHost: pi\r\n{}\r\n", upgrade), ""),
+            (format!("GET /signalk/v1/stream HTTP/1.1\r\nHost: pi\r\n{}Sec-WebSocket-Version: 13\r\n\r\n", upgrade), "HTTP/1.1 101"),
+            (format!("GET /other HTTP/1.1\r\nHost: pi\r\n{}Sec-WebSocket-Version: 13\r\n\r\n", upgrade), "HTTP/1.1 404"),
+        ] {
+            let mut http = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            http.write_all(request.as_bytes()).await.unwrap();
+            let mut response = vec![0; 12];
+            let n = timeout(Duration::from_secs(2), http.read(&mut response)).await.unwrap().unwrap();
+            assert_eq!(status, String::from_utf8_lossy(&response[..n]));
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::bus::{Publisher, Sentence};
+use crate::config::{SourceConfig, SourceKind};
+use crate::nmea::Framer;
+use crate::{anchor, derived, recording, serial, stats};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::broadcast::Sender;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+pub fn spawn(source: SourceConfig, tx: Sender<Sentence>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // Serial ports with an output filter are also written to, from the same bus
+        let mut receiver = match &source.kind {
+            SourceKind::Serial { output: Some(_), .. } => Some(tx.subscribe()),
+            _ => None,
+        };
+        let mut publisher = Publisher::new(&source.name, &source.filter, tx.clone(), stats::source(&source.name));
+        loop {
+            let result = match &source.kind {
+                SourceKind::Serial { path, settings, output } => {
+                    serial::run(path, *settings, output.as_ref(), &mut publisher, receiver.as_mut()).await
+                }
+                SourceKind::TcpClient { addr } => read_tcp(addr, &mut publisher).await,
+                SourceKind::Udp { bind } => read_udp(bind, &mut publisher).await,
+                SourceKind::Stdin => {
+                    if let Err(e) = read_stream(tokio::io::stdin(), &mut publisher).await {
+                        eprintln!("{}: {}", source.name, e);
+                    }
+                    // Nothing to reconnect to once stdin is closed
+                    break;
+                }
+                SourceKind::Replay { path, speed, looping } => {
+                    match recording::replay(path, *speed, *looping, &mut publisher).await {
+                        Ok(()) => println!("{}: finished", source.name),
+                        Err(e) => eprintln!("{}: {}", source.name, e),
+                    }
+                    break;
+                }
+                SourceKind::Derived { outputs, damping } => {
+                    // Runs for as long as the bus does
+                    derived::run(outputs, *damping, tx.subscribe(), &mut publisher).await;
+                    break;
+                }
+                SourceKind::Anchor { radius, track_len } => {
+                    anchor::run(*radius, *track_len, tx.subscribe(), &mut publisher).await;
+                    break;
+                }
+            };
+            match result {
+                Ok(()) => eprintln!("{}: closed, reconnecting", source.name),
+                Err(e) => eprintln!("{}: {}, reconnecting", source.name, e),
+            }
+            sleep(RECONNECT_DELAY).await;
+        }
+    })
+}
+
+async fn read_stream<R: AsyncRead + Unpin>(mut reader: R, publisher: &mut Publisher) -> io::Result<()> {
+    let mut framer = Framer::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        publisher.received(n);
+        for line in framer.push(&buf[..n]) {
+            publisher.publish(&line);
+        }
+    }
+}
+
+async fn read_tcp(addr: &str, publisher: &mut Publisher) -> io::Result<()> {
+    let stream = TcpStream::connect(addr).await?;
+    println!("{}: connected", addr);
+    read_stream(stream, publisher).await
+}
+
+async fn bind_udp(bind: &str) -> io::Result<UdpSocket> {
+    // Binding to a multicast group means listening on its port and joining it
+    match bind.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(addr)) if addr.ip().is_multicast() => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port())).await?;
+            socket.join_multicast_v4(*addr.ip(), Ipv4Addr::UNSPECIFIED)?;
+            Ok(socket)
+        }
+        Ok(SocketAddr::V6(addr)) if addr.ip().is_multicast() => {
+            let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, addr.port())).await?;
+            socket.join_multicast_v6(addr.ip(), 0)?;
+            Ok(socket)
+        }
+        _ => UdpSocket::bind(bind).await,
+    }
+}
+
+async fn read_udp(bind: &str, publisher: &mut Publisher) -> io::Result<()> {
+    let socket = bind_udp(bind).await?;
+    let mut buf = [0u8; 2048];
+    loop {
+        let (n, _peer) = socket.recv_from(&mut buf).await?;
+        publisher.received(n);
+        // Every datagram carries whole sentences, so don't carry partial lines over
+        let mut framer = Framer::new();
+        for line in framer.push(&buf[..n]) {
+            publisher.publish(&line);
+        }
+        if let Some(last) = framer.push(b"\n").pop() {
+            publisher.publish(&last);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::channel;
+
+    #[tokio::test]
+    async fn test_udp_source() {
+        let (tx, mut rx) = channel::<Sentence>(16);
+        let probe = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let bind = probe.local_addr().unwrap().to_string();
+        drop(probe);
+
+        let mut source = SourceConfig::new(SourceKind::Udp { bind: bind.clone() });
+        source.filter = "-*GSV".parse().unwrap();
+        spawn(source, tx);
+        sleep(Duration::from_millis(100)).await;
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender
+            .send_to(b"$GPHDT,123.4,T*31\r\n$GPHDT,1,T*00\r\n$GPGSV,3,1,11*7B\r\n!AIVDM,1,1,,A,13HOI:0P0000VOHLCnHQKwvL05Ip,0*23", &bind)
+            .await
+            .unwrap();
+
+        let first = rx.recv().await.unwrap();
+        assert_eq!(format!("udp:{}", bind), &*first.source);
+        assert_eq!("$GPHDT,123.4,T*31", first.line);
+        // The sentence with the bad checksum and the filtered one are dropped
+        assert_eq!("!AIVDM,1,1,,A,13HOI:0P0000VOHLCnHQKwvL05Ip,0*23", rx.recv().await.unwrap().line);
+    }
+}
@@ -0,0 +1,331 @@
+use crate::anchor;
+use crate::http::{read_request, respond};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Instant;
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+// Sentence rates are averaged over this many one-second buckets
+const RATE_WINDOW: usize = 10;
+
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    sources: BTreeMap::new(),
+    clients: BTreeMap::new(),
+    next_client: 0,
+});
+
+struct Registry {
+    sources: BTreeMap<String, Arc<Counters>>,
+    clients: BTreeMap<u64, ClientEntry>,
+    next_client: u64,
+}
+
+struct ClientEntry {
+    address: SocketAddr,
+    connected: DateTime<Utc>,
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Activity {
+    last_seen: Option<(Instant, DateTime<Utc>)>,
+    buckets: [u64; RATE_WINDOW],
+    second: u64, // of the newest bucket, counted from `created`
+}
+
+/// Traffic counters of one source or client, updated from its task and read
+/// by the status endpoint.
+pub struct Counters {
+    created: Instant,
+    sentences_in: AtomicU64,
+    sentences_out: AtomicU64,
+    checksum_errors: AtomicU64,
+    invalid: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    lagged: AtomicU64,
+    dropped: AtomicU64,
+    activity: Mutex<Activity>,
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters::started_at(Instant::now())
+    }
+
+    fn started_at(created: Instant) -> Counters {
+        Counters {
+            created,
+            sentences_in: AtomicU64::new(0),
+            sentences_out: AtomicU64::new(0),
+            checksum_errors: AtomicU64::new(0),
+            invalid: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            lagged: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            activity: Mutex::new(Activity::default()),
+        }
+    }
+
+    /// A valid sentence came in.
+    pub fn sentence(&self) {
+        self.sentence_at(Instant::now(), Utc::now());
+    }
+
+    fn sentence_at(&self, now: Instant, time: DateTime<Utc>) {
+        self.sentences_in.fetch_add(1, Ordering::Relaxed);
+        let mut activity = self.activity.lock().unwrap();
+        let second = self.advance(&mut activity, now);
+        activity.buckets[second as usize % RATE_WINDOW] += 1;
+        activity.last_seen = Some((now, time));
+    }
+
+    pub fn checksum_error(&self) {
+        self.checksum_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn invalid(&self) {
+        self.invalid.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.sentences_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn lagged(&self, n: u64) -> u64 {
+        self.lagged.fetch_add(n, Ordering::Relaxed) + n
+    }
+
+    /// The client's own queue was full and lost its oldest message.
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Moves the bucket window up to `now`, clearing the seconds in between
+    fn advance(&self, activity: &mut Activity, now: Instant) -> u64 {
+        let second = now.saturating_duration_since(self.created).as_secs();
+        if second >= activity.second + RATE_WINDOW as u64 {
+            activity.buckets = [0; RATE_WINDOW];
+        } else {
+            for s in activity.second + 1..=second {
+                activity.buckets[s as usize % RATE_WINDOW] = 0;
+            }
+        }
+        activity.second = activity.second.max(second);
+        second
+    }
+
+    fn report(&self, now: Instant, stale_after: Option<Duration>) -> Value {
+        let mut activity = self.activity.lock().unwrap();
+        let second = self.advance(&mut activity, now);
+        // Sentences per second over the window, or over the lifetime when shorter
+        let seconds = (second + 1).min(RATE_WINDOW as u64);
+        let rate = activity.buckets.iter().sum::<u64>() as f64 / seconds as f64;
+        let since = activity.last_seen.map_or(self.created, |(seen, _)| seen);
+        let mut report = json!({
+            "sentences_in": self.sentences_in.load(Ordering::Relaxed),
+            "sentences_out": self.sentences_out.load(Ordering::Relaxed),
+            "rate": (rate * 100.0).round() / 100.0,
+            "last_seen": activity.last_seen.map(|(_, time)| time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            "checksum_errors": self.checksum_errors.load(Ordering::Relaxed),
+            "invalid": self.invalid.load(Ordering::Relaxed),
+            "bytes_in": self.bytes_in.load(Ordering::Relaxed),
+            "bytes_out": self.bytes_out.load(Ordering::Relaxed),
+            "lagged": self.lagged.load(Ordering::Relaxed),
+            "dropped": self.dropped.load(Ordering::Relaxed),
+        });
+        if let Some(stale_after) = stale_after {
+            report["stale"] = Value::from(now.saturating_duration_since(since) > stale_after);
+        }
+        report
+    }
+}
+
+/// The counters of a source, kept across reconnects.
+pub fn source(name: &str) -> Arc<Counters> {
+    LazyLock::force(&STARTED);
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.sources.entry(name.to_string()).or_insert_with(|| Arc::new(Counters::new())).clone()
+}
+
+/// Forgets a source that was removed from the configuration.
+pub fn remove_source(name: &str) {
+    REGISTRY.lock().unwrap().sources.remove(name);
+}
+
+/// A connected client's entry in the status report, removed on drop.
+pub struct ClientStats {
+    id: u64,
+    counters: Arc<Counters>,
+}
+
+impl ClientStats {
+    pub fn counters(&self) -> &Arc<Counters> {
+        &self.counters
+    }
+}
+
+impl Drop for ClientStats {
+    fn drop(&mut self) {
+        REGISTRY.lock().unwrap().clients.remove(&self.id);
+    }
+}
+
+pub fn client(address: SocketAddr) -> ClientStats {
+    let counters = Arc::new(Counters::new());
+    let mut registry = REGISTRY.lock().unwrap();
+    let id = registry.next_client;
+    registry.next_client += 1;
+    registry.clients.insert(
+        id,
+        ClientEntry {
+            address,
+            connected: Utc::now(),
+            counters: counters.clone(),
+        },
+    );
+    ClientStats { id, counters }
+}
+
+/// Everything the status endpoint reports; sources that have not produced a
+/// sentence for `stale_after` are flagged as stale.
+pub fn report(stale_after: Duration) -> Value {
+    let now = Instant::now();
+    let registry = REGISTRY.lock().unwrap();
+    let sources: Vec<Value> = registry
+        .sources
+        .iter()
+        .map(|(name, counters)| {
+            let mut report = counters.report(now, Some(stale_after));
+            report["name"] = Value::from(name.as_str());
+            report
+        })
+        .collect();
+    let clients: Vec<Value> = registry
+        .clients
+        .values()
+        .map(|client| {
+            let mut report = client.counters.report(now, None);
+            report["address"] = Value::from(client.address.to_string());
+            report["connected"] = Value::from(client.connected.to_rfc3339_opts(SecondsFormat::Secs, true));
+            report["connected_for"] = Value::from(now.saturating_duration_since(client.counters.created).as_secs());
+            report
+        })
+        .collect();
+    json!({
+        "uptime": now.saturating_duration_since(*STARTED).as_secs(),
+        "stale_after": stale_after.as_secs_f64(),
+        "sources": sources,
+        "clients": clients,
+    })
+}
+
+/// Answers `GET /status` (or `/`) with the report as JSON.
+pub async fn handle_status(mut stream: TcpStream, stale_after: Duration) -> io::Result<()> {
+    let peer = stream.peer_addr()?.ip();
+    let request = read_request(&mut stream).await?;
+    match request.path.as_str() {
+        "/" | "/status" => respond(&mut stream, "200 OK", &report(stale_after).to_string()).await,
+        path if path.starts_with("/anchor") => {
+            let (status, body) = anchor::http(&request, peer);
+            respond(&mut stream, status, &body.to_string()).await
+        }
+        _ => respond(&mut stream, "404 Not Found", r#"{"error":"not found"}"#).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Publisher, Sentence};
+    use crate::config::{ListenerConfig, ListenerKind};
+    use crate::filter::Filter;
+    use crate::listeners;
+    use crate::shutdown::ShutdownHandle;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::broadcast::channel;
+
+    #[test]
+    fn test_rate_and_stale() {
+        let start = Instant::now();
+        let counters = Counters::started_at(start);
+        let stale_after = Some(Duration::from_secs(5));
+        assert_eq!(json!(false), counters.report(start, stale_after)["stale"]);
+        assert_eq!(json!(true), counters.report(start + Duration::from_secs(6), stale_after)["stale"]);
+
+        // 1 Hz for 20 seconds, then 5 Hz for the last 10
+        for second in 0..30 {
+            let per_second = if second < 20 { 1 } else { 5 };
+            for _ in 0..per_second {
+                counters.sentence_at(start + Duration::from_secs(second), Utc::now());
+            }
+        }
+        let end = start + Duration::from_millis(29_500);
+        let report = counters.report(end, stale_after);
+        assert_eq!(json!(5.0), report["rate"]);
+        assert_eq!(json!(70), report["sentences_in"]);
+        assert_eq!(json!(false), report["stale"]);
+
+        // Silence empties the window and makes the source stale
+        let report = counters.report(end + Duration::from_secs(20), stale_after);
+        assert_eq!(json!(0.0), report["rate"]);
+        assert_eq!(json!(true), report["stale"]);
+    }
+
+    #[tokio::test]
+    async fn test_status_endpoint() {
+        let (tx, _rx) = channel::<Sentence>(16);
+        let mut publisher = Publisher::new("stats-test-gps", &Filter::default(), tx.clone(), source("stats-test-gps"));
+        publisher.received(20);
+        publisher.publish("$GPHDT,123.4,T*31");
+        publisher.publish("$GPHDT,123.4,T*32");
+        let client = client("192.168.1.10:51234".parse().unwrap());
+        client.counters().lagged(7);
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let listener = ListenerConfig::new(ListenerKind::Status {
+            bind: format!("127.0.0.1:{}", port),
+            stale_after: Duration::from_secs(10),
+        });
+        listeners::start(&listener, &tx, &ShutdownHandle::default()).await.unwrap();
+
+        let mut http = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        http.write_all(b"GET /status HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).await.unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let status: Value = serde_json::from_str(body).unwrap();
+
+        let gps = status["sources"].as_array().unwrap().iter().find(|s| s["name"] == "stats-test-gps").unwrap();
+        assert_eq!(json!(1), gps["sentences_in"]);
+        assert_eq!(json!(1), gps["checksum_errors"]);
+        assert_eq!(json!(20), gps["bytes_in"]);
+        assert_eq!(json!(false), gps["stale"]);
+        assert!(gps["last_seen"].is_string());
+
+        let clients = status["clients"].as_array().unwrap();
+        let entry = clients.iter().find(|c| c["address"] == "192.168.1.10:51234").unwrap();
+        assert_eq!(json!(7), entry["lagged"]);
+
+        // Disconnected clients disappear from the report
+        drop(client);
+        assert!(!report(Duration::from_secs(10))["clients"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|c| c["address"] == "192.168.1.10:51234"));
+    }
+}
@@ -0,0 +1,147 @@
+use sd_notify::NotifyState;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::FromRawFd;
+use std::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration};
+
+// Sockets passed in by systemd (ListenStream=), kept for the life of the process
+// so a listener restarted by a reload gets the same socket again
+static INHERITED: Mutex<Vec<TcpListener>> = Mutex::new(Vec::new());
+
+/// Takes over the sockets of a socket-activated start (LISTEN_FDS) and
+/// returns how many there were.
+pub fn take_sockets() -> io::Result<usize> {
+    let mut inherited = INHERITED.lock().unwrap();
+    for fd in sd_notify::listen_fds()? {
+        // SAFETY: systemd passes these descriptors to this process to own
+        let socket = unsafe { TcpListener::from_raw_fd(fd) };
+        match socket.local_addr() {
+            Ok(addr) => println!("Socket activation: {}", addr),
+            Err(e) => eprintln!("Socket activation: descriptor {}: {}", fd, e),
+        }
+        inherited.push(socket);
+    }
+    Ok(inherited.len())
+}
+
+/// A copy of the inherited socket for `bind`, if systemd passed one.
+pub fn inherited(bind: &str) -> io::Result<Option<TcpListener>> {
+    let inherited = INHERITED.lock().unwrap();
+    match find(&inherited, bind) {
+        Some(socket) => Ok(Some(socket.try_clone()?)),
+        None => Ok(None),
+    }
+}
+
+fn find<'a>(sockets: &'a [TcpListener], bind: &str) -> Option<&'a TcpListener> {
+    let bind: SocketAddr = bind.parse().ok()?;
+    sockets.iter().find(|socket| match socket.local_addr() {
+        // ListenStream=10110 is [::]:10110, which also serves 0.0.0.0:10110
+        Ok(local) => local == bind || (local.port() == bind.port() && local.ip().is_unspecified() && bind.ip().is_unspecified()),
+        Err(_) => false,
+    })
+}
+
+fn notify(state: &[NotifyState]) {
+    // Without NOTIFY_SOCKET (not started by systemd) this does nothing
+    if let Err(e) = sd_notify::notify(false, state) {
+        eprintln!("sd_notify: {}", e);
+    }
+}
+
+pub fn ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+pub fn reloading() {
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(_) => notify(&[NotifyState::Reloading]),
+    }
+}
+
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Pings the watchdog at half the interval systemd asks for (WatchdogSec=).
+/// The pings come from the runtime, so a wedged runtime gets restarted.
+pub fn spawn_watchdog() -> Option<JoinHandle<()>> {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) || usec == 0 {
+        return None;
+    }
+    let mut ticks = interval(Duration::from_micros(usec) / 2);
+    Some(tokio::spawn(async move {
+        loop {
+            ticks.tick().await;
+            notify(&[NotifyState::Watchdog]);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_find_inherited() {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let sockets = [socket];
+        assert!(find(&sockets, &addr.to_string()).is_some());
+        assert!(find(&sockets, &format!("127.0.0.2:{}", addr.port())).is_none());
+        assert!(find(&sockets, "not an address").is_none());
+
+        let any = TcpListener::bind("[::]:0").unwrap();
+        let port = any.local_addr().unwrap().port();
+        let sockets = [any];
+        assert!(find(&sockets, &format!("0.0.0.0:{}", port)).is_some());
+    }
+
+    // Clears what the test set, even when an assertion fails
+    struct Environment(&'static [&'static str]);
+
+    impl Drop for Environment {
+        fn drop(&mut self) {
+            for name in self.0 {
+                std::env::remove_var(name);
+            }
+        }
+    }
+
+    // The watchdog task needs a worker of its own while the test blocks on the socket
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_notify_socket() {
+        // Stands in for systemd's notification socket
+        let path = std::env::temp_dir().join(format!("rust_tcp_server-{}-notify", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+        let _environment = Environment(&["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"]);
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        std::env::set_var("WATCHDOG_USEC", "100000");
+        std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+
+        let mut buf = [0u8; 256];
+        let mut receive = || {
+            let n = systemd.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        };
+        ready("2 sources");
+        assert_eq!("READY=1\nSTATUS=2 sources\n", receive());
+
+        let watchdog = spawn_watchdog().unwrap();
+        assert_eq!("WATCHDOG=1\n", receive());
+        assert_eq!("WATCHDOG=1\n", receive());
+        watchdog.abort();
+        let _ = watchdog.await;
+
+        stopping();
+        assert_eq!("STOPPING=1\n", receive());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use crate::config::TlsConfig;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+fn open(path: &str) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no certificates", path)));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{}: no private key", path)))
+}
+
+/// Reads the certificate and key, so a bad file stops the listener from
+/// starting instead of failing every client.
+pub fn acceptor(tls: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(&tls.cert)?;
+    let key = load_key(&tls.key)?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", tls.key, e)))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Sentence;
+    use crate::config::{Access, ListenerConfig, ListenerKind, User};
+    use crate::listeners;
+    use crate::shutdown::ShutdownHandle;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::sync::broadcast::{channel, Sender};
+    use tokio::time::{timeout, Duration};
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+    use tokio_tungstenite::tungstenite::Message;
+
+    struct SelfSigned {
+        tls: TlsConfig,
+        cert: CertificateDer<'static>,
+    }
+
+    fn self_signed(name: &str) -> SelfSigned {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let prefix = format!("rust_tcp_server-{}-{}", std::process::id(), name);
+        let cert = dir.join(format!("{}.crt", prefix)).to_string_lossy().into_owned();
+        let key = dir.join(format!("{}.key", prefix)).to_string_lossy().into_owned();
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+        SelfSigned {
+            tls: TlsConfig { cert, key },
+            cert: certified.cert.der().clone(),
+        }
+    }
+
+    async fn start(kind: fn(String) -> ListenerKind, tls: &TlsConfig) -> (u16, Sender<Sentence>) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut listener = ListenerConfig::new(kind(format!("127.0.0.1:{}", port)));
+        listener.tls = Some(tls.clone());
+        listener.users = vec![
+            "plotter:rw:s3cret".parse::<User>().unwrap(),
+            User { name: "display".to_string(), access: Access::Read, password: "d1splay-token".to_string() },
+        ];
+        let (tx, _rx) = channel::<Sentence>(16);
+        listeners::start(&listener, &tx, &ShutdownHandle::default()).await.unwrap();
+        (port, tx)
+    }
+
+    async fn connect(port: u16, cert: &CertificateDer<'static>) -> TlsStream<TcpStream> {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.clone()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        TlsConnector::from(Arc::new(config)).connect(name, stream).await.unwrap()
+    }
+
+    async fn read_line<S: tokio::io::AsyncRead + Unpin>(client: &mut BufReader<S>) -> String {
+        let mut line = String::new();
+        timeout(Duration::from_secs(2), client.read_line(&mut line)).await.unwrap().unwrap();
+        line
+    }
+
+    #[tokio::test]
+    async fn test_tls_login() {
+        let certs = self_signed("tcp");
+        let (port, tx) = start(|bind| ListenerKind::Tcp { bind }, &certs.tls).await;
+        let mut bus = tx.subscribe();
+
+        // The plotter logs in with name and password and may send
+        let mut plotter = BufReader::new(connect(port, &certs.cert).await);
+        let apb = "$ECAPB,A,A,0.10,R,N,V,V,011,M,DEST,011,M,011,M*2D";
+        plotter.get_mut().write_all(format!("#AUTH plotter s3cret\r\n{}\r\n", apb).as_bytes()).await.unwrap();
+        let sentence = timeout(Duration::from_secs(2), bus.recv()).await.unwrap().unwrap();
+        assert_eq!(apb, sentence.line);
+
+        // The display logs in with its token and only receives
+        let mut display = BufReader::new(connect(port, &certs.cert).await);
+        display.get_mut().write_all(b"#AUTH d1splay-token\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        display.get_mut().write_all(b"$GPHDT,1,T*2A\r\n").await.unwrap();
+        tx.send(Sentence::new(&Arc::from("gps"), "$GPHDT,123.4,T*31")).unwrap();
+        assert_eq!("$GPHDT,123.4,T*31\r\n", read_line(&mut display).await);
+        assert_eq!("gps", &*bus.recv().await.unwrap().source);
+        assert!(bus.try_recv().is_err());
+
+        // A wrong password, or no login at all, ends the connection
+        for first in ["#AUTH plotter guess\r\n", "$GPHDT,1,T*2A\r\n"] {
+            let mut intruder = BufReader::new(connect(port, &certs.cert).await);
+            intruder.get_mut().write_all(first.as_bytes()).await.unwrap();
+            assert!(read_line(&mut intruder).await.starts_with("#ERROR"));
+            let mut rest = Vec::new();
+            let _ = intruder.read_to_end(&mut rest).await;
+            assert!(rest.is_empty());
+        }
+
+        // Plain TCP gets no further than the TLS handshake
+        let mut plain = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        plain.write_all(b"#AUTH plotter s3cret\r\n").await.unwrap();
+        let mut rest = Vec::new();
+        let _ = timeout(Duration::from_secs(2), plain.read_to_end(&mut rest)).await.unwrap();
+        assert!(!String::from_utf8_lossy(&rest).contains('$'));
+    }
+
+    #[tokio::test]
+    async fn test_tls_websocket() {
+        let certs = self_signed("ws");
+        let (port, tx) = start(|bind| ListenerKind::WebSocket { bind }, &certs.tls).await;
+
+        let stream = connect(port, &certs.cert).await;
+        let (mut client, _) = tokio_tungstenite::client_async(format!("wss://localhost:{}/", port), stream)
+            .await
+            .unwrap();
+        client.send(Message::Text("#AUTH display d1splay-token".to_string())).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tx.send(Sentence::new(&Arc::from("gps"), "$GPHDT,123.4,T*31")).unwrap();
+        let message = timeout(Duration::from_secs(2), client.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(Message::Text("$GPHDT,123.4,T*31".to_string()), message);
+    }
+
+    #[tokio::test]
+    async fn test_tls_signalk() {
+        let certs = self_signed("signalk");
+        let (port, tx) = start(|bind| ListenerKind::SignalK { bind }, &certs.tls).await;
+
+        // Plain HTTP gets neither the discovery document nor the stream
+        for path in ["/signalk", "/signalk/v1/stream"] {
+            let mut plain = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                path
+            );
+            plain.write_all(request.as_bytes()).await.unwrap();
+            let mut rest = Vec::new();
+            let _ = timeout(Duration::from_secs(2), plain.read_to_end(&mut rest)).await.unwrap();
+            assert!(!String::from_utf8_lossy(&rest).contains("HTTP/1.1"));
+        }
+
+        // Over TLS the stream wants a login first
+        let url = format!("wss://localhost:{}/signalk/v1/stream", port);
+        let stream = connect(port, &certs.cert).await;
+        let (mut client, _) = tokio_tungstenite::client_async(&url, stream).await.unwrap();
+        let _hello = timeout(Duration::from_secs(2), client.next()).await.unwrap();
+        let login = r#"{"requestId":"1","login":{"username":"display","password":"d1splay-token"}}"#;
+        client.send(Message::Text(login.to_string())).await.unwrap();
+        let reply = timeout(Duration::from_secs(2), client.next()).await.unwrap().unwrap().unwrap();
+        assert!(reply.to_text().unwrap().contains(r#""statusCode":200"#));
+        tx.send(Sentence::new(&Arc::from("gps"), "$GPHDT,123.4,T*31")).unwrap();
+        let delta = timeout(Duration::from_secs(2), client.next()).await.unwrap().unwrap().unwrap();
+        assert!(delta.to_text().unwrap().contains("navigation.headingTrue"));
+
+        // A wrong password is answered and the stream closed
+        let stream = connect(port, &certs.cert).await;
+        let (mut intruder, _) = tokio_tungstenite::client_async(&url, stream).await.unwrap();
+        let _hello = timeout(Duration::from_secs(2), intruder.next()).await.unwrap();
+        let login = r#"{"requestId":"2","login":{"username":"plotter","password":"guess"}}"#;
+        intruder.send(Message::Text(login.to_string())).await.unwrap();
+        let reply = timeout(Duration::from_secs(2), intruder.next()).await.unwrap().unwrap().unwrap();
+        assert!(reply.to_text().unwrap().contains(r#""statusCode":401"#));
+        tx.send(Sentence::new(&Arc::from("gps"), "$GPHDT,123.4,T*31")).unwrap();
+        while let Ok(Some(Ok(message))) = timeout(Duration::from_secs(2), intruder.next()).await {
+            assert!(!message.to_text().unwrap_or_default().contains("navigation"));
+        }
+    }
+
+    #[test]
+    fn test_bad_files() {
+        let missing = TlsConfig { cert: "/nonexistent/boat.crt".to_string(), key: "/nonexistent/boat.key".to_string() };
+        let error = acceptor(&missing).err().unwrap().to_string();
+        assert!(error.starts_with("/nonexistent/boat.crt: "));
+
+        // A key file in place of the certificate
+        let certs = self_signed("swapped");
+        let swapped = TlsConfig { cert: certs.tls.key.clone(), key: certs.tls.key.clone() };
+        assert!(acceptor(&swapped).err().unwrap().to_string().contains("no certificates"));
+        assert!(acceptor(&certs.tls).is_ok());
+    }
+}
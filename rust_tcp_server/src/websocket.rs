@@ -0,0 +1,276 @@
+use crate::bus::Sentence;
+use crate::client::{serve, ClientOptions, ClientSink, ClientStream, Input};
+use crate::nmea::{self, Framer};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// What a WebSocket client receives: one NMEA sentence per text message, or
+/// one JSON object per sentence with its fields split out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Nmea,
+    Json,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "nmea0183" | "nmea" => Some(Format::Nmea),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    fn encode(self, sentence: &Sentence) -> String {
+        match self {
+            Format::Nmea => sentence.line.clone(),
+            Format::Json => {
+                let address = nmea::address(&sentence.line);
+                let json = JsonSentence {
+                    source: &sentence.source,
+                    talker: nmea::talker(address),
+                    sentence_type: nmea::sentence_type(address),
+                    fields: nmea::fields(&sentence.line),
+                    line: &sentence.line,
+                };
+                serde_json::to_string(&json).unwrap()
+            }
+        }
+    }
+
+    fn error(self, message: &str) -> String {
+        match self {
+            Format::Nmea => format!("#ERROR {}", message),
+            Format::Json => serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonSentence<'a> {
+    source: &'a str,
+    talker: &'a str,
+    #[serde(rename = "type")]
+    sentence_type: &'a str,
+    fields: Vec<&'a str>,
+    line: &'a str,
+}
+
+// What JSON clients send upstream: a sentence, a filter for what they
+// receive, or their login ("NAME PASSWORD" or a token)
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonInput {
+    sentence: Option<String>,
+    filter: Option<String>,
+    auth: Option<String>,
+}
+
+/// Picks the format from `?format=` or else from the offered subprotocols,
+/// and returns the subprotocol to confirm, if any.
+fn negotiate(request: &Request) -> Result<(Format, Option<String>), String> {
+    let protocol = request
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .find(|name| Format::from_name(name).is_some());
+
+    let query = request.uri().query().unwrap_or_default();
+    let format = match query.split('&').find_map(|pair| pair.strip_prefix("format=")) {
+        Some(name) => Format::from_name(name).ok_or_else(|| format!("unknown format {:?}, expected nmea0183 or json", name))?,
+        None => protocol.and_then(Format::from_name).unwrap_or(Format::Nmea),
+    };
+    Ok((format, protocol.map(String::from)))
+}
+
+/// A WebSocket client's messages, in the format it asked for.
+struct Messages<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    format: Format,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for Messages<S> {
+    // A text message may hold several lines
+    async fn read(&mut self) -> io::Result<Input> {
+        loop {
+            match self.stream.next().await {
+                Some(Ok(Message::Text(text))) => return Ok(Some((text.len(), input_lines(self.format, &text)))),
+                Some(Ok(Message::Close(_))) | None => return Ok(None),
+                Some(Ok(_)) => continue, // ping/pong are answered by tungstenite
+                Some(Err(e)) => return Err(io::Error::other(e)),
+            }
+        }
+    }
+
+    fn encode(&self, sentence: &Sentence) -> String {
+        self.format.encode(sentence)
+    }
+
+    fn error(&self, message: &str) -> String {
+        self.format.error(message)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> ClientSink for SplitSink<WebSocketStream<S>, Message> {
+    async fn send(&mut self, message: String) -> io::Result<()> {
+        SinkExt::send(self, Message::Text(message)).await.map_err(io::Error::other)
+    }
+
+    // With a close frame
+    async fn close(&mut self) -> io::Result<()> {
+        SinkExt::close(self).await.map_err(io::Error::other)
+    }
+}
+
+pub async fn handle_websocket<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
+    addr: SocketAddr,
+    tx: Sender<Sentence>,
+    receiver: Receiver<Sentence>,
+    options: ClientOptions,
+) -> io::Result<()> {
+    let mut format = Format::Nmea;
+    #[allow(clippy::result_large_err)] // the signature tungstenite expects
+    let callback = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+        let (chosen, protocol) = match negotiate(request) {
+            Ok(negotiated) => negotiated,
+            Err(e) => {
+                let mut error = ErrorResponse::new(Some(e));
+                *error.status_mut() = StatusCode::BAD_REQUEST;
+                return Err(error);
+            }
+        };
+        format = chosen;
+        // Browsers drop the connection unless one of their protocols is confirmed
+        if let Some(protocol) = protocol.and_then(|p| HeaderValue::from_str(&p).ok()) {
+            response.headers_mut().insert("Sec-WebSocket-Protocol", protocol);
+        }
+        Ok(response)
+    };
+    let websocket = tokio_tungstenite::accept_hdr_async(stream, callback)
+        .await
+        .map_err(io::Error::other)?;
+    let (sink, stream) = websocket.split();
+    serve(Messages { stream, format }, sink, addr, tx, receiver, options).await
+}
+
+// Raw NMEA lines work in both formats; JSON clients may also send objects
+fn input_lines(format: Format, text: &str) -> Vec<Result<String, String>> {
+    if format == Format::Json && text.trim_start().starts_with('{') {
+        return match serde_json::from_str::<JsonInput>(text) {
+            Ok(input) => input
+                .auth
+                .map(|credentials| format!("#AUTH {}", credentials))
+                .into_iter()
+                .chain(input.filter.map(|spec| format!("#FILTER {}", spec)))
+                .chain(input.sentence)
+                .map(Ok)
+                .collect(),
+            Err(e) => vec![Err(e.to_string())],
+        };
+    }
+    Framer::new().push(format!("{}\n", text).as_bytes()).into_iter().map(Ok).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Access, ListenerConfig, ListenerKind};
+    use crate::listeners;
+    use crate::shutdown::ShutdownHandle;
+    use std::sync::Arc;
+    use tokio::sync::broadcast::channel;
+    use tokio::time::{timeout, Duration};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    async fn next_text<S>(client: &mut S) -> String
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        loop {
+            let message = timeout(Duration::from_secs(2), client.next()).await.unwrap().unwrap().unwrap();
+            if let Message::Text(text) = message {
+                return text;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_formats() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut listener = ListenerConfig::new(ListenerKind::WebSocket { bind: format!("127.0.0.1:{}", port) });
+        listener.access = Access::ReadWrite;
+        let (tx, mut bus) = channel::<Sentence>(16);
+        listeners::start(&listener, &tx, &ShutdownHandle::default()).await.unwrap();
+
+        // Raw NMEA, picked by subprotocol
+        let mut request = format!("ws://127.0.0.1:{}/", port).into_client_request().unwrap();
+        request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static("nmea0183"));
+        let (mut raw, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!("nmea0183", response.headers()["Sec-WebSocket-Protocol"]);
+
+        // JSON, picked by query parameter
+        let (mut json, _) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/?format=json", port))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        tx.send(Sentence::new(&Arc::from("gps"), "$GPHDT,123.4,T*31")).unwrap();
+        assert_eq!("$GPHDT,123.4,T*31", next_text(&mut raw).await);
+        let value: serde_json::Value = serde_json::from_str(&next_text(&mut json).await).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "source": "gps",
+                "talker": "GP",
+                "type": "HDT",
+                "fields": ["123.4", "T"],
+                "line": "$GPHDT,123.4,T*31",
+            }),
+            value
+        );
+
+        // Upstream sentences go onto the bus like those of TCP clients
+        bus.recv().await.unwrap();
+        let apb = "$ECAPB,A,A,0.10,R,N,V,V,011,M,DEST,011,M,011,M*2D";
+        raw.send(Message::Text(apb.to_string())).await.unwrap();
+        let sentence = timeout(Duration::from_secs(2), bus.recv()).await.unwrap().unwrap();
+        assert_eq!(apb, sentence.line);
+        assert!(sentence.source.starts_with("client:127.0.0.1:"));
+        assert!(next_text(&mut json).await.contains("ECAPB"));
+
+        json.send(Message::Text(r#"{"sentence": "$GPHDT,1,T*2A"}"#.to_string())).await.unwrap();
+        assert_eq!("$GPHDT,1,T*2A", timeout(Duration::from_secs(2), bus.recv()).await.unwrap().unwrap().line);
+        json.send(Message::Text(r#"{"filter": "GSV"}"#.to_string())).await.unwrap();
+        assert!(next_text(&mut json).await.starts_with(r#"{"error":"#));
+
+        // Unknown formats are refused during the handshake
+        assert!(tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/?format=xml", port))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_websocket() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let listener = ListenerConfig::new(ListenerKind::WebSocket { bind: format!("127.0.0.1:{}", port) });
+        let (tx, mut bus) = channel::<Sentence>(16);
+        listeners::start(&listener, &tx, &ShutdownHandle::default()).await.unwrap();
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/", port)).await.unwrap();
+        client.send(Message::Text("$GPHDT,1,T*2A".to_string())).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(bus.try_recv().is_err());
+    }
+}